@@ -0,0 +1,181 @@
+//! Read settings from the configuration file
+//!
+//! The file uses the names of the `VCP_*` variables in lower case and
+//! without prefix as keys.  Tables named after a VCS override the global
+//! settings for repositories of that VCS:
+//!
+//! ```toml
+//! format = "%n %b%( [%B%A]%)"
+//! branch = "{cyan}{value}{reset}"
+//!
+//! [git]
+//! symbol = "±"
+//! ```
+use crate::util::xdg_config_home;
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+
+/// Known keys and their `VCP_*` variable
+const KEYS: [(&str, &str); 36] = [
+    ("prefix", "VCP_PREFIX"),
+    ("suffix", "VCP_SUFFIX"),
+    ("separator", "VCP_SEPARATOR"),
+    ("name", "VCP_NAME"),
+    ("symbol", "VCP_SYMBOL"),
+    ("branch", "VCP_BRANCH"),
+    ("tag", "VCP_TAG"),
+    ("detached", "VCP_DETACHED"),
+    ("bookmark", "VCP_BOOKMARK"),
+    ("topic", "VCP_TOPIC"),
+    ("unstable", "VCP_UNSTABLE"),
+    ("upstream", "VCP_UPSTREAM"),
+    ("remote", "VCP_REMOTE"),
+    ("no_upstream", "VCP_NO_UPSTREAM"),
+    ("gone", "VCP_GONE"),
+    ("diff", "VCP_DIFF"),
+    ("phase", "VCP_PHASE"),
+    ("commit", "VCP_COMMIT"),
+    ("operation", "VCP_OPERATION"),
+    ("behind", "VCP_BEHIND"),
+    ("ahead", "VCP_AHEAD"),
+    ("base_ref", "VCP_BASE_REF"),
+    ("base_behind", "VCP_BASE_BEHIND"),
+    ("base_ahead", "VCP_BASE_AHEAD"),
+    ("staged", "VCP_STAGED"),
+    ("changed", "VCP_CHANGED"),
+    ("conflicts", "VCP_CONFLICTS"),
+    ("untracked", "VCP_UNTRACKED"),
+    ("added", "VCP_ADDED"),
+    ("deleted", "VCP_DELETED"),
+    ("renamed", "VCP_RENAMED"),
+    ("missing", "VCP_MISSING"),
+    ("stashed", "VCP_STASHED"),
+    ("clean", "VCP_CLEAN"),
+    ("bare", "VCP_BARE"),
+    ("format", "VCP_FORMAT"),
+];
+
+/// Tables with per-VCS overrides
+const VCS_TABLES: [&str; 5] = ["git", "hg", "jj", "svn", "fossil"];
+
+/// Settings from the configuration file, keyed by `VCP_*` variable
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    global: HashMap<&'static str, String>,
+    vcs:    HashMap<String, HashMap<&'static str, String>>,
+}
+
+impl Config {
+    /// Path of the default config file (`$XDG_CONFIG_HOME/vcprompt/config.toml`)
+    pub fn default_path() -> Option<PathBuf> {
+        xdg_config_home().map(|p| p.join("vcprompt/config.toml"))
+    }
+
+    /// Load the config file *path*, or the default file if *path* is `None`.
+    ///
+    /// A missing default file results in an empty config.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let (path, required) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match Config::default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Config::default()),
+            },
+        };
+        if !required && !path.exists() {
+            return Ok(Config::default());
+        }
+        let source = fs::read_to_string(&path)
+            .with_context(|| format!("Cannot read config file {}", path.display()))?;
+        Config::parse(&source).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Parse the contents of a config file
+    pub fn parse(source: &str) -> Result<Config> {
+        let table: Table = source.parse()?;
+        let mut config = Config::default();
+        for (key, value) in table.iter() {
+            match value {
+                Value::Table(overrides) if VCS_TABLES.contains(&key.as_str()) => {
+                    let settings = config.vcs.entry(key.clone()).or_default();
+                    for (key, value) in overrides.iter() {
+                        let (name, value) = setting(key, value)?;
+                        settings.insert(name, value);
+                    }
+                }
+                Value::Table(_) => bail!(
+                    "Unknown table `[{}]` (expected one of {})",
+                    key,
+                    VCS_TABLES.join(", ")
+                ),
+                _ => {
+                    let (name, value) = setting(key, value)?;
+                    config.global.insert(name, value);
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    /// Return the value of the variable *name* (e.g., `VCP_BRANCH`) for
+    /// the VCS *vcs* (e.g., `git`)
+    pub fn get(&self, vcs: &str, name: &str) -> Option<&str> {
+        self.vcs
+            .get(vcs)
+            .and_then(|settings| settings.get(name))
+            .or_else(|| self.global.get(name))
+            .map(String::as_str)
+    }
+}
+
+/// Map a config *key* to its variable name and check that *value* is a string
+fn setting(key: &str, value: &Value) -> Result<(&'static str, String)> {
+    let name = match KEYS.iter().find(|(k, _)| *k == key) {
+        Some((_, name)) => *name,
+        None => bail!("Unknown key `{}`", key),
+    };
+    match value {
+        Value::String(s) => Ok((name, s.clone())),
+        _ => bail!("Value of `{}` must be a string", key),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overrides() {
+        let config = Config::parse(
+            r#"
+branch = "{cyan}{value}"
+format = "%b"
+
+[git]
+branch = "{green}{value}"
+symbol = "±"
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.get("git", "VCP_BRANCH"), Some("{green}{value}"));
+        assert_eq!(config.get("hg", "VCP_BRANCH"), Some("{cyan}{value}"));
+        assert_eq!(config.get("git", "VCP_FORMAT"), Some("%b"));
+        assert_eq!(config.get("git", "VCP_SYMBOL"), Some("±"));
+        assert_eq!(config.get("hg", "VCP_SYMBOL"), None);
+    }
+
+    #[test]
+    fn errors() {
+        let err = Config::parse("colour = \"red\"").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown key `colour`");
+        assert!(Config::parse("[bzr]\nbranch = \"x\"").is_err());
+        assert!(Config::parse("[git]\nbranchh = \"x\"").is_err());
+        assert!(Config::parse("ahead = 1").is_err());
+        assert!(Config::parse("ahead = ").is_err());
+    }
+}
@@ -0,0 +1,700 @@
+//! Serve statuses from a background process over a Unix socket
+//!
+//! The daemon keeps the status of every repository it was asked about and
+//! recomputes it in the background when the repository changes, so a
+//! prompt only has to read the cached value.
+//!
+//! Clients send one JSON request per connection (`{"rootdir": "...",
+//! "settings": {...}}`) and receive the status in the format of `--json`,
+//! or `{"error": "..."}`.  Statuses are computed with the environment and
+//! config file of the daemon, so clients whose settings affecting the
+//! status differ (see `settings()`) are refused and compute it themselves.
+use crate::{
+    config::Config,
+    format::{status_json, JSON_SCHEMA_VERSION},
+    status::{FileCounts, Progress, Status},
+    util::logger::*,
+    vcs::{Backend, Registry, VCContext},
+};
+use anyhow::{bail, format_err, Context, Result};
+use serde_json::{json, Value};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    env,
+    fs::{self, DirBuilder, Metadata, Permissions},
+    hash::{Hash, Hasher},
+    io::{prelude::*, BufReader},
+    os::unix::{
+        fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+        net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Repositories that were not queried for this long are no longer refreshed
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// Longest pause between checks of a work tree that does not change
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How long clients and the daemon wait for each other
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Names of VCS metadata, which is skipped when checking the work tree for
+/// changes; directories containing them are nested repositories
+static METADATA: [&str; 6] = [".git", ".hg", ".jj", ".svn", ".fslckout", "_FOSSIL_"];
+
+/// Environment variables read by the built-in backends
+static SETTINGS: [&str; 4] = [
+    "VCP_BASE_REF",
+    "VCP_GIT_BACKEND",
+    "VCP_HG_BACKEND",
+    "VCP_HG_TRACKING",
+];
+
+/// Path of the daemon's socket: `VCP_DAEMON_SOCKET`, `vcprompt.sock` in
+/// `$XDG_RUNTIME_DIR` or `daemon.sock` in the directory `vcprompt-<uid>` in
+/// the temp directory, which only the user can access
+pub fn socket_path() -> PathBuf {
+    if let Some(path) = env::var_os("VCP_DAEMON_SOCKET") {
+        return path.into();
+    }
+    match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => Path::new(&dir).join("vcprompt.sock"),
+        None => env::temp_dir()
+            .join(format!("vcprompt-{}", uid()))
+            .join("daemon.sock"),
+    }
+}
+
+/// Real user ID of the process
+fn uid() -> u32 {
+    // getuid() always succeeds
+    unsafe { libc::getuid() }
+}
+
+/// Fail unless *path* belongs to the user *uid*
+fn check_owner(path: &Path, uid: u32) -> Result<()> {
+    let owner = fs::metadata(path)
+        .with_context(|| format!("Failed to stat {:?}", path))?
+        .uid();
+    if owner != uid {
+        bail!("{:?} belongs to user {}", path, owner);
+    }
+    Ok(())
+}
+
+/// Cached status of a repository
+struct Entry {
+    /// Response sent to clients (the JSON status)
+    response:   String,
+    /// `metadata_fingerprint()` before the status was computed
+    metadata:   u64,
+    /// `tree_fingerprint()` before the status was computed
+    tree:       u64,
+    /// Last time a client asked for the status
+    requested:  Instant,
+    /// Pause before the next check for changes; doubled whenever nothing
+    /// changed
+    backoff:    Duration,
+    /// Next time the repository is checked for changes
+    next_check: Instant,
+}
+
+/// Status cache of the daemon
+pub struct Daemon {
+    /// Backends computing the statuses
+    registry: Registry,
+    /// Settings from the configuration file (e.g., `VCP_BASE_REF`)
+    config:   Config,
+    /// Shortest pause between checks for changes
+    interval: Duration,
+    repos:    Mutex<HashMap<PathBuf, Entry>>,
+}
+
+impl Daemon {
+    /// Create a daemon that computes statuses with the backends in
+    /// *registry* and the settings in *config*.  Queried repositories are
+    /// checked for changes every *interval* at first, less often while
+    /// they do not change.
+    pub fn new(registry: Registry, config: Config, interval: Duration) -> Daemon {
+        Daemon {
+            registry,
+            config,
+            interval,
+            repos: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Listen on *path* until the process is terminated
+    pub fn serve(self, path: &Path) -> Result<()> {
+        if UnixStream::connect(path).is_ok() {
+            bail!("A daemon is already listening on {:?}", path);
+        }
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            if !dir.exists() {
+                DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+            }
+            // Other users could replace the socket in their directories
+            let owner = fs::metadata(dir)?.uid();
+            if owner != uid() && owner != 0 {
+                bail!("{:?} belongs to user {}", dir, owner);
+            }
+        }
+        // Left behind by a daemon that was killed
+        let _ = fs::remove_file(path);
+        let listener =
+            UnixListener::bind(path).with_context(|| format!("Failed to bind {:?}", path))?;
+        fs::set_permissions(path, Permissions::from_mode(0o600))?;
+        info!("Listening on {:?}", path);
+
+        let daemon = Arc::new(self);
+        let refresher = Arc::clone(&daemon);
+        thread::spawn(move || refresher.refresh());
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Failed to accept connection: {}", e);
+                    continue;
+                }
+            };
+            let daemon = Arc::clone(&daemon);
+            thread::spawn(move || {
+                if let Err(e) = daemon.handle(stream) {
+                    debug!("Failed to handle request: {:#}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    /// Answer a single request
+    fn handle(&self, stream: UnixStream) -> Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut request = String::new();
+        BufReader::new(&stream).read_line(&mut request)?;
+        let request: Value = serde_json::from_str(&request)?;
+        let response = match request["rootdir"].as_str() {
+            Some(rootdir) => self.get(Path::new(rootdir), &request["settings"]),
+            None => Err(format_err!("Invalid request: {}", request)),
+        };
+        let response =
+            response.unwrap_or_else(|e| json!({ "error": format!("{:#}", e) }).to_string());
+        (&stream).write_all(response.as_bytes())?;
+        (&stream).write_all(b"\n")?;
+        Ok(())
+    }
+
+    /// Return the status of *rootdir* for a client with *settings*.  The
+    /// cached status is only recomputed if it is unknown or the VCS
+    /// metadata or the work tree changed since.
+    fn get(&self, rootdir: &Path, settings: &Value) -> Result<String> {
+        let vcs = self
+            .registry
+            .find(rootdir)
+            .filter(|vcs| vcs.rootdir() == rootdir)
+            .with_context(|| format!("No repository at {:?}", rootdir))?;
+        if *settings != self::settings(vcs.backend(), &self.config) {
+            bail!("The daemon uses different settings");
+        }
+        let metadata = metadata_fingerprint(&vcs, &self.config);
+        let cached = match self.repos.lock().unwrap().get_mut(rootdir) {
+            Some(entry) => {
+                // Files are likely to change after a prompt was shown
+                let now = Instant::now();
+                entry.requested = now;
+                entry.backoff = self.interval;
+                entry.next_check = entry.next_check.min(now + self.interval);
+                Some(entry.tree).filter(|_| entry.metadata == metadata)
+            }
+            None => None,
+        };
+        // Files may have been edited since the last check; walking the work
+        // tree is still cheaper than computing the status
+        let tree = tree_fingerprint(&vcs);
+        if cached == Some(tree) {
+            if let Some(entry) = self.repos.lock().unwrap().get(rootdir) {
+                return Ok(entry.response.clone());
+            }
+        }
+        self.update(vcs, metadata, tree)
+    }
+
+    /// Compute the status of *vcs* and cache it along with the
+    /// fingerprints taken before
+    fn update(&self, vcs: VCContext, metadata: u64, tree: u64) -> Result<String> {
+        let rootdir = vcs.rootdir().to_path_buf();
+        let response = status_json(&vcs.get_status(&self.config)?).to_string();
+        let mut repos = self.repos.lock().unwrap();
+        let now = Instant::now();
+        let entry = repos.entry(rootdir).or_insert_with(|| Entry {
+            response:   String::new(),
+            metadata:   0,
+            tree:       0,
+            requested:  now,
+            backoff:    self.interval,
+            next_check: now + self.interval,
+        });
+        entry.response = response.clone();
+        entry.metadata = metadata;
+        entry.tree = tree;
+        Ok(response)
+    }
+
+    /// Check the repositories that were queried recently for changes (e.g.,
+    /// edited files) and recompute the statuses of those that changed.
+    /// Repositories are checked less often the longer they do not change.
+    fn refresh(&self) {
+        loop {
+            thread::sleep(self.interval);
+            let now = Instant::now();
+            let due: Vec<PathBuf> = {
+                let mut repos = self.repos.lock().unwrap();
+                repos.retain(|_, entry| entry.requested.elapsed() < IDLE_TIMEOUT);
+                repos
+                    .iter()
+                    .filter(|(_, entry)| entry.next_check <= now)
+                    .map(|(rootdir, _)| rootdir.clone())
+                    .collect()
+            };
+            for rootdir in due {
+                let vcs = match self.registry.find(&rootdir) {
+                    Some(vcs) if vcs.rootdir() == rootdir => vcs,
+                    _ => {
+                        self.repos.lock().unwrap().remove(&rootdir);
+                        continue;
+                    }
+                };
+                let metadata = metadata_fingerprint(&vcs, &self.config);
+                let tree = tree_fingerprint(&vcs);
+                let changed = match self.repos.lock().unwrap().get_mut(&rootdir) {
+                    Some(entry) => {
+                        let changed = entry.metadata != metadata || entry.tree != tree;
+                        entry.backoff = if changed {
+                            self.interval
+                        } else {
+                            (entry.backoff * 2).min(MAX_BACKOFF)
+                        };
+                        entry.next_check = Instant::now() + entry.backoff;
+                        changed
+                    }
+                    None => false,
+                };
+                if !changed {
+                    continue;
+                }
+                if let Err(e) = self.update(vcs, metadata, tree) {
+                    debug!("Failed to refresh {:?}: {:#}", rootdir, e);
+                }
+            }
+        }
+    }
+}
+
+/// Values of the settings that change the status of a repository of
+/// *backend* rather than how it is shown: the environment variables in
+/// `SETTINGS` and the base ref in *config*
+fn settings(backend: &dyn Backend, config: &Config) -> Value {
+    let mut settings: serde_json::Map<_, _> = SETTINGS
+        .iter()
+        .map(|name| (name.to_string(), json!(env::var(name).ok())))
+        .collect();
+    let vcs = backend.name().to_lowercase();
+    settings.insert(
+        "base_ref".to_owned(),
+        json!(config.get(&vcs, "VCP_BASE_REF")),
+    );
+    Value::Object(settings)
+}
+
+/// Stat data that changes when a file is modified or replaced
+fn stat(meta: &Metadata) -> (u64, u64, i64, i64) {
+    (meta.ino(), meta.len(), meta.mtime(), meta.mtime_nsec())
+}
+
+/// Hash the stat data of the paths watched by the backend of *vcs*,
+/// including missing ones and the direct entries of directories
+fn metadata_fingerprint(vcs: &VCContext, config: &Config) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for path in vcs.backend().watched_paths(vcs.rootdir(), config) {
+        let meta = fs::metadata(&path).ok();
+        (&path, meta.as_ref().map(stat)).hash(&mut hasher);
+        if !meta.is_some_and(|meta| meta.is_dir()) {
+            continue;
+        }
+        let mut entries: Vec<_> = match fs::read_dir(&path) {
+            Ok(entries) => entries
+                .filter_map(|e| e.ok())
+                .filter_map(|e| Some((e.file_name(), stat(&e.metadata().ok()?))))
+                .collect(),
+            Err(_) => continue,
+        };
+        entries.sort();
+        entries.hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+/// Hash the stat data of every file and directory in the work tree of
+/// *vcs*, so that edits are noticed without running the backend.  VCS
+/// metadata, ignored directories and nested repositories are skipped.
+fn tree_fingerprint(vcs: &VCContext) -> u64 {
+    let rootdir = vcs.rootdir();
+    let mut ignored = vcs.backend().ignored_dirs(rootdir);
+    let mut fingerprint = 0u64;
+    let mut dirs = vec![rootdir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries: Vec<_> = match fs::read_dir(&dir) {
+            Ok(entries) => entries.filter_map(|e| e.ok()).collect(),
+            Err(_) => continue,
+        };
+        let is_metadata =
+            |entry: &fs::DirEntry| METADATA.iter().any(|name| entry.file_name() == *name);
+        if dir != rootdir && entries.iter().any(is_metadata) {
+            continue;
+        }
+        for entry in entries.iter().filter(|entry| !is_metadata(entry)) {
+            // Symbolic links are not followed
+            let meta = match entry.metadata() {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            let path = entry.path();
+            let mut hasher = DefaultHasher::new();
+            if meta.is_dir() {
+                // Changes inside are found by walking it, unless it is ignored
+                (&path, meta.ino()).hash(&mut hasher);
+                if !ignored(path.strip_prefix(rootdir).unwrap_or(&path)) {
+                    dirs.push(path);
+                }
+            } else {
+                (&path, stat(&meta)).hash(&mut hasher);
+            }
+            // The order of directory entries is unspecified
+            fingerprint = fingerprint.wrapping_add(hasher.finish());
+        }
+    }
+    fingerprint
+}
+
+/// Ask the daemon listening on *path* for the status of *vcs*, which fails
+/// if the daemon's settings differ from *config* and the environment.
+///
+/// Fails without connecting if the socket belongs to another user, who
+/// could send a made-up status.
+pub fn query(path: &Path, vcs: &VCContext, config: &Config) -> Result<Status> {
+    check_owner(path, uid())?;
+    let stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let request = json!({
+        "rootdir": vcs.rootdir(),
+        "settings": settings(vcs.backend(), config),
+    });
+    (&stream).write_all(format!("{}\n", request).as_bytes())?;
+    let mut response = String::new();
+    BufReader::new(&stream).read_line(&mut response)?;
+    let value: Value = serde_json::from_str(&response).context("Invalid response")?;
+    if let Some(error) = value["error"].as_str() {
+        bail!("{}", error);
+    }
+    status_from_json(&value, vcs)
+}
+
+/// Turn an operation name into the `&'static str` used by `Status`.
+///
+/// Strings are leaked, which is fine for a client that exits after
+/// printing the prompt.
+fn intern(name: &str) -> &'static str {
+    Box::leak(name.to_string().into_boxed_str())
+}
+
+/// Parse the JSON status (as printed by `--json`) of the repository *vcs*
+fn status_from_json(value: &Value, vcs: &VCContext) -> Result<Status> {
+    if value["version"] != JSON_SCHEMA_VERSION {
+        bail!("Unsupported status version {}", value["version"]);
+    }
+    let backend = vcs.backend();
+    if value["vcs"] != backend.name().to_lowercase() || value["rootdir"] != json!(vcs.rootdir()) {
+        bail!("Status of a different repository");
+    }
+    let field = |v: &Value, key: &str| -> Result<Value> {
+        v.get(key)
+            .cloned()
+            .with_context(|| format!("Missing field `{}`", key))
+    };
+    let string = |v: &Value, key: &str| -> Result<String> {
+        let value = field(v, key)?;
+        value
+            .as_str()
+            .map(String::from)
+            .context(format!("Invalid field `{}`", key))
+    };
+    let optional = |key: &str| -> Result<Option<String>> {
+        match field(value, key)? {
+            Value::Null => Ok(None),
+            _ => string(value, key).map(Some),
+        }
+    };
+    let count = |v: &Value, key: &str| -> Result<u32> {
+        let value = field(v, key)?;
+        let n = value.as_u64().context(format!("Invalid field `{}`", key))?;
+        Ok(n as u32)
+    };
+    let strings = |key: &str| -> Result<Vec<String>> {
+        let value = field(value, key)?;
+        let list = value
+            .as_array()
+            .context(format!("Invalid field `{}`", key))?;
+        list.iter()
+            .map(|s| {
+                s.as_str()
+                    .map(String::from)
+                    .context(format!("Invalid field `{}`", key))
+            })
+            .collect()
+    };
+
+    let mut status = Status::new(backend);
+    status.symbol = string(value, "symbol")?;
+    status.branch = string(value, "branch")?;
+    status.ref_kind = string(value, "ref_kind")?.parse()?;
+    status.commit = string(value, "commit")?;
+    status.upstream = optional("upstream")?;
+    status.remote = optional("remote")?;
+    status.tracking = string(value, "tracking")?.parse()?;
+    status.ahead = count(value, "ahead")?;
+    status.behind = count(value, "behind")?;
+    status.phase = optional("phase")?;
+    status.bookmarks = strings("bookmarks")?;
+    status.bookmark = optional("bookmark")?;
+    status.topic = optional("topic")?;
+    status.unstable = strings("unstable")?;
+    status.base = optional("base")?;
+    status.base_ahead = count(value, "base_ahead")?;
+    status.base_behind = count(value, "base_behind")?;
+    status.staged = count(value, "staged")?;
+    status.changed = count(value, "changed")?;
+    status.untracked = count(value, "untracked")?;
+    status.conflicts = count(value, "conflicts")?;
+    status.stashes = count(value, "stashes")?;
+    let files = field(value, "files")?;
+    status.files = FileCounts {
+        added:   count(&files, "added")?,
+        deleted: count(&files, "deleted")?,
+        renamed: count(&files, "renamed")?,
+        missing: count(&files, "missing")?,
+    };
+    let diff = field(value, "diff")?;
+    status.added = count(&diff, "added")?;
+    status.deleted = count(&diff, "deleted")?;
+    status.operations = strings("operations")?.iter().map(|op| intern(op)).collect();
+    status.progress = match field(value, "progress")? {
+        Value::Null => None,
+        p => Some(Progress {
+            operation: intern(&string(&p, "operation")?),
+            step:      count(&p, "step")?,
+            total:     count(&p, "total")?,
+            head_name: p["head_name"].as_str().map(String::from),
+        }),
+    };
+    status.rootdir = vcs.rootdir().to_path_buf();
+    status.worktree = optional("worktree")?;
+    status.bare = field(value, "bare")?
+        .as_bool()
+        .context("Invalid field `bare`")?;
+    Ok(status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        git::Git,
+        status::{RefKind, Tracking},
+        util::exec_cmd,
+        vcs::DirFilter,
+    };
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Backend counting how often the status is computed, which watches
+    /// the same files as the git backend
+    struct Counter(Arc<AtomicUsize>);
+
+    impl Backend for Counter {
+        fn name(&self) -> &'static str {
+            "Counter"
+        }
+
+        fn symbol(&self) -> &'static str {
+            "c"
+        }
+
+        fn detect(&self, dir: &Path) -> bool {
+            Git.detect(dir)
+        }
+
+        fn status(&self, _rootdir: &Path) -> Result<Status> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            let mut status = Status::new(self);
+            status.branch = "main".to_owned();
+            Ok(status)
+        }
+
+        fn watched_paths(&self, rootdir: &Path, config: &Config) -> Vec<PathBuf> {
+            Git.watched_paths(rootdir, config)
+        }
+
+        fn ignored_dirs(&self, rootdir: &Path) -> DirFilter {
+            Git.ignored_dirs(rootdir)
+        }
+    }
+
+    fn sample(rootdir: &Path) -> Status {
+        let mut status = Status::new(&Git);
+        status.branch = "v1.0~2".to_owned();
+        status.ref_kind = RefKind::Tag;
+        status.commit = "dc716b061d9a0bc6a59f4e02d72b9952cce28927".to_owned();
+        status.upstream = Some("origin/main".to_owned());
+        status.tracking = Tracking::Gone;
+        status.ahead = 2;
+        status.unstable = vec!["orphan".to_owned()];
+        status.staged = 1;
+        status.files.renamed = 1;
+        status.added = 10;
+        status.operations = vec!["REBASE"];
+        status.progress = Some(Progress {
+            operation: "REBASE",
+            step:      3,
+            total:     12,
+            head_name: Some("main".to_owned()),
+        });
+        status.rootdir = rootdir.to_path_buf();
+        status
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let rootdir = Path::new("/src/project");
+        let vcs = VCContext::new(Arc::new(Git), rootdir);
+        let status = sample(rootdir);
+        let value = status_json(&status);
+        assert_eq!(status_from_json(&value, &vcs).unwrap(), status);
+
+        let other = VCContext::new(Arc::new(Git), "/src/other");
+        assert!(status_from_json(&value, &other).is_err());
+        let mut value = value;
+        value.as_object_mut().unwrap().remove("staged");
+        assert!(status_from_json(&value, &vcs).is_err());
+    }
+
+    #[test]
+    fn socket_owner() {
+        let path = env::temp_dir();
+        let owner = fs::metadata(&path).unwrap().uid();
+        assert!(check_owner(&path, owner).is_ok());
+        assert!(check_owner(&path, owner + 1).is_err());
+        assert!(check_owner(&path.join("test-vcprompt-missing.sock"), owner).is_err());
+    }
+
+    #[test]
+    fn serves_cached_statuses() {
+        let mut dir = env::temp_dir();
+        dir.push("test-vcprompt-daemon");
+        let _ = fs::remove_dir_all(&dir);
+        let rootdir = dir.join("repo");
+        fs::create_dir_all(rootdir.join("target")).unwrap();
+        fs::create_dir_all(rootdir.join("nested/.git")).unwrap();
+        let git = |args: &[&str]| {
+            let mut full = vec!["-c", "user.name=T", "-c", "user.email=t@example.com"];
+            full.extend_from_slice(args);
+            exec_cmd("git", &full, &rootdir).unwrap()
+        };
+        // Renaming makes every change atomic for the background checks
+        let write = |name: &str, contents: &str| {
+            let tmp = dir.join("tmp");
+            fs::write(&tmp, contents).unwrap();
+            fs::rename(&tmp, rootdir.join(name)).unwrap();
+        };
+        git(&["init", "-q", "-b", "main"]);
+        write(".gitignore", "target/\n");
+        write("file.txt", "one\n");
+        git(&["add", "file.txt", ".gitignore"]);
+        git(&["commit", "-q", "-m", "first"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "second"]);
+        git(&["update-ref", "refs/remotes/origin/main", "HEAD~"]);
+        git(&["config", "branch.main.remote", "origin"]);
+        git(&["config", "branch.main.merge", "refs/heads/main"]);
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut registry = Registry::empty();
+        registry.register(Box::new(Counter(Arc::clone(&calls))));
+        let socket = dir.join("daemon.sock");
+        let daemon = Daemon::new(registry, Config::default(), Duration::from_millis(10));
+        let path = socket.clone();
+        thread::spawn(move || daemon.serve(&path).unwrap());
+
+        let vcs = VCContext::new(Arc::new(Counter(Arc::clone(&calls))), &rootdir);
+        let config = Config::default();
+        let started = Instant::now();
+        let status = loop {
+            match query(&socket, &vcs, &config) {
+                Ok(status) => break status,
+                Err(_) if started.elapsed() < TIMEOUT => thread::sleep(Duration::from_millis(10)),
+                Err(e) => panic!("{:#}", e),
+            }
+        };
+        assert_eq!(status.branch, "main");
+        assert_eq!(status.rootdir, rootdir);
+        let count = || calls.load(Ordering::SeqCst);
+        assert_eq!(count(), 1);
+        // Nothing changed, so neither the background checks nor queries
+        // recompute it
+        thread::sleep(Duration::from_millis(100));
+        query(&socket, &vcs, &config).unwrap();
+        assert_eq!(count(), 1);
+
+        // Pushing moves the upstream ref
+        git(&["update-ref", "refs/remotes/origin/main", "HEAD"]);
+        query(&socket, &vcs, &config).unwrap();
+        assert_eq!(count(), 2);
+
+        // Ignored directories and nested repositories are not checked
+        write("target/out.o", "built");
+        write("nested/file.txt", "nested");
+        thread::sleep(Duration::from_millis(100));
+        query(&socket, &vcs, &config).unwrap();
+        assert_eq!(count(), 2);
+
+        // Edited files are noticed when the status is requested
+        write("file.txt", "edited");
+        query(&socket, &vcs, &config).unwrap();
+        assert_eq!(count(), 3);
+        query(&socket, &vcs, &config).unwrap();
+        assert_eq!(count(), 3);
+
+        // and in the background
+        write("file.txt", "edited again");
+        while count() < 4 && started.elapsed() < 2 * TIMEOUT {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(count(), 4);
+        query(&socket, &vcs, &config).unwrap();
+        assert_eq!(count(), 4);
+
+        // Clients with other settings compute the status themselves
+        let other_config = Config::parse("base_ref = \"origin/main\"").unwrap();
+        assert!(query(&socket, &vcs, &other_config).is_err());
+
+        // Only repository roots are served
+        let other = VCContext::new(Arc::new(Counter(Arc::clone(&calls))), &dir);
+        assert!(query(&socket, &other, &config).is_err());
+        assert_eq!(count(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,208 +1,344 @@
+//! Render a status as prompt string
 use crate::{
-    status::Status,
+    config::Config,
+    status::{RefKind, Status, Tracking},
+    template::{Template, Value},
     util::{globals::*, logger::*},
 };
-use anyhow::Result;
-use std::{collections::HashMap, env};
+use anyhow::{bail, Result};
+use serde_json::json;
+use std::{collections::HashMap, env, str::FromStr};
+
+/// Version of the JSON output schema, incremented on incompatible changes
+pub const JSON_SCHEMA_VERSION: u32 = 1;
 
 /// Available formatting styles
 pub enum OutputStyle {
+    /// Name, branch, tracking, operations and local status
     Detailed,
+    /// Branch and tracking only
     Minimal,
+    /// Format string with placeholders and groups (`-f` or `VCP_FORMAT`)
     FormatString,
+    /// Complete status as JSON object, without colors
+    Json,
+}
+
+/// Shell the prompt is rendered for
+///
+/// Shells need non-printing sequences (color codes) to be marked to
+/// calculate the prompt width correctly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shell {
+    /// Raw escape sequences
+    Plain,
+    /// `\[...\]`, for `PS1` assigned in `PROMPT_COMMAND`; `\`, `$` and `` ` ``
+    /// are escaped so that the prompt does not expand them
+    Bash,
+    /// `%{...%}`, literal `%` escaped as `%%`
+    Zsh,
+    /// Raw escape sequences, fish ignores them when measuring the prompt
+    Fish,
+    /// `%{...%}`, literal `%` escaped as `%%` and `!` (history event) as
+    /// `\!`
+    Tcsh,
+}
+
+impl FromStr for Shell {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "plain" | "none" => Shell::Plain,
+            "bash" => Shell::Bash,
+            "zsh" => Shell::Zsh,
+            "fish" => Shell::Fish,
+            "tcsh" | "csh" => Shell::Tcsh,
+            _ => bail!("Unknown shell `{}` (expected bash, zsh, fish or tcsh)", s),
+        })
+    }
+}
+
+impl Shell {
+    /// Mark the escape sequence *code* as non-printing
+    fn wrap(self, code: &str) -> String {
+        match self {
+            Shell::Plain | Shell::Fish => code.to_string(),
+            Shell::Bash => format!("\\[{}\\]", code),
+            Shell::Zsh | Shell::Tcsh => format!("%{{{}%}}", code),
+        }
+    }
+
+    /// Escape characters of *text* that are special in prompts
+    fn escape(self, text: &str) -> String {
+        match self {
+            // bash decodes backslash escapes before it expands the prompt
+            // (`promptvars`), so every backslash is doubled once more
+            Shell::Bash => text
+                .replace('\\', "\\\\\\\\")
+                .replace('$', "\\\\$")
+                .replace('`', "\\\\`"),
+            Shell::Zsh => text.replace('%', "%%"),
+            Shell::Tcsh => text.replace('%', "%%").replace('!', "\\!"),
+            _ => text.to_string(),
+        }
+    }
 }
 
 /// Get formatted output depending on OutputStyle
+///
+/// Color codes are wrapped as required by *shell*.  Settings not given as
+/// environment variable are looked up in *config*.
 pub fn get_output(
     status: &Status,
     style: OutputStyle,
     fmt_string: Option<String>,
+    shell: Shell,
+    config: &Config,
 ) -> Result<String> {
-    let variables: HashMap<&'static str, String> = [
+    let defaults = [
         ("VCP_PREFIX", ""),
         ("VCP_SUFFIX", "{reset}"),
         ("VCP_SEPARATOR", "{reset}|"),
         ("VCP_NAME", "{symbol}"),
+        ("VCP_SYMBOL", &status.symbol),
         ("VCP_BRANCH", "{cyan}{value}{reset}"),
+        ("VCP_TAG", "{magenta}{value}{reset}"),
+        ("VCP_DETACHED", "{cyan}({value}){reset}"),
+        ("VCP_BOOKMARK", "{cyan}*{value}{reset}"),
+        ("VCP_TOPIC", "{cyan}//{value}{reset}"),
+        ("VCP_UNSTABLE", "{red}{value}{reset}"),
+        ("VCP_UPSTREAM", "{gray}{value}{reset}"),
+        ("VCP_REMOTE", "{value}"),
+        ("VCP_NO_UPSTREAM", "{gray}∅{reset}"),
+        ("VCP_GONE", "{red}✗{reset}"),
         ("VCP_DIFF", "{value}"),
+        ("VCP_PHASE", "{gray}{value}{reset}"),
         ("VCP_COMMIT", "{black_on_green}{value}{reset}"),
         ("VCP_OPERATION", "{red}{value}{reset}"),
         ("VCP_BEHIND", "⇣{value}"),
         ("VCP_AHEAD", "⇡{value}"),
+        ("VCP_BASE_BEHIND", "{gray}↓{value}{reset}"),
+        ("VCP_BASE_AHEAD", "{gray}↑{value}{reset}"),
         ("VCP_STAGED", "{blue}●{value}"),
         ("VCP_CHANGED", "{yellow}Δ{value}"), // ✚
         ("VCP_CONFLICTS", "{red}‼{value}"),
         ("VCP_UNTRACKED", "{gray}…{value}"),
+        ("VCP_ADDED", "{green}+{value}"),
+        ("VCP_DELETED", "{red}-{value}"),
+        ("VCP_RENAMED", "{blue}»{value}"),
+        ("VCP_MISSING", "{yellow}!{value}"),
+        ("VCP_STASHED", "{magenta}⚑{value}"),
         ("VCP_CLEAN", "{green}{bold}✔"),
-    ]
-    .iter()
-    .map(|(k, v)| (*k, env::var(k).unwrap_or(v.to_string())))
-    .collect();
+        ("VCP_BARE", "{yellow}bare{reset}"),
+        ("VCP_FORMAT", "%n %b %o"),
+    ];
+    // Environment variables take precedence over the config file
+    let vcs = status.name.to_lowercase();
+    let variables: HashMap<&'static str, String> = defaults
+        .iter()
+        .map(|(k, v)| {
+            let value = env::var(k)
+                .ok()
+                .or_else(|| config.get(&vcs, k).map(String::from))
+                .unwrap_or_else(|| v.to_string());
+            (*k, value)
+        })
+        .collect();
     debug!("{:?}", variables);
 
     let mut output = match style {
-        OutputStyle::Detailed => format_full(&status, &variables)?,
-        OutputStyle::Minimal => format_minimal(&status, &variables)?,
-        OutputStyle::FormatString => format_from_string(&status, &variables, fmt_string)?,
+        OutputStyle::Detailed => format_full(status, &variables)?,
+        OutputStyle::Minimal => format_minimal(status, &variables)?,
+        OutputStyle::FormatString => format_from_string(status, &variables, fmt_string)?,
+        // Values are emitted verbatim, without color codes
+        OutputStyle::Json => return format_json(status),
     };
 
+    output = shell.escape(&output);
     for (k, v) in COLORS.iter() {
-        output = output.replace(k, v);
+        output = output.replace(k, &shell.wrap(v));
     }
     Ok(output)
 }
 
+/// Format *status* with a format string (see [`crate::template`])
 fn format_from_string(
     status: &Status,
     variables: &HashMap<&'static str, String>,
     fmt_string: Option<String>,
 ) -> Result<String> {
-    let mut output = String::with_capacity(100);
-    // TODO: should this be combined with `variables`?
-    let fmt_string = fmt_string
-        .unwrap_or_else(|| env::var("VCP_FORMAT").unwrap_or_else(|_| String::from("%n %b %o")));
-    let mut fmt_string_chars = fmt_string.chars();
-
-    while let Some(c) = fmt_string_chars.next() {
-        if c == '%' {
-            if let Some(c) = fmt_string_chars.next() {
-                match &c {
-                    'n' => output.push_str(
-                        &variables
-                            .get("VCP_NAME")
-                            .unwrap()
-                            .replace("{value}", &status.name.to_string())
-                            .replace("{symbol}", &status.symbol),
-                    ),
-                    'b' => output.push_str(
-                        &variables
-                            .get("VCP_BRANCH")
-                            .unwrap()
-                            .replace("{value}", &status.branch),
-                    ),
-                    'c' => output.push_str(
-                        &variables
-                            .get("VCP_COMMIT")
-                            .unwrap()
-                            .replace("{value}", status.fmt_commit(7)),
-                    ),
-                    'd' => {
-                        output.push_str(
-                            &variables
-                                .get("VCP_DIFF")
-                                .unwrap()
-                                .replace("{value}", &status.fmt_diff().unwrap_or_default()),
-                        );
-                    }
-                    'A' => {
-                        if status.ahead > 0 {
-                            output.push_str(
-                                &variables
-                                    .get("VCP_AHEAD")
-                                    .unwrap()
-                                    .replace("{value}", &status.ahead.to_string()),
-                            )
-                        }
-                    }
-                    'B' => {
-                        if status.behind > 0 {
-                            output.push_str(
-                                &variables
-                                    .get("VCP_BEHIND")
-                                    .unwrap()
-                                    .replace("{value}", &status.behind.to_string()),
-                            )
-                        }
-                    }
-                    's' => {
-                        if status.staged > 0 {
-                            output.push_str(
-                                &variables
-                                    .get("VCP_STAGED")
-                                    .unwrap()
-                                    .replace("{value}", &status.staged.to_string()),
-                            )
-                        }
-                    }
-                    // Unmerged
-                    'U' => {
-                        if status.conflicts > 0 {
-                            output.push_str(
-                                &variables
-                                    .get("VCP_CONFLICTS")
-                                    .unwrap()
-                                    .replace("{value}", &status.conflicts.to_string()),
-                            )
-                        }
-                    }
-                    // Modified
-                    'm' => {
-                        if status.changed > 0 {
-                            output.push_str(
-                                &variables
-                                    .get("VCP_CHANGED")
-                                    .unwrap()
-                                    .replace("{value}", &status.changed.to_string()),
-                            )
-                        }
-                    }
-                    'u' => {
-                        if status.untracked > 0 {
-                            output.push_str(
-                                &variables
-                                    .get("VCP_UNTRACKED")
-                                    .unwrap()
-                                    .replace("{value}", &status.untracked.to_string()),
-                            )
-                        }
-                    }
-                    'o' => {
-                        for op in status.operations.iter() {
-                            output.push_str(
-                                &variables
-                                    .get("VCP_OPERATION")
-                                    .unwrap()
-                                    .replace("{value}", op),
-                            );
-                        }
-                    }
-                    _ => output.push(c),
-                }
-            }
-        } else {
-            // Push unchaged to string
-            output.push(c);
-        }
-    }
-    if status.is_clean() {
-        output.push_str(&variables.get("VCP_CLEAN").unwrap());
-    }
-    output.push_str(&variables.get("VCP_SUFFIX").unwrap());
+    let fmt_string = fmt_string.unwrap_or_else(|| variables.get("VCP_FORMAT").unwrap().clone());
+    let template = Template::parse(&fmt_string)?;
+    let mut output = template.render(|c| placeholder(c, status, variables));
 
-    for (k, v) in COLORS.iter() {
-        output = output.replace(k, v);
+    if status.is_clean() && !status.bare {
+        output.push_str(variables.get("VCP_CLEAN").unwrap());
     }
+    output.push_str(variables.get("VCP_SUFFIX").unwrap());
     Ok(output)
 }
 
+/// Style the branch of *status* with `VCP_BRANCH`, `VCP_TAG` or
+/// `VCP_DETACHED`, depending on what it refers to
+fn fmt_branch(status: &Status, variables: &HashMap<&'static str, String>) -> String {
+    let name = match status.ref_kind {
+        RefKind::Branch => "VCP_BRANCH",
+        RefKind::Tag => "VCP_TAG",
+        RefKind::Commit => "VCP_DETACHED",
+    };
+    variables
+        .get(name)
+        .unwrap()
+        .replace("{value}", &status.branch)
+}
+
+/// Append the active bookmark and topic of *status* (hg) to the styled
+/// branch
+fn fmt_branch_extras(status: &Status, variables: &HashMap<&'static str, String>) -> String {
+    let mut output = String::new();
+    if let Some(bookmark) = &status.bookmark {
+        output.push_str(
+            &variables
+                .get("VCP_BOOKMARK")
+                .unwrap()
+                .replace("{value}", bookmark),
+        );
+    }
+    if let Some(topic) = &status.topic {
+        output.push_str(
+            &variables
+                .get("VCP_TOPIC")
+                .unwrap()
+                .replace("{value}", topic),
+        );
+    }
+    output
+}
+
+/// Style the upstream branch of *status* with `VCP_UPSTREAM`, or its absence
+/// with `VCP_NO_UPSTREAM`/`VCP_GONE`.  Returns `None` if it is unknown.
+fn fmt_upstream(status: &Status, variables: &HashMap<&'static str, String>) -> Option<String> {
+    let name = match status.tracking {
+        Tracking::Unknown => return None,
+        Tracking::None => "VCP_NO_UPSTREAM",
+        Tracking::Gone => "VCP_GONE",
+        Tracking::Upstream => "VCP_UPSTREAM",
+    };
+    let upstream = status.upstream.as_deref().unwrap_or_default();
+    Some(variables.get(name).unwrap().replace("{value}", upstream))
+}
+
+/// Render the placeholder `%c` of a format string.
+///
+/// Returns `None` for unknown placeholders. Counts of zero render as empty
+/// string.
+fn placeholder(
+    c: char,
+    status: &Status,
+    variables: &HashMap<&'static str, String>,
+) -> Option<Value> {
+    let styled = |name: &str, value: &str| variables.get(name).unwrap().replace("{value}", value);
+    let count = |name: &str, value: u32| {
+        if value > 0 {
+            (styled(name, &value.to_string()), true)
+        } else {
+            (String::new(), false)
+        }
+    };
+    let value = match c {
+        'n' => (
+            styled("VCP_NAME", status.name)
+                .replace("{symbol}", variables.get("VCP_SYMBOL").unwrap()),
+            true,
+        ),
+        'b' => (fmt_branch(status, variables), !status.branch.is_empty()),
+        'c' => (
+            styled("VCP_COMMIT", status.fmt_commit(7)),
+            !status.commit.is_empty(),
+        ),
+        'd' => {
+            let diff = status.fmt_diff().unwrap_or_default();
+            (styled("VCP_DIFF", &diff), !diff.is_empty())
+        }
+        't' => match fmt_upstream(status, variables) {
+            Some(upstream) => (upstream, true),
+            None => (String::new(), false),
+        },
+        'r' => match &status.remote {
+            Some(remote) => (styled("VCP_REMOTE", remote), true),
+            None => (String::new(), false),
+        },
+        'p' => match &status.phase {
+            Some(phase) => (styled("VCP_PHASE", phase), true),
+            None => (String::new(), false),
+        },
+        'k' => match &status.bookmark {
+            Some(bookmark) => (styled("VCP_BOOKMARK", bookmark), true),
+            None => (String::new(), false),
+        },
+        'T' => match &status.topic {
+            Some(topic) => (styled("VCP_TOPIC", topic), true),
+            None => (String::new(), false),
+        },
+        'e' => (
+            styled("VCP_UNSTABLE", &status.unstable.join(",")),
+            !status.unstable.is_empty(),
+        ),
+        'A' => count("VCP_AHEAD", status.ahead),
+        'B' => count("VCP_BEHIND", status.behind),
+        // Compared with the base ref, `{base}` is its name
+        '>' | '<' => {
+            let (name, value) = match c {
+                '>' => ("VCP_BASE_AHEAD", status.base_ahead),
+                _ => ("VCP_BASE_BEHIND", status.base_behind),
+            };
+            let (value, non_empty) = count(name, value);
+            let base = status.base.as_deref().unwrap_or_default();
+            (value.replace("{base}", base), non_empty)
+        }
+        's' => count("VCP_STAGED", status.staged),
+        // Unmerged
+        'U' => count("VCP_CONFLICTS", status.conflicts),
+        // Modified
+        'm' => count("VCP_CHANGED", status.changed),
+        'u' => count("VCP_UNTRACKED", status.untracked),
+        'S' => count("VCP_STASHED", status.stashes),
+        'a' => count("VCP_ADDED", status.files.added),
+        'D' => count("VCP_DELETED", status.files.deleted),
+        'R' => count("VCP_RENAMED", status.files.renamed),
+        'M' => count("VCP_MISSING", status.files.missing),
+        'o' => (
+            status
+                .operations
+                .iter()
+                .map(|op| styled("VCP_OPERATION", &status.fmt_operation(op)))
+                .collect(),
+            !status.operations.is_empty(),
+        ),
+        _ => return None,
+    };
+    Some(value)
+}
+
 /// Format *status* in detailed style
 /// (`{name}{branch}{branch tracking}|{local status}`).
 fn format_full(status: &Status, variables: &HashMap<&'static str, String>) -> Result<String> {
     let mut output = String::with_capacity(100);
-    output.push_str(&variables.get("VCP_PREFIX").unwrap());
+    output.push_str(variables.get("VCP_PREFIX").unwrap());
     output.push_str(
         &variables
             .get("VCP_NAME")
             .unwrap()
-            .replace("{value}", &status.name.to_string())
-            .replace("{symbol}", &status.symbol),
-    );
-    output.push_str(
-        &variables
-            .get("VCP_BRANCH")
-            .unwrap()
-            .replace("{value}", &status.branch),
+            .replace("{value}", status.name)
+            .replace("{symbol}", variables.get("VCP_SYMBOL").unwrap()),
     );
+    output.push_str(&fmt_branch(status, variables));
+    output.push_str(&fmt_branch_extras(status, variables));
+    // Only the absence of an upstream branch is worth noting here
+    if matches!(status.tracking, Tracking::None | Tracking::Gone) {
+        output.push_str(&fmt_upstream(status, variables).unwrap_or_default());
+    }
     if status.behind > 0 {
         output.push_str(
             &variables
@@ -219,16 +355,31 @@ fn format_full(status: &Status, variables: &HashMap<&'static str, String>) -> Re
                 .replace("{value}", &status.ahead.to_string()),
         );
     }
+    for instability in status.unstable.iter() {
+        output.push_str(variables.get("VCP_SEPARATOR").unwrap());
+        output.push_str(
+            &variables
+                .get("VCP_UNSTABLE")
+                .unwrap()
+                .replace("{value}", instability),
+        );
+    }
     for op in status.operations.iter() {
-        output.push_str(&variables.get("VCP_SEPARATOR").unwrap());
+        output.push_str(variables.get("VCP_SEPARATOR").unwrap());
         output.push_str(
             &variables
                 .get("VCP_OPERATION")
                 .unwrap()
-                .replace("{value}", op),
+                .replace("{value}", &status.fmt_operation(op)),
         );
     }
-    output.push_str(&variables.get("VCP_SEPARATOR").unwrap());
+    output.push_str(variables.get("VCP_SEPARATOR").unwrap());
+    // There is no local status without work tree
+    if status.bare {
+        output.push_str(variables.get("VCP_BARE").unwrap());
+        output.push_str(variables.get("VCP_SUFFIX").unwrap());
+        return Ok(output);
+    }
     if status.staged > 0 {
         output.push_str(
             &variables
@@ -261,23 +412,27 @@ fn format_full(status: &Status, variables: &HashMap<&'static str, String>) -> Re
                 .replace("{value}", &status.untracked.to_string()),
         );
     }
+    if status.stashes > 0 {
+        output.push_str(
+            &variables
+                .get("VCP_STASHED")
+                .unwrap()
+                .replace("{value}", &status.stashes.to_string()),
+        );
+    }
     if status.is_clean() {
-        output.push_str(&variables.get("VCP_CLEAN").unwrap());
+        output.push_str(variables.get("VCP_CLEAN").unwrap());
     }
-    output.push_str(&variables.get("VCP_SUFFIX").unwrap());
+    output.push_str(variables.get("VCP_SUFFIX").unwrap());
     Ok(output)
 }
 
 /// Format status in minimal style
 fn format_minimal(status: &Status, variables: &HashMap<&'static str, String>) -> Result<String> {
     let mut output = String::with_capacity(100);
-    output.push_str(&variables.get("VCP_PREFIX").unwrap());
-    output.push_str(
-        &variables
-            .get("VCP_BRANCH")
-            .unwrap()
-            .replace("{value}", &status.branch),
-    );
+    output.push_str(variables.get("VCP_PREFIX").unwrap());
+    output.push_str(&fmt_branch(status, variables));
+    output.push_str(&fmt_branch_extras(status, variables));
     if status.staged > 0 {
         output.push_str("{bold}{yellow}+{reset}");
     }
@@ -300,7 +455,299 @@ fn format_minimal(status: &Status, variables: &HashMap<&'static str, String>) ->
                 .replace("{value}", &status.ahead.to_string()),
         );
     }
-    output.push_str(&variables.get("VCP_SUFFIX").unwrap());
+    output.push_str(variables.get("VCP_SUFFIX").unwrap());
 
     Ok(output)
 }
+
+/// Format *status* as JSON object
+///
+/// All fields are always present; see `JSON_SCHEMA_VERSION`.
+fn format_json(status: &Status) -> Result<String> {
+    Ok(serde_json::to_string(&status_json(status))?)
+}
+
+/// Convert *status* into the JSON object printed by `--json`
+pub(crate) fn status_json(status: &Status) -> serde_json::Value {
+    json!({
+        "version": JSON_SCHEMA_VERSION,
+        "vcs": status.name.to_lowercase(),
+        "symbol": status.symbol,
+        "rootdir": status.rootdir,
+        "worktree": status.worktree,
+        "branch": status.branch,
+        "ref_kind": status.ref_kind.as_str(),
+        "commit": status.commit,
+        "upstream": status.upstream,
+        "remote": status.remote,
+        "tracking": status.tracking.as_str(),
+        "ahead": status.ahead,
+        "behind": status.behind,
+        "phase": status.phase,
+        "bookmarks": status.bookmarks,
+        "bookmark": status.bookmark,
+        "topic": status.topic,
+        "unstable": status.unstable,
+        "base": status.base,
+        "base_ahead": status.base_ahead,
+        "base_behind": status.base_behind,
+        "staged": status.staged,
+        "changed": status.changed,
+        "untracked": status.untracked,
+        "conflicts": status.conflicts,
+        "stashes": status.stashes,
+        "files": {
+            "added": status.files.added,
+            "deleted": status.files.deleted,
+            "renamed": status.files.renamed,
+            "missing": status.files.missing,
+        },
+        "diff": {
+            "added": status.added,
+            "deleted": status.deleted,
+        },
+        "operations": status.operations,
+        "progress": status.progress.as_ref().map(|p| json!({
+            "operation": p.operation,
+            "step": p.step,
+            "total": p.total,
+            "head_name": p.head_name,
+        })),
+        "bare": status.bare,
+        "clean": status.is_clean() && !status.bare,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{git::Git, hg::Hg};
+    use serde_json::Value;
+
+    #[test]
+    fn json_output() {
+        let mut status = Status::new(&Git);
+        status.branch = "{red}main".to_owned();
+        status.commit = "dc716b061d9a0bc6a59f4e02d72b9952cce28927".to_owned();
+        status.ahead = 2;
+        status.changed = 1;
+        status.added = 3;
+        status.operations = vec!["MERGING"];
+        status.rootdir = "/src/project".into();
+
+        let output = get_output(
+            &status,
+            OutputStyle::Json,
+            None,
+            Shell::Plain,
+            &Config::default(),
+        )
+        .unwrap();
+        let value: Value = serde_json::from_str(&output).unwrap();
+        assert_eq!(value["version"], JSON_SCHEMA_VERSION);
+        assert_eq!(value["vcs"], "git");
+        assert_eq!(value["rootdir"], "/src/project");
+        // Color placeholders are not replaced
+        assert_eq!(value["branch"], "{red}main");
+        assert_eq!(value["ref_kind"], "branch");
+        assert_eq!(value["upstream"], Value::Null);
+        assert_eq!(value["tracking"], "unknown");
+        assert_eq!(value["ahead"], 2);
+        assert_eq!(value["behind"], 0);
+        assert_eq!(value["diff"]["added"], 3);
+        assert_eq!(value["files"]["renamed"], 0);
+        assert_eq!(value["operations"][0], "MERGING");
+        assert_eq!(value["clean"], false);
+    }
+
+    #[test]
+    fn format_string_groups() {
+        let mut status = Status::new(&Git);
+        status.branch = "main".to_owned();
+        status.ahead = 2;
+        let variables: HashMap<&'static str, String> = [
+            ("VCP_BRANCH", "{value}"),
+            ("VCP_AHEAD", "+{value}"),
+            ("VCP_BEHIND", "-{value}"),
+            ("VCP_BASE_AHEAD", "{base}+{value}"),
+            ("VCP_BASE_BEHIND", "{base}-{value}"),
+            ("VCP_OPERATION", "{value}"),
+            ("VCP_CLEAN", "ok"),
+            ("VCP_SUFFIX", ""),
+        ]
+        .iter()
+        .map(|(k, v)| (*k, v.to_string()))
+        .collect();
+        let fmt = String::from("%b%( [%A%B]%)%( (%o)%)");
+        let output = format_from_string(&status, &variables, Some(fmt)).unwrap();
+        assert_eq!(output, "main [+2]ok");
+
+        status.base = Some("dev".to_owned());
+        status.base_behind = 3;
+        let fmt = String::from("%b%( %>%<%)");
+        let output = format_from_string(&status, &variables, Some(fmt)).unwrap();
+        assert_eq!(output, "main dev-3ok");
+    }
+
+    #[test]
+    fn config_overrides() {
+        let mut status = Status::new(&Git);
+        status.branch = "main".to_owned();
+        let config = Config::parse(
+            "format = \"%n:%b\"\nbranch = \"<{value}>\"\n[hg]\nsymbol = \"hg\"\n[git]\nsymbol = \"git\"",
+        )
+        .unwrap();
+        let output = get_output(
+            &status,
+            OutputStyle::FormatString,
+            None,
+            Shell::Plain,
+            &config,
+        );
+        assert!(output.unwrap().starts_with("git:<main>"));
+    }
+
+    #[test]
+    fn ref_kind_styles() {
+        let mut status = Status::new(&Git);
+        let config = Config::parse("format = \"%b\"\ntag = \"<{value}>\"").unwrap();
+        let render = |status: &Status| {
+            get_output(
+                status,
+                OutputStyle::FormatString,
+                None,
+                Shell::Plain,
+                &config,
+            )
+            .unwrap()
+        };
+        status.branch = "v1.0~2".to_owned();
+        status.ref_kind = RefKind::Tag;
+        assert!(render(&status).starts_with("<v1.0~2>"));
+        status.branch = "dc716b0".to_owned();
+        status.ref_kind = RefKind::Commit;
+        assert!(render(&status).starts_with("\x1B[36m(dc716b0)"));
+    }
+
+    #[test]
+    fn upstream_states() {
+        let mut status = Status::new(&Git);
+        status.branch = "main".to_owned();
+        let config = Config::parse(
+            "format = \"%b%( %t%)%( (%r)%)\"\nbranch = \"{value}\"\nupstream = \"{value}\"\n\
+             no_upstream = \"none\"\ngone = \"gone:{value}\"\nsuffix = \"\"\nclean = \"\"",
+        )
+        .unwrap();
+        let render = |status: &Status| {
+            get_output(
+                status,
+                OutputStyle::FormatString,
+                None,
+                Shell::Plain,
+                &config,
+            )
+            .unwrap()
+        };
+        assert_eq!(render(&status), "main");
+        status.tracking = Tracking::None;
+        assert_eq!(render(&status), "main none");
+        status.upstream = Some("origin/main".to_owned());
+        status.remote = Some("origin".to_owned());
+        status.tracking = Tracking::Gone;
+        assert_eq!(render(&status), "main gone:origin/main (origin)");
+        status.tracking = Tracking::Upstream;
+        assert_eq!(render(&status), "main origin/main (origin)");
+    }
+
+    #[test]
+    fn hg_bookmark_topic_unstable() {
+        let mut status = Status::new(&Hg);
+        status.branch = "default".to_owned();
+        status.staged = 1;
+        let config = Config::parse(
+            "branch = \"{value}\"\nbookmark = \"*{value}\"\ntopic = \"//{value}\"\n\
+             unstable = \"{value}\"\nseparator = \"|\"\nname = \"\"\nstaged = \"S\"\n\
+             suffix = \"\"",
+        )
+        .unwrap();
+        let render = |status: &Status, style, fmt: Option<&str>| {
+            get_output(status, style, fmt.map(String::from), Shell::Plain, &config).unwrap()
+        };
+        assert_eq!(render(&status, OutputStyle::Detailed, None), "default|S");
+        status.bookmark = Some("feature".to_owned());
+        status.topic = Some("login".to_owned());
+        status.unstable = vec!["obsolete".to_owned(), "orphan".to_owned()];
+        assert_eq!(
+            render(&status, OutputStyle::Detailed, None),
+            "default*feature//login|obsolete|orphan|S"
+        );
+        assert!(render(&status, OutputStyle::Minimal, None).starts_with("default*feature//login"));
+        assert_eq!(
+            render(&status, OutputStyle::FormatString, Some("%k %T %e")),
+            "*feature //login obsolete,orphan"
+        );
+        status.bookmarks = vec!["feature".to_owned(), "wip".to_owned()];
+        let value: Value = serde_json::from_str(&render(&status, OutputStyle::Json, None)).unwrap();
+        assert_eq!(value["bookmarks"][1], "wip");
+        assert_eq!(value["bookmark"], "feature");
+        assert_eq!(value["topic"], "login");
+        assert_eq!(value["unstable"][1], "orphan");
+    }
+
+    #[test]
+    fn shell_escapes() {
+        let mut status = Status::new(&Git);
+        status.branch = "100%!".to_owned();
+        let fmt = Some(String::from("{red}%b"));
+        let config = Config::default();
+        let render = |shell| {
+            get_output(
+                &status,
+                OutputStyle::FormatString,
+                fmt.clone(),
+                shell,
+                &config,
+            )
+        };
+        assert!(render(Shell::Plain)
+            .unwrap()
+            .starts_with("\x1B[31m\x1B[36m100%!"));
+        assert!(render(Shell::Bash)
+            .unwrap()
+            .starts_with("\\[\x1B[31m\\]\\[\x1B[36m\\]100%!"));
+        assert!(render(Shell::Zsh)
+            .unwrap()
+            .starts_with("%{\x1B[31m%}%{\x1B[36m%}100%%!"));
+        assert!(render(Shell::Tcsh)
+            .unwrap()
+            .starts_with("%{\x1B[31m%}%{\x1B[36m%}100%%\\!"));
+        assert_eq!("fish".parse::<Shell>().unwrap(), Shell::Fish);
+        assert!("cmd".parse::<Shell>().is_err());
+    }
+
+    #[test]
+    fn bash_expansions_escaped() {
+        let mut status = Status::new(&Git);
+        status.branch = "$(touch${IFS}pwned)`id`\\".to_owned();
+        status.upstream = Some("origin/$HOME".to_owned());
+        status.tracking = Tracking::Gone;
+        let config = Config::parse(
+            "format = \"%b %t\"\nbranch = \"{value}\"\ngone = \"{value}\"\nsuffix = \"\"\n\
+             clean = \"\"",
+        )
+        .unwrap();
+        let output = get_output(
+            &status,
+            OutputStyle::FormatString,
+            None,
+            Shell::Bash,
+            &config,
+        )
+        .unwrap();
+        // bash turns `\\` into `\`, which then escapes the next character
+        assert_eq!(
+            output,
+            "\\\\$(touch\\\\${IFS}pwned)\\\\`id\\\\`\\\\\\\\ origin/\\\\$HOME"
+        );
+    }
+}
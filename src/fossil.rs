@@ -0,0 +1,169 @@
+//! Get Fossil status
+use crate::{
+    config::Config,
+    status::Status,
+    util::{exec_cmd, logger::*, CommandOutput},
+    vcs::Backend,
+};
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+
+/// Fossil backend
+pub struct Fossil;
+
+impl Backend for Fossil {
+    fn name(&self) -> &'static str {
+        "Fossil"
+    }
+
+    fn symbol(&self) -> &'static str {
+        "fossil"
+    }
+
+    fn detect(&self, dir: &Path) -> bool {
+        dir.join(".fslckout").exists() || dir.join("_FOSSIL_").exists()
+    }
+
+    fn status(&self, rootdir: &Path) -> Result<Status> {
+        status(rootdir)
+    }
+
+    fn watched_paths(&self, rootdir: &Path, _config: &Config) -> Vec<PathBuf> {
+        vec![rootdir.join(".fslckout"), rootdir.join("_FOSSIL_")]
+    }
+}
+
+/// Get the status of the checkout at *rootdir*
+fn status(rootdir: &Path) -> Result<Status> {
+    let status_output = fossil_status(rootdir)?;
+    debug!("fossil status: {:?}", status_output.stdout);
+    let mut result = parse_status(&status_output.stdout);
+    match fossil_branch(rootdir) {
+        Ok(output) if !output.stdout.trim().is_empty() => {
+            result.branch = output.stdout.trim().to_string()
+        }
+        Ok(_) => (),
+        Err(e) => debug!("Failed to get branch: {:#}", e),
+    }
+    parse_changes(&fossil_changes(rootdir)?.stdout, &mut result);
+    Ok(result)
+}
+
+fn fossil_status(rootdir: &Path) -> Result<CommandOutput> {
+    exec_cmd("fossil", &["status"], rootdir)
+}
+
+/// Print the branch of the checkout; the `tags:` line of `fossil status`
+/// starts with it only if the check-in has no tag sorting before it
+fn fossil_branch(rootdir: &Path) -> Result<CommandOutput> {
+    exec_cmd("fossil", &["branch", "current"], rootdir)
+}
+
+/// List changed and unmanaged ("extra") files
+fn fossil_changes(rootdir: &Path) -> Result<CommandOutput> {
+    exec_cmd("fossil", &["changes", "--extra"], rootdir)
+}
+
+/// Parse the header of `fossil status` (check-in and operations)
+fn parse_status(status: &str) -> Status {
+    let mut result = Status::new(&Fossil);
+    for line in status.lines() {
+        let mut parts = line.split_whitespace();
+        match parts.next() {
+            // checkout: <hash> <date> <time> UTC
+            Some("checkout:") => {
+                if let Some(hash) = parts.next() {
+                    result.commit = hash.to_string();
+                }
+            }
+            Some("MERGED_WITH") | Some("CHERRYPICK") | Some("BACKOUT")
+                if !result.operations.contains(&"MERGING") =>
+            {
+                result.operations.push("MERGING")
+            }
+            _ => (),
+        }
+    }
+    result
+}
+
+/// Parse the output of `fossil changes --extra` and update the counts of
+/// *status*.
+///
+/// Additions, deletions and renames are scheduled for the next commit and
+/// count as staged, edits as changed and extra files as untracked.
+fn parse_changes(changes: &str, status: &mut Status) {
+    for line in changes.lines() {
+        match line.split_whitespace().next() {
+            Some("ADDED")
+            | Some("ADDED_BY_MERGE")
+            | Some("ADDED_BY_INTEGRATE")
+            | Some("DELETED")
+            | Some("RENAMED") => status.staged += 1,
+            Some("EDITED")
+            | Some("UPDATED_BY_MERGE")
+            | Some("UPDATED_BY_INTEGRATE")
+            | Some("MISSING")
+            | Some("EXECUTABLE")
+            | Some("SYMLINK")
+            | Some("UNEXEC")
+            | Some("UNLINK")
+            | Some("NOT_A_FILE") => status.changed += 1,
+            Some("CONFLICT") => status.conflicts += 1,
+            Some("EXTRA") => status.untracked += 1,
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_status_full() {
+        let output = "\
+repository:   /home/nick/repos/project.fossil
+local-root:   /home/nick/src/project/
+config-db:    /home/nick/.fossil
+checkout:     6d5d4a0b3cbf2e1e0a2b1e5f3e9c2d9f1c6b0b7a 2020-01-01 12:00:00 UTC
+parent:       1f0c4d1b8a2e3f4a5b6c7d8e9f0a1b2c3d4e5f6a 2019-12-31 12:00:00 UTC
+tags:         beta, feature
+comment:      Add feature (user: nick)
+EDITED     src/main.c
+MERGED_WITH 9a8b7c6d5e4f
+";
+        // The branch is not necessarily the first tag
+        let mut expected = Status::new(&Fossil);
+        expected.commit = "6d5d4a0b3cbf2e1e0a2b1e5f3e9c2d9f1c6b0b7a".to_owned();
+        expected.operations = vec!["MERGING"];
+        assert_eq!(parse_status(output), expected);
+    }
+
+    #[test]
+    fn parse_changes_full() {
+        let output = "\
+EDITED     src/main.c
+ADDED      src/new.c
+DELETED    src/old.c
+RENAMED    src/moved.c
+MISSING    src/gone.c
+CONFLICT   src/both.c
+EXTRA      notes.txt
+EXTRA      build/out.o
+";
+        let mut result = Status::new(&Fossil);
+        parse_changes(output, &mut result);
+        assert_eq!(result.staged, 3);
+        assert_eq!(result.changed, 2);
+        assert_eq!(result.conflicts, 1);
+        assert_eq!(result.untracked, 2);
+    }
+
+    #[test]
+    fn parse_changes_clean() {
+        let mut result = Status::new(&Fossil);
+        parse_changes("", &mut result);
+        assert_eq!(result, Status::new(&Fossil));
+    }
+}
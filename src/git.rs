@@ -1,11 +1,26 @@
 //! Get Git status
+#[cfg_attr(not(unix), allow(dead_code))]
+mod config;
+#[cfg(unix)]
+mod ignore;
+#[cfg(unix)]
+mod index;
+#[cfg(unix)]
+mod native;
+#[cfg(unix)]
+mod odb;
+
 use crate::{
-    status::Status,
-    util::{exec_cmd, CommandOutput},
-    vcs::VCS,
+    config::Config as Settings,
+    status::{FileCounts, Progress, RefKind, Status, Tracking},
+    util::{exec_cmd, logger::*, CommandOutput},
+    vcs::{Backend, DirFilter},
 };
 use anyhow::{Context, Result};
-use std::path::PathBuf;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
 
 static OPERATIONS: [(&str, &str); 6] = [
     ("rebase-merge", "REBASE"),
@@ -16,18 +31,371 @@ static OPERATIONS: [(&str, &str); 6] = [
     ("BISECT_LOG", "BISECTING"),
 ];
 
-/// Get the status for the cwd
-pub fn status(rootdir: PathBuf) -> Result<Status> {
-    let status_output = get_status()?;
-    let diff_output = git_diff_numstat()?;
-    let mut result = parse_status(&status_output.stdout)?;
-    parse_diff(&diff_output.stdout, &mut result);
-    get_operations(&mut result.operations, &rootdir);
+/// Git backend
+pub struct Git;
+
+impl Backend for Git {
+    fn name(&self) -> &'static str {
+        "Git"
+    }
+
+    fn symbol(&self) -> &'static str {
+        ""
+    }
+
+    fn detect(&self, dir: &Path) -> bool {
+        GitDirs::find(dir).is_some()
+    }
+
+    fn find_from_env(&self, cwd: &Path) -> Option<PathBuf> {
+        GitDirs::from_env(cwd).map(|dirs| dirs.root().to_path_buf())
+    }
+
+    fn status(&self, rootdir: &Path) -> Result<Status> {
+        status(rootdir, &Settings::default())
+    }
+
+    fn status_with_config(&self, rootdir: &Path, settings: &Settings) -> Result<Status> {
+        status(rootdir, settings)
+    }
+
+    fn watched_paths(&self, rootdir: &Path, settings: &Settings) -> Vec<PathBuf> {
+        match GitDirs::find(rootdir) {
+            Some(dirs) => watched_paths(&dirs, settings),
+            None => vec![],
+        }
+    }
+
+    fn ignored_dirs(&self, rootdir: &Path) -> DirFilter {
+        match GitDirs::find(rootdir) {
+            Some(dirs) => ignored_dirs(&dirs),
+            None => Box::new(|_| false),
+        }
+    }
+}
+
+/// Directories of a repository
+#[derive(Clone, Debug, PartialEq)]
+struct GitDirs {
+    /// Root of the work tree, `None` for bare repositories
+    work_dir:   Option<PathBuf>,
+    /// Per-worktree directory (`HEAD`, index and ongoing operations)
+    git_dir:    PathBuf,
+    /// Directory shared by all worktrees (objects, refs and config)
+    common_dir: PathBuf,
+}
+
+impl GitDirs {
+    /// Locate the git directories of the work tree *work_dir*.
+    ///
+    /// `.git` is either the git directory or, in linked worktrees and
+    /// submodules, a file pointing to it (`gitdir: <path>`).  Linked
+    /// worktrees share most data with the main repository via `commondir`.
+    ///
+    /// If *work_dir* has no `.git`, it may be a bare repository itself.
+    fn find(work_dir: &Path) -> Option<GitDirs> {
+        let dot_git = work_dir.join(".git");
+        let git_dir = if dot_git.is_dir() {
+            dot_git
+        } else if dot_git.is_file() {
+            let contents = fs::read_to_string(&dot_git).ok()?;
+            let target = contents.lines().next()?.strip_prefix("gitdir:")?.trim();
+            work_dir.join(target)
+        } else {
+            return GitDirs::new(work_dir.to_path_buf(), None).filter(GitDirs::is_bare);
+        };
+        GitDirs::new(git_dir, Some(work_dir.to_path_buf()))
+    }
+
+    /// Use the repository selected by `GIT_DIR` and `GIT_WORK_TREE` if
+    /// *cwd* is inside its work tree (or inside a bare repository)
+    fn from_env(cwd: &Path) -> Option<GitDirs> {
+        let git_dir = cwd.join(env::var_os("GIT_DIR")?);
+        let work_tree = env::var_os("GIT_WORK_TREE").map(|w| cwd.join(w));
+        GitDirs::from_git_dir(cwd, git_dir, work_tree).filter(|dirs| cwd.starts_with(dirs.root()))
+    }
+
+    /// Determine the work tree for an explicitly given *git_dir* like git:
+    /// `GIT_WORK_TREE`, `core.worktree`, no work tree if `core.bare` is set
+    /// and the cwd otherwise
+    fn from_git_dir(cwd: &Path, git_dir: PathBuf, work_tree: Option<PathBuf>) -> Option<GitDirs> {
+        let mut dirs = GitDirs::new(git_dir, None)?;
+        let mut config = config::Config::default();
+        config.read_file(&dirs.common_dir.join("config")).ok()?;
+        dirs.work_dir = match (work_tree, config.get("core.worktree")) {
+            (Some(work_tree), _) => Some(work_tree),
+            (None, Some(work_tree)) => Some(dirs.git_dir.join(work_tree)),
+            (None, None) if config.get_bool("core.bare") == Some(true) => None,
+            (None, None) => Some(cwd.to_path_buf()),
+        };
+        Some(dirs)
+    }
+
+    /// Check that *git_dir* is a git directory and locate its common dir
+    fn new(git_dir: PathBuf, work_dir: Option<PathBuf>) -> Option<GitDirs> {
+        if !git_dir.join("HEAD").is_file() {
+            return None;
+        }
+        let common_dir = match fs::read_to_string(git_dir.join("commondir")) {
+            Ok(contents) => git_dir.join(contents.trim()),
+            Err(_) => git_dir.clone(),
+        };
+        if !common_dir.join("objects").is_dir() {
+            return None;
+        }
+        Some(GitDirs {
+            work_dir,
+            git_dir,
+            common_dir,
+        })
+    }
+
+    /// Return true if the git dir is configured as bare repository
+    fn is_bare(&self) -> bool {
+        let mut config = config::Config::default();
+        config.read_file(&self.common_dir.join("config")).is_ok()
+            && config.get_bool("core.bare") == Some(true)
+    }
+
+    /// The work tree or, for bare repositories, the git dir
+    fn root(&self) -> &Path {
+        self.work_dir.as_deref().unwrap_or(&self.git_dir)
+    }
+
+    /// Name of the linked worktree, `None` for the main work tree
+    fn worktree(&self) -> Option<String> {
+        if self.git_dir == self.common_dir {
+            return None;
+        }
+        let name = self.git_dir.file_name()?;
+        Some(name.to_string_lossy().into_owned())
+    }
+}
+
+/// How the status is collected
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum StatusBackend {
+    /// Read the repository files directly (default)
+    Native,
+    /// Parse the output of the `git` binary
+    Command,
+}
+
+impl StatusBackend {
+    /// Select the backend with `VCP_GIT_BACKEND` (`native` or `command`)
+    pub fn from_env() -> StatusBackend {
+        match env::var("VCP_GIT_BACKEND").as_ref().map(String::as_str) {
+            Ok("command") => StatusBackend::Command,
+            _ => StatusBackend::Native,
+        }
+    }
+}
+
+/// Get the status of the repository at *rootdir*
+fn status(rootdir: &Path, settings: &Settings) -> Result<Status> {
+    let from_env = env::current_dir()
+        .ok()
+        .and_then(|cwd| GitDirs::from_env(&cwd))
+        .filter(|dirs| dirs.root() == rootdir);
+    let dirs = match from_env {
+        Some(dirs) => dirs,
+        None => GitDirs::find(rootdir).context("Failed to find git directory")?,
+    };
+    debug!("{:?}", dirs);
+    let base = base_ref(&dirs, settings);
+    let base = base.as_deref();
+    let mut result = match StatusBackend::from_env() {
+        StatusBackend::Native => native_status(&dirs, base).or_else(|e| {
+            debug!("Native status failed, falling back to `git`: {:#}", e);
+            command_status(&dirs, base)
+        })?,
+        StatusBackend::Command => command_status(&dirs, base)?,
+    };
+    result.bare = dirs.work_dir.is_none();
+    get_operations(&mut result.operations, &dirs.git_dir);
+    result.progress = get_progress(&dirs.git_dir);
+    result.stashes = count_stashes(&dirs.common_dir);
+    result.worktree = dirs.worktree();
     Ok(result)
 }
 
-fn git_diff_numstat() -> Result<CommandOutput> {
-    exec_cmd("git", &["diff", "--numstat"])
+/// Name of the ref to compare HEAD with, from `VCP_BASE_REF`, the git config
+/// (`vcprompt.baseRef`) or *settings*, in that order
+fn base_ref(dirs: &GitDirs, settings: &Settings) -> Option<String> {
+    env::var("VCP_BASE_REF")
+        .ok()
+        .or_else(|| {
+            let config = config::Config::load(&dirs.common_dir.join("config")).ok()?;
+            config.get("vcprompt.baseRef").map(String::from)
+        })
+        .or_else(|| settings.get("git", "VCP_BASE_REF").map(String::from))
+        .filter(|name| !name.is_empty())
+}
+
+/// Name of the ref that *branch* tracks and its remote (`None` for local
+/// branches), from `branch.<name>.remote` and `branch.<name>.merge`
+fn upstream_ref(config: &config::Config, branch: &str) -> Option<(String, Option<String>)> {
+    let remote = config.get(&format!("branch.{}.remote", branch));
+    let merge = config.get(&format!("branch.{}.merge", branch));
+    match (remote, merge) {
+        (Some("."), Some(merge)) => Some((merge.to_string(), None)),
+        (Some(remote), Some(merge)) => Some((
+            format!(
+                "refs/remotes/{}/{}",
+                remote,
+                merge.strip_prefix("refs/heads/").unwrap_or(merge)
+            ),
+            Some(remote.to_string()),
+        )),
+        _ => None,
+    }
+}
+
+/// Files and directories the status is read from besides the work tree and
+/// the objects: the git dirs (`HEAD`, the index, `packed-refs` and ongoing
+/// operations), the refs of the branch, its upstream and the base ref, and
+/// the stash log
+fn watched_paths(dirs: &GitDirs, settings: &Settings) -> Vec<PathBuf> {
+    let mut paths = vec![
+        dirs.git_dir.clone(),
+        dirs.git_dir.join("rebase-merge"),
+        dirs.git_dir.join("rebase-apply"),
+        dirs.common_dir.join("logs/refs/stash"),
+    ];
+    if dirs.common_dir != dirs.git_dir {
+        paths.push(dirs.common_dir.clone());
+    }
+    let mut refs = vec![];
+    let head = fs::read_to_string(dirs.git_dir.join("HEAD")).unwrap_or_default();
+    if let Some(refname) = head.trim().strip_prefix("ref: ") {
+        refs.push(refname.to_string());
+        let config = config::Config::load(&dirs.common_dir.join("config")).unwrap_or_default();
+        let branch = refname.strip_prefix("refs/heads/").unwrap_or(refname);
+        refs.extend(upstream_ref(&config, branch).map(|(refname, _)| refname));
+    }
+    if let Some(base) = base_ref(dirs, settings) {
+        refs.push(format!("refs/heads/{}", base));
+        refs.push(format!("refs/remotes/{}", base));
+        refs.push(format!("refs/tags/{}", base));
+        refs.push(base);
+    }
+    // Branches are shared by all worktrees
+    paths.extend(
+        refs.iter()
+            .filter(|refname| refname.starts_with("refs/"))
+            .map(|refname| dirs.common_dir.join(refname)),
+    );
+    paths
+}
+
+#[cfg(unix)]
+fn ignored_dirs(dirs: &GitDirs) -> DirFilter {
+    native::ignored_dirs(dirs)
+}
+
+#[cfg(not(unix))]
+fn ignored_dirs(_dirs: &GitDirs) -> DirFilter {
+    Box::new(|_| false)
+}
+
+#[cfg(unix)]
+fn native_status(dirs: &GitDirs, base: Option<&str>) -> Result<Status> {
+    native::status(dirs, base)
+}
+
+#[cfg(not(unix))]
+fn native_status(_dirs: &GitDirs, _base: Option<&str>) -> Result<Status> {
+    Err(anyhow::format_err!("Native status is only supported on Unix"))
+}
+
+/// Get the status by running `git status` and `git diff`
+fn command_status(dirs: &GitDirs, base: Option<&str>) -> Result<Status> {
+    let mut result = if dirs.work_dir.is_none() {
+        command_bare_status(dirs)?
+    } else {
+        let status_output = get_status(dirs)?;
+        let diff_output = git_diff_numstat(dirs)?;
+        let mut result = parse_status(&status_output.stdout)?;
+        parse_diff(&diff_output.stdout, &mut result);
+        if result.upstream.is_some() {
+            let config = config::Config::load(&dirs.common_dir.join("config"))?;
+            let remote = config.get(&format!("branch.{}.remote", result.branch));
+            result.remote = remote.filter(|&r| r != ".").map(String::from);
+        }
+        result
+    };
+    if result.branch == "(detached)" {
+        describe_detached(dirs, &mut result);
+    }
+    if let Some(base) = base {
+        let range = format!("HEAD...{}", base);
+        match git(dirs, &["rev-list", "--left-right", "--count", &range, "--"]) {
+            Ok(output) => {
+                let mut counts = output.stdout.split_whitespace().map(str::parse);
+                if let (Some(Ok(ahead)), Some(Ok(behind))) = (counts.next(), counts.next()) {
+                    result.base = Some(base.to_string());
+                    result.base_ahead = ahead;
+                    result.base_behind = behind;
+                }
+            }
+            Err(e) => debug!("Cannot compare with base {}: {:#}", base, e),
+        }
+    }
+    Ok(result)
+}
+
+/// Name a detached HEAD after the nearest tag containing it, or after the
+/// abbreviated commit hash
+fn describe_detached(dirs: &GitDirs, result: &mut Status) {
+    let tag = git(dirs, &["describe", "--tags", "--exact-match", "HEAD"])
+        .or_else(|_| git(dirs, &["describe", "--tags", "--contains", "HEAD"]));
+    match tag {
+        Ok(output) => {
+            result.branch = output.stdout.trim().to_string();
+            result.ref_kind = RefKind::Tag;
+        }
+        Err(_) => {
+            result.branch = result.fmt_commit(7).to_string();
+            result.ref_kind = RefKind::Commit;
+        }
+    }
+}
+
+/// Get branch and commit of a bare repository, which has no work tree
+/// to compare
+fn command_bare_status(dirs: &GitDirs) -> Result<Status> {
+    let mut result = Status::new(&Git);
+    result.branch = match git(dirs, &["symbolic-ref", "--short", "-q", "HEAD"]) {
+        Ok(output) => output.stdout.trim().to_string(),
+        Err(_) => String::from("(detached)"),
+    };
+    result.commit = match git(dirs, &["rev-parse", "-q", "--verify", "HEAD"]) {
+        Ok(output) => output.stdout.trim().to_string(),
+        Err(_) => String::from("(initial)"),
+    };
+    Ok(result)
+}
+
+/// Run `git` with *args* for the repository *dirs*, which is selected
+/// explicitly so that neither the cwd nor `GIT_DIR` and `GIT_WORK_TREE`
+/// (relative to the cwd) matter
+fn git(dirs: &GitDirs, args: &[&str]) -> Result<CommandOutput> {
+    let path = |dir: &Path| {
+        dir.to_str()
+            .map(String::from)
+            .context("Invalid repository path")
+    };
+    let mut full = vec![format!("--git-dir={}", path(&dirs.git_dir)?)];
+    if let Some(work_dir) = &dirs.work_dir {
+        full.push(format!("--work-tree={}", path(work_dir)?));
+    }
+    let mut full: Vec<&str> = full.iter().map(String::as_str).collect();
+    full.extend_from_slice(args);
+    exec_cmd("git", &full, dirs.root())
+}
+
+fn git_diff_numstat(dirs: &GitDirs) -> Result<CommandOutput> {
+    git(dirs, &["diff", "--numstat"])
 }
 
 fn parse_diff(diff: &str, status: &mut Status) {
@@ -39,9 +407,9 @@ fn parse_diff(diff: &str, status: &mut Status) {
 }
 
 /// Run `git status` and return its output.
-fn get_status() -> Result<CommandOutput> {
-    exec_cmd(
-        "git",
+fn get_status(dirs: &GitDirs) -> Result<CommandOutput> {
+    git(
+        dirs,
         &[
             "status",
             "--porcelain=2",
@@ -54,7 +422,8 @@ fn get_status() -> Result<CommandOutput> {
 
 /// Parse the output string of `get_status()`.
 fn parse_status(status: &str) -> Result<Status> {
-    let mut result = Status::new(VCS::Git);
+    let mut result = Status::new(&Git);
+    let mut has_ab = false;
 
     for line in status.lines() {
         let mut parts = line.split(' ');
@@ -62,24 +431,26 @@ fn parse_status(status: &str) -> Result<Status> {
         match parts.next().unwrap_or("") {
             "#" => match parts.next() {
                 Some("branch.head") => {
-                    result.branch = parts.next().unwrap_or(&"<unknown>").to_string()
+                    result.branch = parts.next().unwrap_or("<unknown>").to_string()
                 }
                 Some("branch.oid") => {
-                    result.commit = parts.next().unwrap_or(&"<unknown>").to_string()
+                    result.commit = parts.next().unwrap_or("<unknown>").to_string()
                 }
+                Some("branch.upstream") => result.upstream = parts.next().map(String::from),
                 Some("branch.ab") => {
+                    has_ab = true;
                     result.ahead = parts
                         .next()
                         .unwrap_or("0")
                         .parse::<i32>()
                         .context("Failed to parse")?
-                        .abs() as u32;
+                        .unsigned_abs();
                     result.behind = parts
                         .next()
                         .unwrap_or("0")
                         .parse::<i32>()
                         .context("Failed to parse")?
-                        .abs() as u32;
+                        .unsigned_abs();
                 }
                 _ => (),
             },
@@ -93,6 +464,7 @@ fn parse_status(status: &str) -> Result<Status> {
                     if !status.ends_with('.') {
                         result.changed += 1;
                     }
+                    count_file(status, &mut result.files);
                 }
             }
             "u" => result.conflicts += 1,
@@ -100,16 +472,41 @@ fn parse_status(status: &str) -> Result<Status> {
             _ => (),
         }
     }
+    // `branch.ab` is missing if the configured upstream does not exist
+    result.tracking = match (&result.upstream, has_ab) {
+        (Some(_), true) => Tracking::Upstream,
+        (Some(_), false) => Tracking::Gone,
+        (None, _) if result.branch == "(detached)" || result.branch == "<unknown>" => {
+            Tracking::Unknown
+        }
+        (None, _) => Tracking::None,
+    };
     Ok(result)
 }
 
-/// Look for files that indicate an ongoing operation (e.g., a merge)
-/// and update *list* accordingly
-fn get_operations(list: &mut Vec<&str>, rootdir: &PathBuf) {
-    let mut gitdir = rootdir.clone();
-    gitdir.push(".git");
+/// Update *files* with the kind of change of an `XY` status code (index and
+/// work tree status)
+fn count_file(xy: &str, files: &mut FileCounts) {
+    let mut codes = xy.chars();
+    let (x, y) = (codes.next(), codes.next());
+    match x {
+        // Copies are new files as well
+        Some('A') | Some('C') => files.added += 1,
+        Some('D') => files.deleted += 1,
+        Some('R') => files.renamed += 1,
+        _ if y == Some('A') => files.added += 1,
+        _ => (),
+    }
+    if y == Some('D') {
+        files.missing += 1;
+    }
+}
+
+/// Look for files in the per-worktree *git_dir* that indicate an ongoing
+/// operation (e.g., a merge) and update *list* accordingly
+fn get_operations(list: &mut Vec<&str>, git_dir: &Path) {
     for &(fname, op) in OPERATIONS.iter() {
-        let mut file = gitdir.clone();
+        let mut file = git_dir.to_path_buf();
         file.push(fname);
         if file.exists() {
             list.push(op);
@@ -117,6 +514,40 @@ fn get_operations(list: &mut Vec<&str>, rootdir: &PathBuf) {
     }
 }
 
+/// Read the progress of a rebase or `git am` from *git_dir*
+fn get_progress(git_dir: &Path) -> Option<Progress> {
+    // Interactive and merge based rebases use msgnum/end, am and
+    // apply based rebases next/last
+    let (operation, dir, step, total) = if git_dir.join("rebase-merge").is_dir() {
+        ("REBASE", git_dir.join("rebase-merge"), "msgnum", "end")
+    } else if git_dir.join("rebase-apply").is_dir() {
+        ("AM/REBASE", git_dir.join("rebase-apply"), "next", "last")
+    } else {
+        return None;
+    };
+    let read = |name: &str| fs::read_to_string(dir.join(name)).ok();
+    let number = |name: &str| read(name)?.trim().parse().ok();
+    let head_name = read("head-name")
+        .map(|name| name.trim().trim_start_matches("refs/heads/").to_string())
+        .filter(|name| name != "detached HEAD");
+    Some(Progress {
+        operation,
+        step: number(step)?,
+        total: number(total)?,
+        head_name,
+    })
+}
+
+/// Count the entries of the `refs/stash` reflog in *common_dir*
+fn count_stashes(common_dir: &Path) -> u32 {
+    match fs::read(common_dir.join("logs/refs/stash")) {
+        Ok(log) => log.split(|&b| b == b'\n').filter(|l| !l.is_empty()).count() as u32,
+        // Without reflog, only the latest stash is known
+        Err(_) if common_dir.join("refs/stash").exists() => 1,
+        Err(_) => 0,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
@@ -153,15 +584,23 @@ u UU <sub> <m1> <m2> <m3> <mW> <h1> <h2> <h3> <path>
 ? untracked.txt
 ! ignored.txt
 ";
-        let mut expected = Status::new(VCS::Git);
+        let mut expected = Status::new(&Git);
         expected.branch = "master".to_owned();
         expected.commit = "dc716b061d9a0bc6a59f4e02d72b9952cce28927".to_owned();
+        expected.upstream = Some("origin/master".to_owned());
+        expected.tracking = Tracking::Upstream;
         expected.ahead = 1;
         expected.behind = 2;
         expected.staged = 14;
         expected.changed = 11;
         expected.untracked = 1;
         expected.conflicts = 1;
+        expected.files = FileCounts {
+            added:   6,
+            deleted: 2,
+            renamed: 3,
+            missing: 5,
+        };
         assert_eq!(parse_status(output).unwrap(), expected);
     }
 
@@ -171,22 +610,57 @@ u UU <sub> <m1> <m2> <m3> <mW> <h1> <h2> <h3> <path>
 # branch.oid dc716b061d9a0bc6a59f4e02d72b9952cce28927
 # branch.head master
 ";
-        let mut expected = Status::new(VCS::Git);
+        let mut expected = Status::new(&Git);
         expected.branch = "master".to_owned();
         expected.commit = "dc716b061d9a0bc6a59f4e02d72b9952cce28927".to_owned();
+        expected.tracking = Tracking::None;
         assert_eq!(parse_status(output).unwrap(), expected);
+
+        let gone = format!("{}# branch.upstream origin/master\n", output);
+        expected.upstream = Some("origin/master".to_owned());
+        expected.tracking = Tracking::Gone;
+        assert_eq!(parse_status(&gone).unwrap(), expected);
     }
 
     #[test]
     fn parse_status_empty() {
-        assert_eq!(parse_status("").unwrap(), Status::new(VCS::Git));
+        assert_eq!(parse_status("").unwrap(), Status::new(&Git));
+    }
+
+    /// `git` runs in the repository, not in the cwd (this crate)
+    #[test]
+    fn command_status_outside_cwd() {
+        let mut rootdir = temp_dir();
+        rootdir.push("test-vcprompt-command");
+        let _ = fs::remove_dir_all(&rootdir);
+        fs::create_dir_all(&rootdir).unwrap();
+        let git = |args: &[&str]| {
+            let mut full = vec!["-c", "user.name=T", "-c", "user.email=t@example.com"];
+            full.extend_from_slice(args);
+            exec_cmd("git", &full, &rootdir).unwrap()
+        };
+        git(&["init", "-q", "-b", "elsewhere"]);
+        fs::write(rootdir.join("a.txt"), "a\n").unwrap();
+        git(&["add", "a.txt"]);
+        git(&["commit", "-q", "-m", "initial"]);
+        fs::write(rootdir.join("a.txt"), "a\nb\n").unwrap();
+        git(&["checkout", "-q", "--detach"]);
+
+        let dirs = GitDirs::find(&rootdir).unwrap();
+        let result = command_status(&dirs, Some("elsewhere")).unwrap();
+        assert_eq!(result.commit, git(&["rev-parse", "HEAD"]).stdout.trim());
+        assert_eq!(result.branch, result.fmt_commit(7));
+        assert_eq!((result.changed, result.added), (1, 1));
+        assert_eq!(result.base.as_deref(), Some("elsewhere"));
+        fs::remove_dir_all(&rootdir).unwrap();
     }
 
     #[test]
     fn detect_merge() {
         let mut result = Vec::<&str>::new();
         let mut rootdir = temp_dir();
-        rootdir.push("test-vcprompt");
+        rootdir.push("test-vcprompt-merge");
+        let _ = fs::remove_dir_all(&rootdir);
 
         let mut path = rootdir.clone();
         path.push(".git");
@@ -197,8 +671,115 @@ u UU <sub> <m1> <m2> <m3> <mW> <h1> <h2> <h3> <path>
         path.push("MERGE_HEAD");
         File::create(path).unwrap();
 
-        get_operations(&mut result, &rootdir);
+        get_operations(&mut result, &rootdir.join(".git"));
 
         assert_eq!(result, vec!["MERGING"]);
+        fs::remove_dir_all(&rootdir).unwrap();
+    }
+
+    #[test]
+    fn rebase_progress() {
+        let mut git_dir = temp_dir();
+        git_dir.push("test-vcprompt-rebase/.git");
+        let _ = fs::remove_dir_all(&git_dir);
+        let state = git_dir.join("rebase-merge");
+        DirBuilder::new().recursive(true).create(&state).unwrap();
+        assert_eq!(get_progress(&git_dir), None);
+        fs::write(state.join("msgnum"), "3\n").unwrap();
+        fs::write(state.join("end"), "12\n").unwrap();
+        fs::write(state.join("head-name"), "refs/heads/feature\n").unwrap();
+
+        let mut status = Status::new(&Git);
+        get_operations(&mut status.operations, &git_dir);
+        status.progress = get_progress(&git_dir);
+        let progress = status.progress.as_ref().unwrap();
+        assert_eq!((progress.step, progress.total), (3, 12));
+        assert_eq!(progress.head_name.as_deref(), Some("feature"));
+        assert_eq!(status.fmt_operation("REBASE"), "REBASE 3/12");
+        fs::remove_dir_all(git_dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn stash_count() {
+        let mut rootdir = temp_dir();
+        rootdir.push("test-vcprompt-stash");
+        let _ = fs::remove_dir_all(&rootdir);
+        let logs = rootdir.join(".git/logs/refs");
+        DirBuilder::new().recursive(true).create(&logs).unwrap();
+        fs::write(
+            logs.join("stash"),
+            "0000 1111 A <a@b> 1 +0000\tWIP on main: abc\n1111 2222 A <a@b> 2 +0000\tOn main: x\n",
+        )
+        .unwrap();
+        assert_eq!(count_stashes(&rootdir.join(".git")), 2);
+        assert_eq!(count_stashes(&rootdir.join("missing")), 0);
+        fs::remove_dir_all(&rootdir).unwrap();
+    }
+
+    #[test]
+    fn linked_worktree() {
+        let mut base = temp_dir();
+        base.push("test-vcprompt-worktree");
+        let _ = fs::remove_dir_all(&base);
+        let main_git = base.join("main/.git");
+        let wt_git = main_git.join("worktrees/feature");
+        DirBuilder::new().recursive(true).create(&wt_git).unwrap();
+        fs::create_dir(main_git.join("objects")).unwrap();
+        fs::create_dir_all(base.join("feature")).unwrap();
+        fs::write(main_git.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(wt_git.join("HEAD"), "ref: refs/heads/feature\n").unwrap();
+        fs::write(wt_git.join("commondir"), "../..\n").unwrap();
+        fs::write(wt_git.join("MERGE_HEAD"), "").unwrap();
+        fs::write(
+            base.join("feature/.git"),
+            format!("gitdir: {}\n", wt_git.display()),
+        )
+        .unwrap();
+
+        let dirs = GitDirs::find(&base.join("feature")).unwrap();
+        assert_eq!(dirs.git_dir, wt_git);
+        assert_eq!(dirs.common_dir, wt_git.join("../.."));
+        assert_eq!(dirs.worktree().as_deref(), Some("feature"));
+        let mut operations = vec![];
+        get_operations(&mut operations, &dirs.git_dir);
+        assert_eq!(operations, vec!["MERGING"]);
+
+        let main = GitDirs::find(&base.join("main")).unwrap();
+        assert_eq!(main.worktree(), None);
+        assert!(GitDirs::find(&base).is_none());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn bare_and_explicit_git_dir() {
+        let mut base = temp_dir();
+        base.push("test-vcprompt-bare");
+        let _ = fs::remove_dir_all(&base);
+        let git_dir = base.join("dotfiles.git");
+        DirBuilder::new()
+            .recursive(true)
+            .create(git_dir.join("objects"))
+            .unwrap();
+        fs::write(git_dir.join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        fs::write(git_dir.join("config"), "[core]\n\tbare = true\n").unwrap();
+
+        let dirs = GitDirs::find(&git_dir).unwrap();
+        assert_eq!(dirs.work_dir, None);
+        assert_eq!(dirs.root(), git_dir);
+        // Inside the git dir of a non-bare repository
+        fs::write(git_dir.join("config"), "[core]\n\tbare = false\n").unwrap();
+        assert!(GitDirs::find(&git_dir).is_none());
+        fs::write(git_dir.join("config"), "[core]\n\tbare = true\n").unwrap();
+
+        // GIT_DIR with GIT_WORK_TREE, e.g., for dotfiles
+        let dirs = GitDirs::from_git_dir(&base, git_dir.clone(), Some(base.clone())).unwrap();
+        assert_eq!(dirs.work_dir.as_deref(), Some(base.as_path()));
+        let dirs = GitDirs::from_git_dir(&base, git_dir.clone(), None).unwrap();
+        assert_eq!(dirs.work_dir, None);
+        // Without work tree and `core.bare`, the cwd is the work tree
+        fs::write(git_dir.join("config"), "").unwrap();
+        let dirs = GitDirs::from_git_dir(&base, git_dir, None).unwrap();
+        assert_eq!(dirs.work_dir.as_deref(), Some(base.as_path()));
+        fs::remove_dir_all(&base).unwrap();
     }
 }
@@ -0,0 +1,182 @@
+//! Minimal reader for git config files
+use crate::util::xdg_config_home;
+use anyhow::Result;
+use std::{env, fs, io, path::Path, path::PathBuf};
+
+/// Key/value pairs from one or more config files, in the order they were read
+#[derive(Debug, Default)]
+pub struct Config {
+    entries: Vec<(String, String)>,
+}
+
+impl Config {
+    /// Read the system, global and repository config files, in that order
+    pub fn load(repo_config: &Path) -> Result<Config> {
+        let mut config = Config::default();
+        config.read_file(Path::new("/etc/gitconfig"))?;
+        if let Some(path) = xdg_config_home().map(|p| p.join("git/config")) {
+            config.read_file(&path)?;
+        }
+        if let Some(home) = env::var_os("HOME") {
+            config.read_file(&PathBuf::from(home).join(".gitconfig"))?;
+        }
+        config.read_file(repo_config)?;
+        Ok(config)
+    }
+
+    /// Append the entries of *path*; missing files are ignored
+    pub fn read_file(&mut self, path: &Path) -> Result<()> {
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                self.parse(&contents);
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn parse(&mut self, contents: &str) {
+        let mut section = String::new();
+        for line in contents.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') {
+                if let Some(end) = line.find(']') {
+                    section = parse_section(&line[1..end]);
+                }
+                continue;
+            }
+            let (name, value) = match line.find('=') {
+                Some(pos) => (line[..pos].trim(), parse_value(&line[pos + 1..])),
+                // A key without value is a boolean true
+                None => (line, String::from("true")),
+            };
+            self.entries
+                .push((format!("{}.{}", section, name.to_lowercase()), value));
+        }
+    }
+
+    /// Return the last value of *key* (`section[.subsection].name`)
+    pub fn get(&self, key: &str) -> Option<&str> {
+        let key = normalize_key(key);
+        self.entries
+            .iter()
+            .rev()
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Return the last value of *key* interpreted as boolean
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        match self.get(key)?.to_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Some(true),
+            "false" | "no" | "off" | "0" | "" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// Expand a leading `~/` in config paths
+pub fn expand_path(value: &str) -> PathBuf {
+    match (value.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => PathBuf::from(value),
+    }
+}
+
+/// Section names are case insensitive, subsections are not
+fn parse_section(header: &str) -> String {
+    match header.find('"') {
+        Some(quote) => {
+            let name = header[..quote].trim().to_lowercase();
+            let sub = header[quote + 1..].trim_end().trim_end_matches('"');
+            format!("{}.{}", name, sub.replace("\\\"", "\"").replace("\\\\", "\\"))
+        }
+        // Deprecated `[section.subsection]` syntax
+        None => match header.find('.') {
+            Some(dot) => format!(
+                "{}.{}",
+                header[..dot].trim().to_lowercase(),
+                header[dot + 1..].trim()
+            ),
+            None => header.trim().to_lowercase(),
+        },
+    }
+}
+
+/// Strip comments and quotes and resolve escapes in a value
+fn parse_value(raw: &str) -> String {
+    let mut value = String::with_capacity(raw.len());
+    let mut quoted = false;
+    let mut chars = raw.trim().chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' | ';' if !quoted => break,
+            '\\' => match chars.next() {
+                Some('n') => value.push('\n'),
+                Some('t') => value.push('\t'),
+                Some(c) => value.push(c),
+                None => (),
+            },
+            c => value.push(c),
+        }
+    }
+    if !quoted {
+        let trimmed = value.trim_end().len();
+        value.truncate(trimmed);
+    }
+    value
+}
+
+fn normalize_key(key: &str) -> String {
+    match (key.find('.'), key.rfind('.')) {
+        (Some(first), Some(last)) if first != last => format!(
+            "{}{}{}",
+            key[..first].to_lowercase(),
+            &key[first..last],
+            key[last..].to_lowercase()
+        ),
+        _ => key.to_lowercase(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let mut config = Config::default();
+        config.parse(
+            r#"
+[core]
+    bare = false
+    FileMode = true ; trailing comment
+[branch "Feature/x"]
+    remote = origin
+    merge = refs/heads/feature/x
+[alias]
+    lg = "log --oneline # not a comment"
+[Core]
+    bare
+"#,
+        );
+        assert_eq!(config.get("branch.Feature/x.remote"), Some("origin"));
+        assert_eq!(config.get("branch.feature/x.remote"), None);
+        assert_eq!(config.get_bool("core.filemode"), Some(true));
+        assert_eq!(config.get_bool("core.bare"), Some(true));
+        assert_eq!(config.get("alias.lg"), Some("log --oneline # not a comment"));
+        assert_eq!(config.get("core.missing"), None);
+    }
+
+    #[test]
+    fn parse_legacy_subsection() {
+        let mut config = Config::default();
+        config.parse("[branch.main]\nremote = upstream\n");
+        assert_eq!(config.get("branch.main.remote"), Some("upstream"));
+    }
+}
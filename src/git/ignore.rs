@@ -0,0 +1,267 @@
+//! Match paths against gitignore patterns
+use std::{fs, path::Path};
+
+/// A single pattern from an ignore file
+#[derive(Debug)]
+pub struct Pattern {
+    /// Directory of the ignore file, relative to the work tree (with trailing `/`)
+    base:     Vec<u8>,
+    glob:     Vec<u8>,
+    negated:  bool,
+    dir_only: bool,
+    /// Patterns containing a slash match the full path, others only the basename
+    anchored: bool,
+}
+
+impl Pattern {
+    /// Parse one line of an ignore file located in directory *base*
+    pub fn parse(line: &[u8], base: &[u8]) -> Option<Pattern> {
+        let mut line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.is_empty() || line[0] == b'#' {
+            return None;
+        }
+        // Trailing spaces are ignored unless escaped
+        while line.ends_with(b" ") && !line.ends_with(b"\\ ") {
+            line = &line[..line.len() - 1];
+        }
+        let negated = line.starts_with(b"!");
+        if negated {
+            line = &line[1..];
+        }
+        let dir_only = line.ends_with(b"/");
+        if dir_only {
+            line = &line[..line.len() - 1];
+        }
+        let anchored = line.contains(&b'/');
+        let line = line.strip_prefix(b"/").unwrap_or(line);
+        if line.is_empty() {
+            return None;
+        }
+        let mut base = base.to_vec();
+        if !base.is_empty() && !base.ends_with(b"/") {
+            base.push(b'/');
+        }
+        Some(Pattern {
+            base,
+            glob: line.to_vec(),
+            negated,
+            dir_only,
+            anchored,
+        })
+    }
+
+    fn matches(&self, path: &[u8], is_dir: bool) -> bool {
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        let rel = match path.strip_prefix(&self.base[..]) {
+            Some(rel) => rel,
+            None => return false,
+        };
+        if self.anchored {
+            wildmatch(&self.glob, rel)
+        } else {
+            let name = rel.rsplit(|&b| b == b'/').next().unwrap_or(rel);
+            wildmatch(&self.glob, name)
+        }
+    }
+}
+
+/// Ordered list of patterns, later patterns take precedence
+#[derive(Debug, Default)]
+pub struct Ignore {
+    patterns: Vec<Pattern>,
+}
+
+impl Ignore {
+    /// Append the patterns of the ignore file *path*; missing files are ignored
+    pub fn add_file(&mut self, path: &Path, base: &[u8]) {
+        if let Ok(contents) = fs::read(path) {
+            self.add_patterns(&contents, base);
+        }
+    }
+
+    pub fn add_patterns(&mut self, contents: &[u8], base: &[u8]) {
+        self.patterns.extend(
+            contents
+                .split(|&b| b == b'\n')
+                .filter_map(|line| Pattern::parse(line, base)),
+        );
+    }
+
+    /// Number of patterns, used to drop the patterns of a directory after leaving it
+    pub fn len(&self) -> usize {
+        self.patterns.len()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.patterns.truncate(len);
+    }
+
+    /// Return true if *path* (relative to the work tree) is ignored
+    pub fn is_ignored(&self, path: &[u8], is_dir: bool) -> bool {
+        self.patterns
+            .iter()
+            .rev()
+            .find(|p| p.matches(path, is_dir))
+            .is_some_and(|p| !p.negated)
+    }
+}
+
+/// Match *text* against a glob, where wildcards do not match `/`
+/// except for `**` between slashes
+pub fn wildmatch(pattern: &[u8], text: &[u8]) -> bool {
+    let mut p = 0;
+    let mut t = 0;
+    while p < pattern.len() {
+        match pattern[p] {
+            b'?' => {
+                if t >= text.len() || text[t] == b'/' {
+                    return false;
+                }
+                p += 1;
+                t += 1;
+            }
+            b'*' => {
+                let mut end = p + 1;
+                while end < pattern.len() && pattern[end] == b'*' {
+                    end += 1;
+                }
+                let at_start = p == 0 || pattern[p - 1] == b'/';
+                if end - p > 1 && at_start {
+                    match pattern.get(end) {
+                        // Trailing `**` matches everything below
+                        None => return true,
+                        // `**/` matches zero or more directories
+                        Some(b'/') => {
+                            let rest = &pattern[end + 1..];
+                            return wildmatch(rest, &text[t..])
+                                || (t..text.len())
+                                    .any(|i| text[i] == b'/' && wildmatch(rest, &text[i + 1..]));
+                        }
+                        _ => (),
+                    }
+                }
+                let rest = &pattern[end..];
+                for i in t..=text.len() {
+                    if wildmatch(rest, &text[i..]) {
+                        return true;
+                    }
+                    if i < text.len() && text[i] == b'/' {
+                        break;
+                    }
+                }
+                return false;
+            }
+            b'[' => {
+                let c = match text.get(t) {
+                    Some(&c) if c != b'/' => c,
+                    _ => return false,
+                };
+                match match_class(&pattern[p + 1..], c) {
+                    Some((true, len)) => {
+                        p += len + 1;
+                        t += 1;
+                    }
+                    Some((false, _)) => return false,
+                    // Unterminated class matches a literal `[`
+                    None => {
+                        if c != b'[' {
+                            return false;
+                        }
+                        p += 1;
+                        t += 1;
+                    }
+                }
+            }
+            c => {
+                let (c, len) = if c == b'\\' && p + 1 < pattern.len() {
+                    (pattern[p + 1], 2)
+                } else {
+                    (c, 1)
+                };
+                if text.get(t) != Some(&c) {
+                    return false;
+                }
+                p += len;
+                t += 1;
+            }
+        }
+    }
+    t == text.len()
+}
+
+/// Match *c* against the bracket expression starting after `[`.
+///
+/// Returns whether it matched and the length of the expression
+/// including the closing `]`.
+fn match_class(class: &[u8], c: u8) -> Option<(bool, usize)> {
+    let mut i = 0;
+    let negated = matches!(class.first(), Some(b'!') | Some(b'^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    while i < class.len() {
+        let mut lo = class[i];
+        if lo == b']' && !first {
+            return Some((matched != negated, i + 1));
+        }
+        first = false;
+        if lo == b'\\' && i + 1 < class.len() {
+            i += 1;
+            lo = class[i];
+        }
+        if class.get(i + 1) == Some(&b'-') && class.get(i + 2).is_some_and(|&b| b != b']') {
+            let hi = class[i + 2];
+            matched |= lo <= c && c <= hi;
+            i += 3;
+        } else {
+            matched |= lo == c;
+            i += 1;
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildmatch_basic() {
+        assert!(wildmatch(b"*.log", b"debug.log"));
+        assert!(!wildmatch(b"*.log", b"logs/debug.log"));
+        assert!(wildmatch(b"debug?.log", b"debug1.log"));
+        assert!(wildmatch(b"debug[0-9].log", b"debug7.log"));
+        assert!(!wildmatch(b"debug[!0-9].log", b"debug7.log"));
+        assert!(wildmatch(b"\\#file", b"#file"));
+    }
+
+    #[test]
+    fn wildmatch_double_star() {
+        assert!(wildmatch(b"**/foo", b"foo"));
+        assert!(wildmatch(b"**/foo", b"a/b/foo"));
+        assert!(wildmatch(b"a/**/b", b"a/b"));
+        assert!(wildmatch(b"a/**/b", b"a/x/y/b"));
+        assert!(wildmatch(b"abc/**", b"abc/x/y"));
+        assert!(!wildmatch(b"a/**/b", b"a/x/c"));
+    }
+
+    #[test]
+    fn ignore_precedence() {
+        let mut ignore = Ignore::default();
+        ignore.add_patterns(b"# comment\n*.log\n!keep.log\nbuild/\n/root.txt\n", b"");
+        ignore.add_patterns(b"*.tmp\n", b"sub");
+        assert!(ignore.is_ignored(b"debug.log", false));
+        assert!(ignore.is_ignored(b"sub/debug.log", false));
+        assert!(!ignore.is_ignored(b"keep.log", false));
+        assert!(ignore.is_ignored(b"build", true));
+        assert!(!ignore.is_ignored(b"build", false));
+        assert!(ignore.is_ignored(b"root.txt", false));
+        assert!(!ignore.is_ignored(b"sub/root.txt", false));
+        assert!(ignore.is_ignored(b"sub/a.tmp", false));
+        assert!(!ignore.is_ignored(b"a.tmp", false));
+    }
+}
@@ -0,0 +1,342 @@
+//! Parse the git index (`.git/index`)
+use super::odb::{read_offset_varint, Oid};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::Path,
+    time::UNIX_EPOCH,
+};
+
+const FLAG_ASSUME_VALID: u16 = 0x8000;
+const FLAG_EXTENDED: u16 = 0x4000;
+const FLAG_STAGE_MASK: u16 = 0x3000;
+const FLAG_NAME_MASK: u16 = 0x0fff;
+const EXT_FLAG_SKIP_WORKTREE: u16 = 0x4000;
+const EXT_FLAG_INTENT_TO_ADD: u16 = 0x2000;
+
+/// Fixed size part of an on-disk entry (stat data, oid and flags)
+const ENTRY_LEN: usize = 62;
+
+/// A single entry of the index
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Entry {
+    pub ctime:     (u32, u32),
+    pub mtime:     (u32, u32),
+    pub dev:       u32,
+    pub ino:       u32,
+    pub mode:      u32,
+    pub uid:       u32,
+    pub gid:       u32,
+    pub size:      u32,
+    pub oid:       Oid,
+    pub flags:     u16,
+    pub ext_flags: u16,
+    pub path:      Vec<u8>,
+}
+
+impl Entry {
+    /// Merge stage (0 for normal entries, 1-3 for conflicts)
+    pub fn stage(&self) -> u16 {
+        (self.flags & FLAG_STAGE_MASK) >> 12
+    }
+
+    pub fn assume_valid(&self) -> bool {
+        self.flags & FLAG_ASSUME_VALID != 0
+    }
+
+    pub fn skip_worktree(&self) -> bool {
+        self.ext_flags & EXT_FLAG_SKIP_WORKTREE != 0
+    }
+
+    /// Entry added with `git add --intent-to-add`
+    pub fn intent_to_add(&self) -> bool {
+        self.ext_flags & EXT_FLAG_INTENT_TO_ADD != 0
+    }
+}
+
+/// The parsed index file
+#[derive(Debug, Default)]
+pub struct Index {
+    pub entries:    Vec<Entry>,
+    /// Valid cache-tree nodes (directory path -> tree id)
+    pub cache_tree: HashMap<Vec<u8>, Oid>,
+    /// Modification time of the index file, used to detect racily clean entries
+    pub mtime:      Option<(u32, u32)>,
+}
+
+/// Read the index at *path*; a missing file yields an empty index
+pub fn read(path: &Path) -> Result<Index> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Index::default()),
+        Err(e) => return Err(e).context("Failed to read index"),
+    };
+    let mut index = parse(&data)?;
+    index.mtime = fs::metadata(path)?
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| (d.as_secs() as u32, d.subsec_nanos()));
+    Ok(index)
+}
+
+impl Index {
+    /// Return true if an entry with *mtime* could have been modified
+    /// within the same timestamp granularity as the index was written
+    pub fn is_racy(&self, mtime: (u32, u32)) -> bool {
+        self.mtime.is_none_or(|index_mtime| mtime >= index_mtime)
+    }
+}
+
+fn be_u16(data: &[u8], pos: usize) -> u16 {
+    u16::from_be_bytes([data[pos], data[pos + 1]])
+}
+
+fn be_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+fn parse(data: &[u8]) -> Result<Index> {
+    // header (12) + trailing checksum (20)
+    if data.len() < 32 || &data[..4] != b"DIRC" {
+        bail!("Invalid index signature");
+    }
+    let version = be_u32(data, 4);
+    if !(2..=4).contains(&version) {
+        bail!("Unsupported index version {}", version);
+    }
+    let count = be_u32(data, 8) as usize;
+    let end = data.len() - 20;
+    let mut pos = 12;
+    let mut entries = Vec::with_capacity(count);
+    let mut prev_path: Vec<u8> = vec![];
+
+    for _ in 0..count {
+        if pos + ENTRY_LEN > end {
+            bail!("Truncated index");
+        }
+        let start = pos;
+        let mut entry = Entry {
+            ctime: (be_u32(data, pos), be_u32(data, pos + 4)),
+            mtime: (be_u32(data, pos + 8), be_u32(data, pos + 12)),
+            dev: be_u32(data, pos + 16),
+            ino: be_u32(data, pos + 20),
+            mode: be_u32(data, pos + 24),
+            uid: be_u32(data, pos + 28),
+            gid: be_u32(data, pos + 32),
+            size: be_u32(data, pos + 36),
+            flags: be_u16(data, pos + 60),
+            ..Entry::default()
+        };
+        entry.oid.copy_from_slice(&data[pos + 40..pos + 60]);
+        pos += ENTRY_LEN;
+        if entry.flags & FLAG_EXTENDED != 0 {
+            if version < 3 {
+                bail!("Extended flags in index version {}", version);
+            }
+            entry.ext_flags = be_u16(data, pos);
+            pos += 2;
+        }
+
+        if version == 4 {
+            // Path is prefix compressed against the previous entry
+            let mut reader = data.get(pos..end).context("Truncated index")?;
+            let strip = read_offset_varint(&mut reader)? as usize;
+            pos = end - reader.len();
+            let keep = prev_path
+                .len()
+                .checked_sub(strip)
+                .context("Invalid path compression")?;
+            let nul = data[pos..end]
+                .iter()
+                .position(|&b| b == 0)
+                .context("Unterminated path")?;
+            let mut path = prev_path[..keep].to_vec();
+            path.extend_from_slice(&data[pos..pos + nul]);
+            pos += nul + 1;
+            entry.path = path;
+        } else {
+            let name_len = (entry.flags & FLAG_NAME_MASK) as usize;
+            let nul = if name_len < FLAG_NAME_MASK as usize {
+                name_len
+            } else {
+                data[pos..end]
+                    .iter()
+                    .position(|&b| b == 0)
+                    .context("Unterminated path")?
+            };
+            entry.path = data
+                .get(pos..end)
+                .and_then(|rest| rest.get(..nul))
+                .context("Truncated index")?
+                .to_vec();
+            // Entries are NUL padded to a multiple of eight bytes
+            pos = start + ((pos - start + nul + 8) & !7);
+        }
+        prev_path.clone_from(&entry.path);
+        entries.push(entry);
+    }
+
+    let mut cache_tree = HashMap::new();
+    while pos + 8 <= end {
+        let signature = &data[pos..pos + 4];
+        let size = be_u32(data, pos + 4) as usize;
+        let body = data
+            .get(pos + 8..pos + 8 + size)
+            .context("Truncated index extension")?;
+        match signature {
+            b"TREE" => {
+                parse_cache_tree(body, &mut 0, &[], &mut cache_tree)?;
+            }
+            b"link" => bail!("Split index is not supported"),
+            b"sdir" => bail!("Sparse index is not supported"),
+            // Lowercase extensions are required to understand the index
+            s if s[0].is_ascii_lowercase() => {
+                bail!("Unsupported index extension {:?}", String::from_utf8_lossy(s))
+            }
+            _ => (),
+        }
+        pos += 8 + size;
+    }
+
+    Ok(Index {
+        entries,
+        cache_tree,
+        mtime: None,
+    })
+}
+
+/// Parse one cache-tree node and its subtrees (depth first)
+fn parse_cache_tree(
+    data: &[u8],
+    pos: &mut usize,
+    prefix: &[u8],
+    result: &mut HashMap<Vec<u8>, Oid>,
+) -> Result<()> {
+    let rest = &data[*pos..];
+    let nul = rest
+        .iter()
+        .position(|&b| b == 0)
+        .context("Invalid cache tree")?;
+    // Directory names may contain newlines, so search after the name
+    let newline = nul
+        + 1
+        + rest[nul + 1..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .context("Invalid cache tree")?;
+    let counts = rest.get(nul + 1..newline).context("Invalid cache tree")?;
+    let counts = String::from_utf8_lossy(counts).into_owned();
+    let mut counts = counts.split(' ');
+    let entry_count: i64 = counts.next().unwrap_or("").parse()?;
+    let subtrees: usize = counts.next().unwrap_or("").parse()?;
+
+    let mut path = prefix.to_vec();
+    if !path.is_empty() {
+        path.push(b'/');
+    }
+    path.extend_from_slice(&rest[..nul]);
+    *pos += newline + 1;
+
+    // Invalidated nodes have a negative entry count and no oid
+    if entry_count >= 0 {
+        let mut oid = [0; 20];
+        oid.copy_from_slice(data.get(*pos..*pos + 20).context("Invalid cache tree")?);
+        *pos += 20;
+        result.insert(path.clone(), oid);
+    }
+    for _ in 0..subtrees {
+        parse_cache_tree(data, pos, &path, result)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a version 2 index containing *paths*
+    fn build_index(paths: &[(&str, u16)], extensions: &[u8]) -> Vec<u8> {
+        let mut data = b"DIRC".to_vec();
+        data.extend_from_slice(&2u32.to_be_bytes());
+        data.extend_from_slice(&(paths.len() as u32).to_be_bytes());
+        for (path, stage) in paths {
+            let start = data.len();
+            data.extend_from_slice(&[0; 24]);
+            data.extend_from_slice(&0o100644u32.to_be_bytes());
+            data.extend_from_slice(&[0; 8]);
+            data.extend_from_slice(&6u32.to_be_bytes());
+            data.extend_from_slice(&[7; 20]);
+            let flags = (stage << 12) | path.len() as u16;
+            data.extend_from_slice(&flags.to_be_bytes());
+            data.extend_from_slice(path.as_bytes());
+            data.push(0);
+            while !(data.len() - start).is_multiple_of(8) {
+                data.push(0);
+            }
+        }
+        data.extend_from_slice(extensions);
+        data.extend_from_slice(&[0; 20]);
+        data
+    }
+
+    #[test]
+    fn parse_entries() {
+        let data = build_index(&[("a.txt", 0), ("dir/b.txt", 0), ("c.txt", 2)], &[]);
+        let index = parse(&data).unwrap();
+        let paths: Vec<&[u8]> = index.entries.iter().map(|e| &e.path[..]).collect();
+        assert_eq!(paths, vec![&b"a.txt"[..], b"dir/b.txt", b"c.txt"]);
+        assert_eq!(index.entries[0].size, 6);
+        assert_eq!(index.entries[0].mode, 0o100644);
+        assert_eq!(index.entries[2].stage(), 2);
+        assert!(index.cache_tree.is_empty());
+    }
+
+    #[test]
+    fn parse_cache_tree_extension() {
+        let mut body = b"\x002 1\n".to_vec();
+        body.extend_from_slice(&[1; 20]);
+        body.extend_from_slice(b"dir\x00-1 0\n");
+        let mut ext = b"TREE".to_vec();
+        ext.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        ext.extend_from_slice(&body);
+
+        let data = build_index(&[("a.txt", 0), ("dir/b.txt", 0)], &ext);
+        let index = parse(&data).unwrap();
+        assert_eq!(index.cache_tree.get(&b""[..]), Some(&[1; 20]));
+        assert_eq!(index.cache_tree.get(&b"dir"[..]), None);
+
+        // Newlines are valid in directory names
+        let mut body = b"\x002 1\n".to_vec();
+        body.extend_from_slice(&[1; 20]);
+        body.extend_from_slice(b"new\nline\x001 0\n");
+        body.extend_from_slice(&[2; 20]);
+        let mut ext = b"TREE".to_vec();
+        ext.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        ext.extend_from_slice(&body);
+        let data = build_index(&[("a.txt", 0), ("new\nline/b.txt", 0)], &ext);
+        let index = parse(&data).unwrap();
+        assert_eq!(index.cache_tree.get(&b"new\nline"[..]), Some(&[2; 20]));
+    }
+
+    #[test]
+    fn reject_truncated_path() {
+        let mut data = build_index(&[("a.txt", 0)], &[]);
+        // Keep three bytes of the path and the checksum
+        data.truncate(12 + ENTRY_LEN + 3);
+        data.extend_from_slice(&[0; 20]);
+        assert!(parse(&data).is_err());
+    }
+
+    #[test]
+    fn reject_split_index() {
+        let data = build_index(&[("a.txt", 0)], b"link\x00\x00\x00\x00");
+        assert!(parse(&data).is_err());
+    }
+
+    #[test]
+    fn reject_invalid_signature() {
+        assert!(parse(&[0; 40]).is_err());
+    }
+}
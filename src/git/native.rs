@@ -0,0 +1,940 @@
+//! Compute Git status by reading the repository directly instead of
+//! spawning `git`
+use super::{
+    config::{self, Config},
+    ignore::Ignore,
+    index::{self, Entry, Index},
+    odb::{hash_blob, parse_oid, to_hex, Odb, Oid},
+};
+use super::{upstream_ref, Git, GitDirs};
+use crate::{
+    status::{FileCounts, RefKind, Status, Tracking},
+    util::{line_diff, logger::*, xdg_config_home},
+    vcs::DirFilter,
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::{BinaryHeap, HashMap, HashSet},
+    ffi::OsStr,
+    fs::{self, Metadata},
+    io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::{Path, PathBuf},
+};
+
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_FILE: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+const MODE_GITLINK: u32 = 0o160000;
+
+/// Maximum number of symbolic ref indirections to follow
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// Maximum number of first-parent steps from a tag to a detached HEAD
+const MAX_DESCRIBE_DEPTH: u32 = 1000;
+
+/// Files larger than this are not diffed for line statistics
+const MAX_DIFF_SIZE: u64 = 1 << 20;
+
+/// Where HEAD points to
+#[derive(Debug, Eq, PartialEq)]
+enum Head {
+    /// On a branch, which has no commits yet if the oid is `None`
+    Branch(String, Option<Oid>),
+    Detached(Oid),
+}
+
+/// The upstream branch configured for a branch
+#[derive(Debug)]
+struct Upstream {
+    /// Short name (e.g., `origin/main`)
+    name:   String,
+    /// Remote name, `None` for local branches
+    remote: Option<String>,
+    /// `None` if the branch does not exist
+    oid:    Option<Oid>,
+}
+
+/// A non-bare repository with its git directories
+struct Repository {
+    work_dir:   PathBuf,
+    git_dir:    PathBuf,
+    common_dir: PathBuf,
+    config:     Config,
+}
+
+/// Get the status of the work tree described by *dirs*, comparing HEAD
+/// with the *base* ref if given
+pub fn status(dirs: &GitDirs, base: Option<&str>) -> Result<Status> {
+    let repo = Repository::open(dirs)?;
+    let odb = Odb::open(&repo.common_dir.join("objects"))?;
+    let mut result = Status::new(&Git);
+
+    let head = repo.head()?;
+    debug!("HEAD: {:?}", head);
+    let head_oid = match &head {
+        Head::Branch(name, oid) => {
+            result.branch = name.clone();
+            *oid
+        }
+        Head::Detached(oid) => {
+            match repo.describe(&odb, *oid)? {
+                Some(name) => {
+                    result.branch = name;
+                    result.ref_kind = RefKind::Tag;
+                }
+                None => {
+                    result.branch = to_hex(oid)[..7].to_string();
+                    result.ref_kind = RefKind::Commit;
+                }
+            }
+            Some(*oid)
+        }
+    };
+    result.commit = head_oid.map_or_else(|| String::from("(initial)"), |oid| to_hex(&oid));
+
+    if let Head::Branch(name, local) = &head {
+        let upstream = repo.upstream(name)?;
+        debug!("Upstream of {}: {:?}", name, upstream);
+        result.tracking = match upstream {
+            None => Tracking::None,
+            Some(Upstream { oid: None, .. }) => Tracking::Gone,
+            Some(Upstream { oid: Some(_), .. }) => Tracking::Upstream,
+        };
+        if let Some(upstream) = upstream {
+            if let (Some(local), Some(oid)) = (local, upstream.oid) {
+                let (ahead, behind) = ahead_behind(&odb, *local, oid)?;
+                result.ahead = ahead;
+                result.behind = behind;
+            }
+            result.upstream = Some(upstream.name);
+            result.remote = upstream.remote;
+        }
+    }
+    if let (Some(name), Some(head)) = (base, head_oid) {
+        match repo.resolve_short_ref(&odb, name)? {
+            Some(oid) => {
+                let (ahead, behind) = ahead_behind(&odb, head, oid)?;
+                result.base = Some(name.to_string());
+                result.base_ahead = ahead;
+                result.base_behind = behind;
+            }
+            None => debug!("Base ref {} not found", name),
+        }
+    }
+    // Bare repositories have no work tree to compare
+    if dirs.work_dir.is_none() {
+        return Ok(result);
+    }
+
+    let index = index::read(&repo.git_dir.join("index"))?;
+    let head_tree = match head_oid {
+        Some(oid) => Some(odb.read_commit(&oid)?.tree),
+        None => None,
+    };
+    result.staged = count_staged(&odb, head_tree, &index, &mut result.files)?;
+    result.conflicts = count_conflicts(&index);
+    repo.worktree_changes(&odb, &index, &mut result)?;
+    result.untracked = repo.count_untracked(&index)?;
+    Ok(result)
+}
+
+impl Repository {
+    fn open(dirs: &GitDirs) -> Result<Repository> {
+        // Bare repositories only use the methods not touching the work tree
+        let rootdir = dirs.root();
+        let config = Config::load(&dirs.common_dir.join("config"))?;
+        if config.get_bool("extensions.worktreeConfig") == Some(true) {
+            bail!("extensions.worktreeConfig is not supported");
+        }
+        // Content filters make the work tree differ from the blobs
+        // byte-for-byte, which the stat/hash comparison below cannot handle.
+        if let Some(autocrlf) = config.get("core.autocrlf") {
+            if autocrlf.eq_ignore_ascii_case("true") || autocrlf.eq_ignore_ascii_case("input") {
+                bail!("core.autocrlf is not supported");
+            }
+        }
+        let info_attributes = dirs.common_dir.join("info/attributes");
+        for attributes in [rootdir.join(".gitattributes"), info_attributes].iter() {
+            if dirs.work_dir.is_some() && uses_content_filters(attributes) {
+                bail!("Content filters in {:?} are not supported", attributes);
+            }
+        }
+        Ok(Repository {
+            work_dir: rootdir.to_path_buf(),
+            git_dir: dirs.git_dir.clone(),
+            common_dir: dirs.common_dir.clone(),
+            config,
+        })
+    }
+
+    /// Read `HEAD`
+    fn head(&self) -> Result<Head> {
+        let contents = fs::read_to_string(self.git_dir.join("HEAD")).context("Failed to read HEAD")?;
+        match contents.trim().strip_prefix("ref: ") {
+            Some(refname) => {
+                let name = refname.strip_prefix("refs/heads/").unwrap_or(refname);
+                Ok(Head::Branch(name.to_string(), self.resolve_ref(refname)?))
+            }
+            None => Ok(Head::Detached(parse_oid(&contents)?)),
+        }
+    }
+
+    /// Resolve *refname* to an object id using loose refs and `packed-refs`
+    fn resolve_ref(&self, refname: &str) -> Result<Option<Oid>> {
+        let mut refname = refname.to_string();
+        for _ in 0..MAX_SYMREF_DEPTH {
+            match fs::read_to_string(self.ref_dir(&refname).join(&refname)) {
+                Ok(contents) => match contents.trim().strip_prefix("ref: ") {
+                    Some(target) => refname = target.to_string(),
+                    None => return parse_oid(&contents).map(Some),
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return self.find_packed_ref(&refname)
+                }
+                Err(e) => return Err(e).context("Failed to read ref"),
+            }
+        }
+        bail!("Too many levels of symbolic refs for {}", refname)
+    }
+
+    /// Resolve *name* (a full ref, branch, remote-tracking branch or tag)
+    /// to a commit
+    fn resolve_short_ref(&self, odb: &Odb, name: &str) -> Result<Option<Oid>> {
+        let candidates = [
+            name.to_string(),
+            format!("refs/heads/{}", name),
+            format!("refs/remotes/{}", name),
+            format!("refs/tags/{}", name),
+        ];
+        for refname in candidates.iter().filter(|r| r.starts_with("refs/")) {
+            if let Some(oid) = self.resolve_ref(refname)? {
+                return odb.peel(&oid);
+            }
+        }
+        Ok(None)
+    }
+
+    /// Return the directory of *refname*: `HEAD`, pseudo refs and a few
+    /// namespaces are per-worktree, all other refs are shared
+    fn ref_dir(&self, refname: &str) -> &Path {
+        let per_worktree = !refname.starts_with("refs/")
+            || ["refs/bisect/", "refs/worktree/", "refs/rewritten/"]
+                .iter()
+                .any(|prefix| refname.starts_with(prefix));
+        if per_worktree {
+            &self.git_dir
+        } else {
+            &self.common_dir
+        }
+    }
+
+    fn find_packed_ref(&self, refname: &str) -> Result<Option<Oid>> {
+        let contents = match fs::read_to_string(self.common_dir.join("packed-refs")) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).context("Failed to read packed-refs"),
+        };
+        for line in contents.lines() {
+            // Skip the header and peeled tag lines (`^<oid>`)
+            if line.starts_with('#') || line.starts_with('^') {
+                continue;
+            }
+            let mut parts = line.splitn(2, ' ');
+            if let (Some(oid), Some(name)) = (parts.next(), parts.next()) {
+                if name == refname {
+                    return parse_oid(oid).map(Some);
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// List all tags with the commit they point to
+    fn tags(&self, odb: &Odb) -> Result<Vec<(String, Oid)>> {
+        let mut tags = HashMap::new();
+        if let Ok(contents) = fs::read_to_string(self.common_dir.join("packed-refs")) {
+            for line in contents.lines() {
+                let mut parts = line.splitn(2, ' ');
+                if let (Some(oid), Some(name)) = (parts.next(), parts.next()) {
+                    if let Some(name) = name.strip_prefix("refs/tags/") {
+                        tags.insert(name.to_string(), parse_oid(oid)?);
+                    }
+                }
+            }
+        }
+        // Loose refs take precedence over packed ones
+        let mut dirs = vec![String::new()];
+        while let Some(dir) = dirs.pop() {
+            let entries = match fs::read_dir(self.common_dir.join("refs/tags").join(&dir)) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries {
+                let entry = entry?;
+                let name = format!("{}{}", dir, entry.file_name().to_string_lossy());
+                if entry.file_type()?.is_dir() {
+                    dirs.push(name + "/");
+                } else if let Ok(contents) = fs::read_to_string(entry.path()) {
+                    tags.insert(name, parse_oid(&contents)?);
+                }
+            }
+        }
+        let mut result = vec![];
+        for (name, oid) in tags {
+            if let Some(commit) = odb.peel(&oid)? {
+                result.push((name, commit));
+            }
+        }
+        result.sort();
+        Ok(result)
+    }
+
+    /// Name the detached *head* after a tag pointing to it (`v1.0`) or,
+    /// failing that, the tag reaching it in the fewest first-parent steps
+    /// (`v1.0~2`)
+    fn describe(&self, odb: &Odb, head: Oid) -> Result<Option<String>> {
+        // Tags are sorted by name, so the first one wins for each commit
+        let tags = self.tags(odb)?;
+        if let Some((name, _)) = tags.iter().find(|(_, oid)| *oid == head) {
+            return Ok(Some(name.clone()));
+        }
+
+        // Walk the first parents of all tags at once, breadth first, so that
+        // shared history is only read once and HEAD is reached in the fewest
+        // steps.  Each level stays sorted by tag name.  Commit times are not
+        // used to stop early, because clocks may be skewed.
+        let mut seen = HashSet::new();
+        let mut level: Vec<(Oid, &str)> = tags
+            .iter()
+            .filter(|(_, oid)| seen.insert(*oid))
+            .map(|(name, oid)| (*oid, name.as_str()))
+            .collect();
+        for depth in 1..MAX_DESCRIBE_DEPTH {
+            let mut next = vec![];
+            for (oid, name) in level {
+                let parent = match odb.read_commit(&oid)?.parents.first() {
+                    Some(parent) => *parent,
+                    None => continue,
+                };
+                if parent == head {
+                    return Ok(Some(format!("{}~{}", name, depth)));
+                }
+                if seen.insert(parent) {
+                    next.push((parent, name));
+                }
+            }
+            if next.is_empty() {
+                break;
+            }
+            level = next;
+        }
+        Ok(None)
+    }
+
+    /// Resolve the upstream branch configured for *branch*
+    fn upstream(&self, branch: &str) -> Result<Option<Upstream>> {
+        let (refname, remote) = match upstream_ref(&self.config, branch) {
+            Some(upstream) => upstream,
+            None => return Ok(None),
+        };
+        let oid = self.resolve_ref(&refname)?;
+        let name = refname
+            .strip_prefix("refs/remotes/")
+            .or_else(|| refname.strip_prefix("refs/heads/"))
+            .unwrap_or(&refname)
+            .to_string();
+        Ok(Some(Upstream { name, remote, oid }))
+    }
+
+    fn path_of(&self, rel: &[u8]) -> PathBuf {
+        self.work_dir.join(OsStr::from_bytes(rel))
+    }
+
+    /// Count files that differ between the index and the work tree and
+    /// collect their line statistics
+    fn worktree_changes(&self, odb: &Odb, index: &Index, status: &mut Status) -> Result<()> {
+        let filemode = self.config.get_bool("core.filemode").unwrap_or(true);
+        for entry in index.entries.iter().filter(|e| e.stage() == 0) {
+            if entry.skip_worktree() || entry.assume_valid() {
+                continue;
+            }
+            // Submodule changes are not tracked
+            if entry.mode & MODE_TYPE_MASK == MODE_GITLINK {
+                continue;
+            }
+            let path = self.path_of(&entry.path);
+            let meta = match fs::symlink_metadata(&path) {
+                Ok(meta) => Some(meta),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                // A parent was replaced by a file (ENOTDIR)
+                Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) => None,
+                Err(e) => return Err(e).with_context(|| format!("Failed to stat {:?}", path)),
+            };
+            let exists = meta.is_some();
+            let meta = match meta {
+                Some(meta) if !entry.intent_to_add() => meta,
+                // Deleted or only intended to be added
+                _ => {
+                    status.changed += 1;
+                    if exists {
+                        status.files.added += 1;
+                    } else {
+                        status.files.missing += 1;
+                    }
+                    let (added, deleted) = self.diff_stat(odb, entry, exists)?;
+                    status.added += added;
+                    status.deleted += deleted;
+                    continue;
+                }
+            };
+            if self.is_modified(entry, &meta, index, filemode)? {
+                status.changed += 1;
+                let (added, deleted) = self.diff_stat(odb, entry, true)?;
+                status.added += added;
+                status.deleted += deleted;
+            }
+        }
+        Ok(())
+    }
+
+    /// Compare an index entry with the file on disk, hashing the content
+    /// only if the cached stat data is not conclusive
+    fn is_modified(&self, entry: &Entry, meta: &Metadata, index: &Index, filemode: bool) -> Result<bool> {
+        let file_type = meta.file_type();
+        match entry.mode & MODE_TYPE_MASK {
+            MODE_SYMLINK if !file_type.is_symlink() => return Ok(true),
+            MODE_FILE if !file_type.is_file() => return Ok(true),
+            _ => (),
+        }
+        if filemode
+            && file_type.is_file()
+            && (meta.permissions().mode() & 0o100 != 0) != (entry.mode & 0o100 != 0)
+        {
+            return Ok(true);
+        }
+        // The index only stores the lower 32 bits of the size
+        if entry.size != meta.size() as u32 {
+            return Ok(true);
+        }
+        let mtime = (meta.mtime() as u32, meta.mtime_nsec() as u32);
+        let ctime = (meta.ctime() as u32, meta.ctime_nsec() as u32);
+        if entry.mtime == mtime
+            && entry.ctime == ctime
+            && entry.ino == meta.ino() as u32
+            && !index.is_racy(mtime)
+        {
+            return Ok(false);
+        }
+        let path = self.path_of(&entry.path);
+        let content = if file_type.is_symlink() {
+            fs::read_link(&path)?.as_os_str().as_bytes().to_vec()
+        } else {
+            fs::read(&path)?
+        };
+        Ok(hash_blob(&content) != entry.oid)
+    }
+
+    /// Return (inserted, deleted) lines between the index and the work tree,
+    /// like `git diff --numstat`
+    fn diff_stat(&self, odb: &Odb, entry: &Entry, exists: bool) -> Result<(u32, u32)> {
+        if entry.mode & MODE_TYPE_MASK != MODE_FILE || u64::from(entry.size) > MAX_DIFF_SIZE {
+            return Ok((0, 0));
+        }
+        let old = if entry.intent_to_add() {
+            vec![]
+        } else {
+            odb.read(&entry.oid)?.data
+        };
+        let new = if exists {
+            let path = self.path_of(&entry.path);
+            if fs::metadata(&path)?.len() > MAX_DIFF_SIZE {
+                return Ok((0, 0));
+            }
+            fs::read(&path)?
+        } else {
+            vec![]
+        };
+        Ok(line_diff(&old, &new).unwrap_or((0, 0)))
+    }
+
+    /// Count untracked files, reporting a directory without tracked files
+    /// as one entry (like `--untracked-files=normal`)
+    fn count_untracked(&self, index: &Index) -> Result<u32> {
+        let mut tracked_files = HashSet::new();
+        let mut tracked_dirs = HashSet::new();
+        for entry in index.entries.iter() {
+            tracked_files.insert(&entry.path[..]);
+            for (i, &b) in entry.path.iter().enumerate() {
+                if b == b'/' {
+                    tracked_dirs.insert(&entry.path[..i]);
+                }
+            }
+        }
+
+        let mut ignore = excludes(&self.config, &self.common_dir);
+        let walker = Walker {
+            repo: self,
+            tracked_files,
+            tracked_dirs,
+        };
+        walker.walk(b"", &mut ignore)
+    }
+}
+
+/// Load the ignore patterns that apply to the whole work tree:
+/// `core.excludesFile` (or `git/ignore` in the XDG config home) and
+/// `info/exclude` in *common_dir*
+fn excludes(config: &Config, common_dir: &Path) -> Ignore {
+    let mut ignore = Ignore::default();
+    let excludes_file = match config.get("core.excludesFile") {
+        Some(path) => Some(config::expand_path(path)),
+        None => xdg_config_home().map(|p| p.join("git/ignore")),
+    };
+    if let Some(path) = excludes_file {
+        ignore.add_file(&path, b"");
+    }
+    ignore.add_file(&common_dir.join("info/exclude"), b"");
+    ignore
+}
+
+/// Return a filter telling which directories of the work tree of *dirs*
+/// are ignored.  Parents must be checked before their subdirectories,
+/// whose `.gitignore` files are loaded on the way.
+pub fn ignored_dirs(dirs: &GitDirs) -> DirFilter {
+    let config = Config::load(&dirs.common_dir.join("config")).unwrap_or_default();
+    let mut ignore = excludes(&config, &dirs.common_dir);
+    let work_dir = dirs.root().to_path_buf();
+    let mut loaded = HashSet::new();
+    Box::new(move |dir: &Path| {
+        let path = dir.as_os_str().as_bytes();
+        let parent = path.rsplitn(2, |&b| b == b'/').nth(1).unwrap_or(b"");
+        // Patterns only match below their own directory, so the patterns
+        // of other subtrees do not need to be dropped
+        if loaded.insert(parent.to_vec()) {
+            let gitignore = work_dir.join(OsStr::from_bytes(parent)).join(".gitignore");
+            ignore.add_file(&gitignore, parent);
+        }
+        ignore.is_ignored(path, true)
+    })
+}
+
+/// Traverses the work tree looking for untracked files
+struct Walker<'a> {
+    repo:          &'a Repository,
+    tracked_files: HashSet<&'a [u8]>,
+    tracked_dirs:  HashSet<&'a [u8]>,
+}
+
+impl<'a> Walker<'a> {
+    fn read_dir(&self, dir: &[u8]) -> Result<Vec<(Vec<u8>, bool)>> {
+        let mut entries = vec![];
+        for entry in fs::read_dir(self.repo.path_of(dir))? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name == ".git" {
+                continue;
+            }
+            let mut path = dir.to_vec();
+            if !path.is_empty() {
+                path.push(b'/');
+            }
+            path.extend_from_slice(name.as_bytes());
+            entries.push((path, entry.file_type()?.is_dir()));
+        }
+        Ok(entries)
+    }
+
+    /// Count untracked entries below the tracked directory *dir*
+    fn walk(&self, dir: &[u8], ignore: &mut Ignore) -> Result<u32> {
+        let len = ignore.len();
+        ignore.add_file(&self.repo.path_of(dir).join(".gitignore"), dir);
+        let mut count = 0;
+        for (path, is_dir) in self.read_dir(dir)? {
+            if self.tracked_files.contains(&path[..]) || ignore.is_ignored(&path, is_dir) {
+                continue;
+            }
+            if !is_dir {
+                count += 1;
+            } else if self.tracked_dirs.contains(&path[..]) {
+                count += self.walk(&path, ignore)?;
+            } else if self.has_content(&path, ignore)? {
+                count += 1;
+            }
+        }
+        ignore.truncate(len);
+        Ok(count)
+    }
+
+    /// Return true if the untracked directory *dir* contains a file that is
+    /// not ignored or is a nested repository
+    fn has_content(&self, dir: &[u8], ignore: &mut Ignore) -> Result<bool> {
+        let dir_path = self.repo.path_of(dir);
+        if dir_path.join(".git").exists() {
+            return Ok(true);
+        }
+        let len = ignore.len();
+        ignore.add_file(&dir_path.join(".gitignore"), dir);
+        let mut found = false;
+        for (path, is_dir) in self.read_dir(dir)? {
+            if ignore.is_ignored(&path, is_dir) {
+                continue;
+            }
+            if !is_dir || self.has_content(&path, ignore)? {
+                found = true;
+                break;
+            }
+        }
+        ignore.truncate(len);
+        Ok(found)
+    }
+}
+
+/// Return true if a gitattributes file sets attributes that change content
+/// on checkout (eol conversion, filters, ...)
+fn uses_content_filters(path: &Path) -> bool {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(_) => return false,
+    };
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(|line| line.split_whitespace().skip(1))
+        .map(|attr| attr.trim_start_matches(['-', '!']))
+        .map(|attr| attr.split('=').next().unwrap_or(attr))
+        .any(|attr| {
+            ["text", "eol", "crlf", "filter", "ident", "working-tree-encoding"].contains(&attr)
+        })
+}
+
+/// Count paths that differ between HEAD and the index and add the added,
+/// deleted and renamed ones to *files*
+fn count_staged(
+    odb: &Odb,
+    head_tree: Option<Oid>,
+    index: &Index,
+    files: &mut FileCounts,
+) -> Result<u32> {
+    let mut head_files = HashMap::new();
+    let mut unchanged_dirs = HashSet::new();
+    if let Some(tree) = head_tree {
+        collect_tree(odb, tree, b"", index, &mut head_files, &mut unchanged_dirs)?;
+    }
+    let in_unchanged_dir = |path: &[u8]| {
+        unchanged_dirs.contains(&b""[..])
+            || path
+                .iter()
+                .enumerate()
+                .any(|(i, &b)| b == b'/' && unchanged_dirs.contains(&path[..i]))
+    };
+
+    let mut staged = 0;
+    let mut added = vec![];
+    let mut conflicted = HashSet::new();
+    for entry in index.entries.iter() {
+        if entry.stage() != 0 {
+            conflicted.insert(&entry.path[..]);
+            continue;
+        }
+        if entry.intent_to_add() || in_unchanged_dir(&entry.path) {
+            continue;
+        }
+        match head_files.remove(&entry.path[..]) {
+            Some((mode, oid)) => {
+                if mode != entry.mode || oid != entry.oid {
+                    staged += 1;
+                }
+            }
+            None => added.push(entry.oid),
+        }
+    }
+    let deleted: Vec<Oid> = head_files
+        .into_iter()
+        .filter(|(path, _)| !conflicted.contains(&path[..]))
+        .map(|(_, (_, oid))| oid)
+        .collect();
+
+    // Pair deletions with additions of the same content as exact renames
+    let mut deleted_oids: HashMap<Oid, u32> = HashMap::new();
+    for oid in deleted.iter() {
+        *deleted_oids.entry(*oid).or_insert(0) += 1;
+    }
+    let mut renames = 0;
+    for oid in added.iter() {
+        if let Some(count) = deleted_oids.get_mut(oid).filter(|c| **c > 0) {
+            *count -= 1;
+            renames += 1;
+        }
+    }
+    files.added += added.len() as u32 - renames;
+    files.deleted += deleted.len() as u32 - renames;
+    files.renamed += renames;
+    Ok(staged + (added.len() + deleted.len()) as u32 - renames)
+}
+
+/// Flatten the tree *oid* into *files*, skipping directories that the
+/// index' cache tree reports as unchanged
+fn collect_tree(
+    odb: &Odb,
+    oid: Oid,
+    prefix: &[u8],
+    index: &Index,
+    files: &mut HashMap<Vec<u8>, (u32, Oid)>,
+    unchanged_dirs: &mut HashSet<Vec<u8>>,
+) -> Result<()> {
+    if index.cache_tree.get(prefix) == Some(&oid) {
+        unchanged_dirs.insert(prefix.to_vec());
+        return Ok(());
+    }
+    for entry in odb.read_tree(&oid)? {
+        let mut path = prefix.to_vec();
+        if !path.is_empty() {
+            path.push(b'/');
+        }
+        path.extend_from_slice(&entry.name);
+        if entry.is_tree() {
+            collect_tree(odb, entry.oid, &path, index, files, unchanged_dirs)?;
+        } else {
+            files.insert(path, (entry.mode, entry.oid));
+        }
+    }
+    Ok(())
+}
+
+/// Count paths with unmerged entries
+fn count_conflicts(index: &Index) -> u32 {
+    let mut paths: Vec<&[u8]> = index
+        .entries
+        .iter()
+        .filter(|e| e.stage() != 0)
+        .map(|e| &e.path[..])
+        .collect();
+    paths.dedup();
+    paths.len() as u32
+}
+
+/// Count commits reachable from only one of *local* and *upstream*
+fn ahead_behind(odb: &Odb, local: Oid, upstream: Oid) -> Result<(u32, u32)> {
+    const LOCAL: u8 = 1;
+    const UPSTREAM: u8 = 2;
+    const BOTH: u8 = LOCAL | UPSTREAM;
+
+    if local == upstream {
+        return Ok((0, 0));
+    }
+    let mut flags: HashMap<Oid, u8> = HashMap::new();
+    // Number of times each commit is in the queue
+    let mut queued: HashMap<Oid, usize> = HashMap::new();
+    // Number of queue entries not reachable from both sides yet
+    let mut pending = 0;
+    let mut queue = BinaryHeap::new();
+    for &(oid, flag) in [(local, LOCAL), (upstream, UPSTREAM)].iter() {
+        let commit = odb.read_commit(&oid)?;
+        flags.insert(oid, flag);
+        *queued.entry(oid).or_insert(0) += 1;
+        pending += 1;
+        queue.push((commit.time, oid, commit.parents));
+    }
+
+    // Walk newest first until only commits reachable from both sides remain.
+    // Commits with equal timestamps may be visited before their children, so
+    // they are visited again whenever they get a new flag.
+    while pending > 0 {
+        let (_, oid, parents) = match queue.pop() {
+            Some(item) => item,
+            None => break,
+        };
+        let flag = flags[&oid];
+        *queued.get_mut(&oid).unwrap() -= 1;
+        if flag != BOTH {
+            pending -= 1;
+        }
+        for parent in parents {
+            let parent_flag = flags.entry(parent).or_insert(0);
+            if *parent_flag | flag == *parent_flag {
+                continue;
+            }
+            *parent_flag |= flag;
+            let count = queued.entry(parent).or_insert(0);
+            if *parent_flag == BOTH {
+                // Entries queued before are not pending anymore
+                pending -= *count;
+            } else {
+                pending += 1;
+            }
+            *count += 1;
+            let commit = odb.read_commit(&parent)?;
+            queue.push((commit.time, parent, commit.parents));
+        }
+    }
+    let ahead = flags.values().filter(|&&flag| flag == LOCAL).count();
+    let behind = flags.values().filter(|&&flag| flag == UPSTREAM).count();
+    Ok((ahead as u32, behind as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::exec_cmd;
+    use std::env::temp_dir;
+
+    #[test]
+    fn content_filters() {
+        let mut path = temp_dir();
+        path.push("test-vcprompt-attributes");
+        fs::write(&path, "*.png binary\n# *.txt text\n").unwrap();
+        assert!(!uses_content_filters(&path));
+        fs::write(&path, "*.bin filter=lfs diff=lfs merge=lfs -text\n").unwrap();
+        assert!(uses_content_filters(&path));
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Compare the native backend against `git status` in a scratch repository
+    #[test]
+    fn matches_git_status() {
+        let mut rootdir = temp_dir();
+        rootdir.push("test-vcprompt-native");
+        let _ = fs::remove_dir_all(&rootdir);
+        fs::create_dir_all(&rootdir).unwrap();
+        let git = |args: &[&str]| {
+            let mut full = vec!["-c", "user.name=T", "-c", "user.email=t@example.com"];
+            full.extend_from_slice(args);
+            exec_cmd("git", &full, &rootdir).unwrap()
+        };
+        let write = |name: &str, contents: &str| {
+            let path = rootdir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        };
+
+        git(&["init", "-q", "-b", "main"]);
+        write(".gitignore", "*.log\nbuild/\n");
+        write("modified.txt", "one\ntwo\n");
+        write("deleted.txt", "gone\n");
+        write("renamed.txt", "moving\n");
+        write("src/staged.txt", "staged\n");
+        git(&["add", "-A"]);
+        git(&["commit", "-q", "-m", "initial"]);
+
+        write("modified.txt", "one\nthree\nfour\n");
+        fs::remove_file(rootdir.join("deleted.txt")).unwrap();
+        write("src/staged.txt", "staged\nmore\n");
+        git(&["add", "src/staged.txt"]);
+        git(&["mv", "renamed.txt", "moved.txt"]);
+        write("new.txt", "new\n");
+        write("debug.log", "ignored\n");
+        write("build/out.o", "ignored\n");
+        write("untracked_dir/a.txt", "a\n");
+        write("untracked_dir/b.txt", "b\n");
+
+        let mut expected = Status::new(&Git);
+        expected.branch = String::from("main");
+        expected.commit = git(&["rev-parse", "HEAD"]).stdout.trim().to_string();
+        expected.staged = 2;
+        expected.changed = 2;
+        expected.untracked = 2;
+        expected.added = 2;
+        expected.deleted = 2;
+        expected.files.renamed = 1;
+        expected.files.missing = 1;
+        expected.tracking = Tracking::None;
+        assert_eq!(status(&GitDirs::find(&rootdir).unwrap(), None).unwrap(), expected);
+
+        // A linked worktree shares objects and refs with the main repository
+        let worktree = rootdir.with_file_name("test-vcprompt-native-wt");
+        let _ = fs::remove_dir_all(&worktree);
+        git(&["worktree", "add", "-q", "-b", "feature", worktree.to_str().unwrap()]);
+        let mut expected = Status::new(&Git);
+        expected.branch = String::from("feature");
+        expected.commit = git(&["rev-parse", "HEAD"]).stdout.trim().to_string();
+        expected.tracking = Tracking::None;
+        assert_eq!(status(&GitDirs::find(&worktree).unwrap(), None).unwrap(), expected);
+
+        // Local upstream branches have no remote
+        git(&["config", "branch.feature.remote", "."]);
+        git(&["config", "branch.feature.merge", "refs/heads/main"]);
+        expected.upstream = Some(String::from("main"));
+        expected.tracking = Tracking::Upstream;
+        assert_eq!(status(&GitDirs::find(&worktree).unwrap(), None).unwrap(), expected);
+
+        git(&["config", "branch.feature.remote", "origin"]);
+        expected.upstream = Some(String::from("origin/main"));
+        expected.remote = Some(String::from("origin"));
+        expected.tracking = Tracking::Gone;
+        assert_eq!(status(&GitDirs::find(&worktree).unwrap(), None).unwrap(), expected);
+
+        fs::remove_dir_all(&worktree).unwrap();
+        fs::remove_dir_all(&rootdir).unwrap();
+    }
+
+    #[test]
+    fn describe_detached_head() {
+        let mut rootdir = temp_dir();
+        rootdir.push("test-vcprompt-describe");
+        let _ = fs::remove_dir_all(&rootdir);
+        fs::create_dir_all(&rootdir).unwrap();
+        let git = |args: &[&str]| {
+            let mut full = vec!["-c", "user.name=T", "-c", "user.email=t@example.com"];
+            full.extend_from_slice(args);
+            exec_cmd("git", &full, &rootdir).unwrap().stdout.trim().to_string()
+        };
+        let detached = |rev: &str| {
+            git(&["checkout", "-q", "--detach", rev]);
+            let status = status(&GitDirs::find(&rootdir).unwrap(), None).unwrap();
+            (status.branch, status.ref_kind)
+        };
+
+        git(&["init", "-q", "-b", "main"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "one"]);
+        git(&["tag", "-a", "-m", "first", "v1.0"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "two"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "three"]);
+        git(&["tag", "v2.0"]);
+        git(&["checkout", "-q", "-b", "side", "HEAD~2"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "side"]);
+        let side = git(&["rev-parse", "HEAD"]);
+
+        for _ in 0..2 {
+            assert_eq!(detached("main~2"), (String::from("v1.0"), RefKind::Tag));
+            assert_eq!(detached("main~1"), (String::from("v2.0~1"), RefKind::Tag));
+            assert_eq!(detached(&side), (side[..7].to_string(), RefKind::Commit));
+            git(&["pack-refs", "--all"]);
+        }
+
+        // Compare with a base branch or tag
+        let dirs = GitDirs::find(&rootdir).unwrap();
+        let result = status(&dirs, Some("main")).unwrap();
+        assert_eq!(result.base.as_deref(), Some("main"));
+        assert_eq!((result.base_ahead, result.base_behind), (1, 2));
+        let result = status(&dirs, Some("v1.0")).unwrap();
+        assert_eq!((result.base_ahead, result.base_behind), (1, 0));
+        assert_eq!(status(&dirs, Some("nope")).unwrap().base, None);
+
+        // The tag with the fewest steps wins, not the first one by name
+        git(&["checkout", "-q", "main"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "four"]);
+        git(&["tag", "a1.0"]);
+        assert_eq!(detached("main~2"), (String::from("v2.0~1"), RefKind::Tag));
+        assert_eq!(detached("main~3"), (String::from("v1.0"), RefKind::Tag));
+
+        // Tags on commits with a skewed clock are found, too
+        git(&["checkout", "-q", "main"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "five"]);
+        let skewed = std::process::Command::new("git")
+            .args(["-c", "user.name=T", "-c", "user.email=t@example.com"])
+            .args(["commit", "-q", "--allow-empty", "-m", "skewed"])
+            .env("GIT_COMMITTER_DATE", "2000-01-01T00:00:00Z")
+            .current_dir(&rootdir)
+            .status()
+            .unwrap();
+        assert!(skewed.success());
+        git(&["tag", "z1.0"]);
+        assert_eq!(detached("main~1"), (String::from("z1.0~1"), RefKind::Tag));
+
+        fs::remove_dir_all(&rootdir).unwrap();
+    }
+}
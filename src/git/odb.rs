@@ -0,0 +1,540 @@
+//! Read objects from the git object database (loose objects and packfiles)
+use anyhow::{bail, format_err, Context, Result};
+use flate2::read::ZlibDecoder;
+use std::{
+    fs::{self, File},
+    io::{prelude::*, BufReader, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+/// Raw SHA-1 object id
+pub type Oid = [u8; 20];
+
+const MODE_TREE: u32 = 0o040000;
+
+/// Maximum number of nested annotated tags to follow
+const MAX_TAG_DEPTH: usize = 10;
+
+/// Parse a 40 character hex string into an object id
+pub fn parse_oid(hex: &str) -> Result<Oid> {
+    let hex = hex.trim();
+    if hex.len() != 40 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("Invalid object id: {:?}", hex);
+    }
+    let mut oid = [0; 20];
+    for (i, byte) in oid.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .with_context(|| format!("Invalid object id: {:?}", hex))?;
+    }
+    Ok(oid)
+}
+
+/// Format an object id as 40 character hex string
+pub fn to_hex(oid: &Oid) -> String {
+    oid.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Compute the id `git hash-object` would assign to *content*
+pub fn hash_blob(content: &[u8]) -> Oid {
+    let mut hasher = sha1_smol::Sha1::new();
+    hasher.update(format!("blob {}\0", content.len()).as_bytes());
+    hasher.update(content);
+    hasher.digest().bytes()
+}
+
+/// Object types stored in the database
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+}
+
+impl Kind {
+    fn from_name(name: &[u8]) -> Result<Kind> {
+        match name {
+            b"commit" => Ok(Kind::Commit),
+            b"tree" => Ok(Kind::Tree),
+            b"blob" => Ok(Kind::Blob),
+            b"tag" => Ok(Kind::Tag),
+            _ => bail!("Unknown object type {:?}", String::from_utf8_lossy(name)),
+        }
+    }
+
+    fn from_pack_type(t: u8) -> Result<Kind> {
+        match t {
+            1 => Ok(Kind::Commit),
+            2 => Ok(Kind::Tree),
+            3 => Ok(Kind::Blob),
+            4 => Ok(Kind::Tag),
+            _ => bail!("Unknown pack object type {}", t),
+        }
+    }
+}
+
+/// A decompressed object
+#[derive(Debug)]
+pub struct Object {
+    pub kind: Kind,
+    pub data: Vec<u8>,
+}
+
+/// The parts of a commit needed for status
+#[derive(Debug, Eq, PartialEq)]
+pub struct Commit {
+    pub tree:    Oid,
+    pub parents: Vec<Oid>,
+    /// Committer timestamp (seconds since epoch)
+    pub time:    i64,
+}
+
+/// Single entry of a tree object
+#[derive(Debug, Eq, PartialEq)]
+pub struct TreeEntry {
+    pub mode: u32,
+    pub name: Vec<u8>,
+    pub oid:  Oid,
+}
+
+impl TreeEntry {
+    pub fn is_tree(&self) -> bool {
+        self.mode == MODE_TREE
+    }
+}
+
+/// Object database of a repository, including its alternates
+pub struct Odb {
+    dirs:  Vec<PathBuf>,
+    packs: Vec<Pack>,
+}
+
+impl Odb {
+    /// Open the object database rooted at *objects_dir* (usually `.git/objects`)
+    pub fn open(objects_dir: &Path) -> Result<Odb> {
+        let mut dirs = vec![objects_dir.to_path_buf()];
+        if let Ok(alternates) = fs::read_to_string(objects_dir.join("info/alternates")) {
+            for line in alternates.lines() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                dirs.push(objects_dir.join(line));
+            }
+        }
+        let mut packs = vec![];
+        for dir in dirs.iter() {
+            let entries = match fs::read_dir(dir.join("pack")) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries {
+                let path = entry?.path();
+                if path.extension().is_some_and(|ext| ext == "idx") {
+                    packs.push(Pack::open(&path)?);
+                }
+            }
+        }
+        Ok(Odb { dirs, packs })
+    }
+
+    /// Read and decompress the object *oid*
+    pub fn read(&self, oid: &Oid) -> Result<Object> {
+        let hex = to_hex(oid);
+        for dir in self.dirs.iter() {
+            let path = dir.join(&hex[..2]).join(&hex[2..]);
+            if let Ok(compressed) = fs::read(&path) {
+                return read_loose(&compressed).with_context(|| format!("Corrupt object {}", hex));
+            }
+        }
+        for pack in self.packs.iter() {
+            if let Some(offset) = pack.find(oid)? {
+                return pack
+                    .read_at(self, offset)
+                    .with_context(|| format!("Corrupt object {}", hex));
+            }
+        }
+        Err(format_err!("Object {} not found", hex))
+    }
+
+    /// Read *oid* and parse it as commit
+    pub fn read_commit(&self, oid: &Oid) -> Result<Commit> {
+        let object = self.read(oid)?;
+        if object.kind != Kind::Commit {
+            bail!("Object {} is not a commit", to_hex(oid));
+        }
+        parse_commit(&object.data)
+    }
+
+    /// Follow annotated tags starting at *oid* to a commit.
+    ///
+    /// Returns `None` for tags pointing to other objects.
+    pub fn peel(&self, oid: &Oid) -> Result<Option<Oid>> {
+        let mut oid = *oid;
+        for _ in 0..MAX_TAG_DEPTH {
+            let object = self.read(&oid)?;
+            match object.kind {
+                Kind::Commit => return Ok(Some(oid)),
+                Kind::Tag => {
+                    let target = object
+                        .data
+                        .strip_prefix(b"object ")
+                        .and_then(|rest| rest.get(..40))
+                        .context("Invalid tag object")?;
+                    oid = parse_oid(std::str::from_utf8(target)?)?;
+                }
+                _ => return Ok(None),
+            }
+        }
+        bail!("Too many levels of tags for {}", to_hex(&oid))
+    }
+
+    /// Read *oid* and parse it as tree
+    pub fn read_tree(&self, oid: &Oid) -> Result<Vec<TreeEntry>> {
+        let object = self.read(oid)?;
+        if object.kind != Kind::Tree {
+            bail!("Object {} is not a tree", to_hex(oid));
+        }
+        parse_tree(&object.data)
+    }
+}
+
+fn read_loose(compressed: &[u8]) -> Result<Object> {
+    let mut data = Vec::with_capacity(compressed.len() * 2);
+    ZlibDecoder::new(compressed).read_to_end(&mut data)?;
+    let nul = data
+        .iter()
+        .position(|&b| b == 0)
+        .context("Missing object header")?;
+    let kind = {
+        let header = &data[..nul];
+        let space = header
+            .iter()
+            .position(|&b| b == b' ')
+            .context("Invalid object header")?;
+        Kind::from_name(&header[..space])?
+    };
+    data.drain(..=nul);
+    Ok(Object { kind, data })
+}
+
+/// A packfile together with its v2 index
+struct Pack {
+    idx:    File,
+    pack:   File,
+    fanout: [u32; 256],
+}
+
+const IDX_MAGIC: &[u8] = b"\xfftOc";
+const IDX_HEADER_LEN: u64 = 8 + 256 * 4;
+
+impl Pack {
+    fn open(idx_path: &Path) -> Result<Pack> {
+        let mut idx = File::open(idx_path)?;
+        let mut header = [0; IDX_HEADER_LEN as usize];
+        idx.read_exact(&mut header)?;
+        if &header[..4] != IDX_MAGIC || header[4..8] != [0, 0, 0, 2] {
+            bail!("Unsupported pack index {:?}", idx_path);
+        }
+        let mut fanout = [0; 256];
+        for (i, count) in fanout.iter_mut().enumerate() {
+            *count = be_u32(&header[8 + i * 4..]);
+        }
+        let pack = File::open(idx_path.with_extension("pack"))?;
+        Ok(Pack { idx, pack, fanout })
+    }
+
+    fn len(&self) -> u64 {
+        u64::from(self.fanout[255])
+    }
+
+    fn read_idx(&self, pos: u64, buf: &mut [u8]) -> Result<()> {
+        let mut idx = &self.idx;
+        idx.seek(SeekFrom::Start(pos))?;
+        idx.read_exact(buf)?;
+        Ok(())
+    }
+
+    /// Binary search the index for *oid* and return its offset in the pack
+    fn find(&self, oid: &Oid) -> Result<Option<u64>> {
+        let first = oid[0] as usize;
+        let mut lo = if first == 0 { 0 } else { self.fanout[first - 1] };
+        let mut hi = self.fanout[first];
+        let mut name = [0; 20];
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            self.read_idx(IDX_HEADER_LEN + u64::from(mid) * 20, &mut name)?;
+            match name.cmp(oid) {
+                std::cmp::Ordering::Less => lo = mid + 1,
+                std::cmp::Ordering::Greater => hi = mid,
+                std::cmp::Ordering::Equal => return self.offset(u64::from(mid)).map(Some),
+            }
+        }
+        Ok(None)
+    }
+
+    fn offset(&self, pos: u64) -> Result<u64> {
+        let offsets = IDX_HEADER_LEN + self.len() * 24;
+        let mut buf = [0; 8];
+        self.read_idx(offsets + pos * 4, &mut buf[..4])?;
+        let offset = be_u32(&buf);
+        if offset & 0x8000_0000 == 0 {
+            return Ok(u64::from(offset));
+        }
+        let large = offsets + self.len() * 4 + u64::from(offset & 0x7fff_ffff) * 8;
+        self.read_idx(large, &mut buf)?;
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    /// Read the entry at *offset*, resolving deltas
+    fn read_at(&self, odb: &Odb, offset: u64) -> Result<Object> {
+        let mut reader = BufReader::new(&self.pack);
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut c = read_byte(&mut reader)?;
+        let pack_type = (c >> 4) & 0x07;
+        let mut size = u64::from(c & 0x0f);
+        let mut shift = 4;
+        while c & 0x80 != 0 {
+            c = read_byte(&mut reader)?;
+            size |= u64::from(c & 0x7f) << shift;
+            shift += 7;
+        }
+        match pack_type {
+            // OFS_DELTA
+            6 => {
+                let distance = read_offset_varint(&mut reader)?;
+                let delta = inflate(&mut reader, size)?;
+                let base_offset = offset
+                    .checked_sub(distance)
+                    .context("Invalid delta base offset")?;
+                let base = self.read_at(odb, base_offset)?;
+                Ok(Object {
+                    kind: base.kind,
+                    data: apply_delta(&base.data, &delta)?,
+                })
+            }
+            // REF_DELTA
+            7 => {
+                let mut base_oid = [0; 20];
+                reader.read_exact(&mut base_oid)?;
+                let delta = inflate(&mut reader, size)?;
+                let base = odb.read(&base_oid)?;
+                Ok(Object {
+                    kind: base.kind,
+                    data: apply_delta(&base.data, &delta)?,
+                })
+            }
+            t => Ok(Object {
+                kind: Kind::from_pack_type(t)?,
+                data: inflate(&mut reader, size)?,
+            }),
+        }
+    }
+}
+
+fn be_u32(buf: &[u8]) -> u32 {
+    u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]])
+}
+
+fn read_byte<R: Read>(reader: &mut R) -> Result<u8> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+/// Read the offset encoding used by OFS_DELTA entries and index v4 paths
+pub fn read_offset_varint<R: Read>(reader: &mut R) -> Result<u64> {
+    let mut c = read_byte(reader)?;
+    let mut value = u64::from(c & 0x7f);
+    while c & 0x80 != 0 {
+        c = read_byte(reader)?;
+        value = ((value + 1) << 7) | u64::from(c & 0x7f);
+    }
+    Ok(value)
+}
+
+fn inflate<R: BufRead>(reader: &mut R, size: u64) -> Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(size as usize);
+    ZlibDecoder::new(reader).take(size).read_to_end(&mut data)?;
+    if data.len() as u64 != size {
+        bail!("Object size mismatch");
+    }
+    Ok(data)
+}
+
+/// Read the size encoding used in delta headers
+fn delta_size(delta: &[u8], pos: &mut usize) -> Result<usize> {
+    let mut size = 0;
+    let mut shift = 0;
+    loop {
+        let c = *delta.get(*pos).context("Truncated delta")?;
+        *pos += 1;
+        size |= usize::from(c & 0x7f) << shift;
+        shift += 7;
+        if c & 0x80 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+/// Reconstruct an object from its *base* and a git delta
+fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut pos = 0;
+    if delta_size(delta, &mut pos)? != base.len() {
+        bail!("Delta base size mismatch");
+    }
+    let size = delta_size(delta, &mut pos)?;
+    let mut result = Vec::with_capacity(size);
+    while pos < delta.len() {
+        let op = delta[pos];
+        pos += 1;
+        if op & 0x80 != 0 {
+            let mut args = [0usize; 7];
+            for (bit, arg) in args.iter_mut().enumerate() {
+                if op & (1 << bit) != 0 {
+                    *arg = usize::from(*delta.get(pos).context("Truncated delta")?);
+                    pos += 1;
+                }
+            }
+            let start = args[0] | args[1] << 8 | args[2] << 16 | args[3] << 24;
+            let mut len = args[4] | args[5] << 8 | args[6] << 16;
+            if len == 0 {
+                len = 0x10000;
+            }
+            let chunk = base
+                .get(start..start + len)
+                .context("Delta copy out of range")?;
+            result.extend_from_slice(chunk);
+        } else if op != 0 {
+            let len = usize::from(op);
+            let chunk = delta
+                .get(pos..pos + len)
+                .context("Delta insert out of range")?;
+            result.extend_from_slice(chunk);
+            pos += len;
+        } else {
+            bail!("Invalid delta opcode");
+        }
+    }
+    if result.len() != size {
+        bail!("Delta result size mismatch");
+    }
+    Ok(result)
+}
+
+fn parse_commit(data: &[u8]) -> Result<Commit> {
+    let mut tree = None;
+    let mut parents = vec![];
+    let mut time = 0;
+    for line in data.split(|&b| b == b'\n') {
+        if line.is_empty() {
+            break;
+        }
+        let text = String::from_utf8_lossy(line);
+        let mut parts = text.splitn(2, ' ');
+        match (parts.next(), parts.next()) {
+            (Some("tree"), Some(hex)) => tree = Some(parse_oid(hex)?),
+            (Some("parent"), Some(hex)) => parents.push(parse_oid(hex)?),
+            (Some("committer"), Some(ident)) => {
+                // "Name <email> 1577836800 +0100"
+                time = ident
+                    .rsplit(' ')
+                    .nth(1)
+                    .and_then(|t| t.parse().ok())
+                    .unwrap_or(0);
+            }
+            _ => (),
+        }
+    }
+    Ok(Commit {
+        tree: tree.context("Commit without tree")?,
+        parents,
+        time,
+    })
+}
+
+fn parse_tree(mut data: &[u8]) -> Result<Vec<TreeEntry>> {
+    let mut entries = vec![];
+    while !data.is_empty() {
+        let space = data
+            .iter()
+            .position(|&b| b == b' ')
+            .context("Invalid tree entry")?;
+        let mode = u32::from_str_radix(&String::from_utf8_lossy(&data[..space]), 8)
+            .context("Invalid tree entry mode")?;
+        let nul = space
+            + data[space..]
+                .iter()
+                .position(|&b| b == 0)
+                .context("Invalid tree entry")?;
+        let name = data[space + 1..nul].to_vec();
+        let mut oid = [0; 20];
+        oid.copy_from_slice(data.get(nul + 1..nul + 21).context("Truncated tree")?);
+        entries.push(TreeEntry { mode, name, oid });
+        data = &data[nul + 21..];
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oid_roundtrip() {
+        let hex = "dc716b061d9a0bc6a59f4e02d72b9952cce28927";
+        assert_eq!(to_hex(&parse_oid(hex).unwrap()), hex);
+        assert!(parse_oid("dc716b").is_err());
+        // 40 bytes, but not 40 characters
+        assert!(parse_oid("dä16b061d9a0bc6a59f4e02d72b9952cce28927").is_err());
+    }
+
+    #[test]
+    fn blob_hash() {
+        // `printf 'hello\n' | git hash-object --stdin`
+        assert_eq!(
+            to_hex(&hash_blob(b"hello\n")),
+            "ce013625030ba8dba906f756967f9e9ca394464a"
+        );
+    }
+
+    #[test]
+    fn delta_copy_and_insert() {
+        let base = b"hello world";
+        // base size 11, result size 12, copy 6 bytes from 0, insert "there!"
+        let delta = b"\x0b\x0c\x90\x06\x06there!";
+        assert_eq!(apply_delta(base, delta).unwrap(), b"hello there!");
+    }
+
+    #[test]
+    fn commit_headers() {
+        let data = b"tree dc716b061d9a0bc6a59f4e02d72b9952cce28927
+parent ce013625030ba8dba906f756967f9e9ca394464a
+author A U Thor <author@example.com> 1577836800 +0100
+committer C O Mitter <committer@example.com> 1577836900 +0100
+
+Subject
+";
+        let commit = parse_commit(data).unwrap();
+        assert_eq!(
+            to_hex(&commit.tree),
+            "dc716b061d9a0bc6a59f4e02d72b9952cce28927"
+        );
+        assert_eq!(commit.parents.len(), 1);
+        assert_eq!(commit.time, 1_577_836_900);
+    }
+
+    #[test]
+    fn tree_entries() {
+        let mut data = b"100644 file.txt\0".to_vec();
+        data.extend_from_slice(&[1; 20]);
+        data.extend_from_slice(b"40000 dir\0");
+        data.extend_from_slice(&[2; 20]);
+        let entries = parse_tree(&data).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].mode, 0o100644);
+        assert_eq!(entries[0].name, b"file.txt");
+        assert!(entries[1].is_tree());
+        assert_eq!(entries[1].oid, [2; 20]);
+    }
+}
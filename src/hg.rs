@@ -1,48 +1,312 @@
 //! Get Mercurial (hg) status
-use crate::{status::Status, util::logger::*, vcs::VCS};
-use anyhow::{format_err, Context, Result};
-use std::{fs::File, io::prelude::*, path::PathBuf, process::Command};
+#[cfg(unix)]
+mod diff;
+#[cfg_attr(not(unix), allow(dead_code))]
+mod dirstate;
+#[cfg(unix)]
+mod ignore;
+#[cfg(unix)]
+mod native;
+mod obsstore;
+mod phases;
+mod revlog;
 
-/// Get the status for the cwd
-pub fn status(rootdir: PathBuf) -> Result<Status> {
-    let status_str = get_status()?;
+use crate::{
+    config::Config,
+    status::Status,
+    util::{exec_cmd, logger::*, parse_diff_stat_summary},
+    vcs::{Backend, DirFilter},
+};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashSet,
+    env,
+    fs::{self, File},
+    io::prelude::*,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Template printing the phase of the working directory parent and whether
+/// it is obsolete or unstable (evolve).  Computing instabilities walks the
+/// history, so this is only used with `VCP_HG_TRACKING`; otherwise, only
+/// obsolete and orphan parents are detected by `read_drafts()`.
+const TRACKING_TEMPLATE: &str = "{phase} {obsolete} {instabilities}";
+
+/// Files in `.hg` that indicate an ongoing operation
+static OPERATIONS: [(&str, &str); 6] = [
+    ("rebasestate", "REBASE"),
+    ("histedit-state", "HISTEDIT"),
+    ("graftstate", "GRAFTING"),
+    ("shelvedstate", "UNSHELVING"),
+    ("transplant/journal", "TRANSPLANTING"),
+    ("bisect.state", "BISECTING"),
+];
+
+/// Length of a node id in the dirstate
+const NODE_LEN: usize = 20;
+
+/// Marker at the start of a dirstate-v2 docket, which is followed by the
+/// parents padded to 32 bytes each
+const DIRSTATE_V2_MARKER: &[u8] = b"dirstate-v2\n";
+
+/// Mercurial backend
+pub struct Hg;
+
+impl Backend for Hg {
+    fn name(&self) -> &'static str {
+        "Hg"
+    }
+
+    fn symbol(&self) -> &'static str {
+        "☿"
+    }
+
+    fn detect(&self, dir: &Path) -> bool {
+        dir.join(".hg/00changelog.i").exists()
+    }
+
+    fn status(&self, rootdir: &Path) -> Result<Status> {
+        status(rootdir)
+    }
+
+    /// `.hg` (the dirstate, bookmarks and ongoing operations) and the
+    /// phases, bookmarks and obsolescence markers in the store
+    fn watched_paths(&self, rootdir: &Path, _config: &Config) -> Vec<PathBuf> {
+        let hg_dir = rootdir.join(".hg");
+        let store = store_dir(&hg_dir);
+        let mut paths: Vec<_> = ["merge", "shelved", "transplant"]
+            .iter()
+            .map(|dir| hg_dir.join(dir))
+            .collect();
+        paths.extend(
+            ["00changelog.i", "phaseroots", "bookmarks", "obsstore"]
+                .iter()
+                .map(|file| store.join(file)),
+        );
+        paths.push(hg_dir);
+        paths
+    }
+
+    fn ignored_dirs(&self, rootdir: &Path) -> DirFilter {
+        ignored_dirs(rootdir)
+    }
+}
+
+/// Get the status of the repository at *rootdir*
+fn status(rootdir: &Path) -> Result<Status> {
+    let mut status = match env::var("VCP_HG_BACKEND").as_ref().map(String::as_str) {
+        Ok("command") => command_status(rootdir)?,
+        _ => native_status(rootdir).or_else(|e| {
+            debug!("Native status failed, falling back to `hg`: {:#}", e);
+            command_status(rootdir)
+        })?,
+    };
+    status.branch = get_branch(rootdir)?;
+    status.commit = match dirstate_parents(rootdir).into_iter().next() {
+        Some(node) => node,
+        None => String::from("(initial)"),
+    };
+    status.bookmarks = get_bookmarks(rootdir, &status.commit);
+    status.bookmark = read_name(&rootdir.join(".hg/bookmarks.current"));
+    status.topic = read_name(&rootdir.join(".hg/topic"));
+    status.conflicts = count_conflicts(rootdir);
+    status.stashes = count_shelves(rootdir);
+    get_operations(&mut status.operations, rootdir);
+    if let Some(node) = dirstate_parents(rootdir).first() {
+        if let Err(e) = read_drafts(rootdir, node, &mut status) {
+            debug!("Failed to read the phase: {:#}", e);
+        }
+    }
+    // Starting `hg` takes longer than everything above, so the values that
+    // need it are opt-in
+    if env_flag("VCP_HG_TRACKING") {
+        match get_tracking(rootdir) {
+            Ok(tracking) => parse_tracking(&tracking, &mut status),
+            Err(e) => debug!("Failed to get phase and tracking info: {:#}", e),
+        }
+    }
+    Ok(status)
+}
+
+/// Read the phase of the working directory parent *node*, the number of
+/// outgoing changesets and whether it is obsolete or orphan (evolve) from
+/// the store
+fn read_drafts(rootdir: &Path, node: &str, status: &mut Status) -> Result<()> {
+    let hg_dir = rootdir.join(".hg");
+    let mut drafts = phases::drafts(&hg_dir, node)?;
+    let phase = drafts.phase()?;
+    status.phase = Some(phase.to_string());
+    // Public changesets cannot become obsolete
+    if phase == "public" {
+        return Ok(());
+    }
+    status.ahead = drafts.outgoing()?;
+    let obsolete = obsstore::obsolete(&store_dir(&hg_dir))?;
+    let node = revlog::parse_node(node).context("Invalid node")?;
+    if obsolete.contains(&node) {
+        status.unstable.push(String::from("obsolete"));
+    } else if drafts.ancestors().iter().any(|&n| obsolete.contains(n)) {
+        status.unstable.push(String::from("orphan"));
+    }
+    Ok(())
+}
+
+/// Return the directory of the store in *hg_dir* (repositories created
+/// before Mercurial 0.9.2 do not have one)
+fn store_dir(hg_dir: &Path) -> PathBuf {
+    match hg_dir.join("store") {
+        store if store.is_dir() => store,
+        _ => hg_dir.to_path_buf(),
+    }
+}
+
+/// Return a filter matching directories against `.hgignore` and the
+/// `ui.ignore` files of *rootdir*
+#[cfg(unix)]
+fn ignored_dirs(rootdir: &Path) -> DirFilter {
+    use std::os::unix::ffi::OsStrExt;
+    match ignore::Matcher::load(rootdir) {
+        Ok(matcher) => Box::new(move |dir| matcher.is_ignored(dir.as_os_str().as_bytes())),
+        Err(e) => {
+            debug!("Failed to load the ignore files: {:#}", e);
+            Box::new(|_| false)
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn ignored_dirs(_rootdir: &Path) -> DirFilter {
+    Box::new(|_| false)
+}
+
+/// Return true if the environment variable *name* is set to `1`
+fn env_flag(name: &str) -> bool {
+    env::var(name).is_ok_and(|value| value == "1")
+}
+
+#[cfg(unix)]
+fn native_status(rootdir: &Path) -> Result<Status> {
+    native::status(rootdir)
+}
+
+#[cfg(not(unix))]
+fn native_status(_rootdir: &Path) -> Result<Status> {
+    Err(anyhow::format_err!("Native status is only supported on Unix"))
+}
+
+/// Get the status by running `hg status`
+fn command_status(rootdir: &Path) -> Result<Status> {
+    let status_str = get_status(rootdir)?;
     debug!("Status str: {:?}", status_str);
     let mut status = parse_status(&status_str);
-    status.branch = get_branch(&rootdir)? + &get_bookmark(&rootdir);
+    // Every change is staged, so there is nothing to diff otherwise
+    if status.staged > 0 {
+        command_diff_stat(rootdir, &mut status);
+    }
     Ok(status)
 }
 
 /// Run `hg status` and return its output.
-fn get_status() -> Result<String> {
+fn get_status(rootdir: &Path) -> Result<String> {
     let result = Command::new("hg")
-        .args(&["hg", "status", "--color=false", "--pager=false"])
+        .args(["status", "--copies", "--color=false", "--pager=false"])
+        .current_dir(rootdir)
         .output()
         .context("Failed to execute \"hg\"")?;
     let output = String::from_utf8_lossy(&result.stdout).into_owned();
 
     if !result.status.success() {
-        format_err!("hg status failed: {}", &output);
+        bail!(
+            "hg status failed: {}",
+            String::from_utf8_lossy(&result.stderr)
+        );
     }
     Ok(output)
 }
 
+/// Update the line counts of *status* from `hg diff --stat`
+fn command_diff_stat(rootdir: &Path, status: &mut Status) {
+    let args = ["diff", "--stat", "--color=false", "--pager=false"];
+    match exec_cmd("hg", &args, rootdir) {
+        Ok(output) => parse_diff_stat(&output.stdout, status),
+        Err(e) => debug!("Failed to get diff stat: {:#}", e),
+    }
+}
+
+/// Parse the summary line of `hg diff --stat` and update the line counts of
+/// *status* (changed files are counted by `parse_status()`)
+fn parse_diff_stat(stat: &str, status: &mut Status) {
+    let (_, insertions, deletions) = parse_diff_stat_summary(stat);
+    status.added = insertions;
+    status.deleted = deletions;
+}
+
+/// Run `hg log` with `TRACKING_TEMPLATE` for the working directory parent
+fn get_tracking(rootdir: &Path) -> Result<String> {
+    let args = [
+        "log",
+        "-r",
+        ".",
+        "-T",
+        TRACKING_TEMPLATE,
+        "--color=false",
+        "--pager=false",
+    ];
+    Ok(exec_cmd("hg", &args, rootdir)?.stdout)
+}
+
+/// Parse the output of `get_tracking()` and update *status* accordingly
+fn parse_tracking(output: &str, status: &mut Status) {
+    let mut parts = output.split_whitespace();
+    status.phase = parts.next().map(String::from);
+    status.unstable = parts.map(String::from).collect();
+}
+
 /// Parse the output string of `get_status()`.
+///
+/// Added files are followed by their copy source (indented), which makes
+/// them renames if the source was removed.  A removed file is the source of
+/// one rename at most, further copies of it count as added.
 fn parse_status(status: &str) -> Status {
-    let mut result = Status::new(VCS::Hg);
+    let mut result = Status::new(&Hg);
+    let mut removed = HashSet::new();
+    let mut copy_sources = vec![];
 
     for line in status.lines() {
         match line.split(" ").next() {
-            Some("M") | Some("A") | Some("R") | Some("!") => result.staged += 1,
+            Some("M") => result.staged += 1,
+            Some("A") => {
+                result.staged += 1;
+                result.files.added += 1;
+            }
+            Some("R") => {
+                result.staged += 1;
+                result.files.deleted += 1;
+                removed.insert(&line[2..]);
+            }
+            Some("!") => {
+                result.staged += 1;
+                result.files.missing += 1;
+            }
             Some("?") => result.untracked += 1,
+            Some("") => copy_sources.push(line.trim_start()),
             _ => (),
         }
     }
+    let renames = copy_sources
+        .iter()
+        .filter(|source| removed.remove(*source))
+        .count() as u32;
+    result.files.added -= renames;
+    result.files.deleted -= renames;
+    result.files.renamed = renames;
     result
 }
 
 /// Return the current branch
-fn get_branch(rootdir: &PathBuf) -> Result<String> {
-    let mut path = rootdir.clone();
+fn get_branch(rootdir: &Path) -> Result<String> {
+    let mut path = rootdir.to_path_buf();
     path.push(".hg/branch");
     debug!("Attempting to find branch at {:?}", path);
     match File::open(path) {
@@ -55,17 +319,123 @@ fn get_branch(rootdir: &PathBuf) -> Result<String> {
     }
 }
 
-/// Return the current bookmark or an empty string
-fn get_bookmark(rootdir: &PathBuf) -> String {
-    let mut path = rootdir.clone();
-    path.push(".hg/bookmarks.current");
-    match File::open(path) {
-        Ok(mut f) => {
-            let mut contents = String::new();
-            f.read_to_string(&mut contents).unwrap();
-            "*".to_string() + contents.trim()
+/// Return the trimmed contents of *path*, `None` if it is missing or empty
+/// (e.g., the active bookmark or topic)
+fn read_name(path: &Path) -> Option<String> {
+    let contents = fs::read_to_string(path).ok()?;
+    Some(contents.trim().to_string()).filter(|name| !name.is_empty())
+}
+
+/// Return the bookmarks pointing to *node* (hex), sorted by name.
+///
+/// They are stored in `.hg/bookmarks` (or `.hg/store/bookmarks` with the
+/// `bookmarksinstore` requirement) as `<node> <name>` lines.
+fn get_bookmarks(rootdir: &Path, node: &str) -> Vec<String> {
+    let hg_dir = rootdir.join(".hg");
+    let contents = match fs::read_to_string(hg_dir.join("bookmarks")) {
+        Ok(contents) => contents,
+        Err(_) => fs::read_to_string(hg_dir.join("store/bookmarks")).unwrap_or_default(),
+    };
+    let mut bookmarks: Vec<String> = contents
+        .lines()
+        .filter_map(|line| line.split_once(' '))
+        .filter(|(hex, _)| *hex == node)
+        .map(|(_, name)| name.to_string())
+        .collect();
+    bookmarks.sort();
+    bookmarks
+}
+
+/// Read the non-null working directory parents (as hex) from the header of
+/// `.hg/dirstate`; there are two while merging
+fn dirstate_parents(rootdir: &Path) -> Vec<String> {
+    let mut header = Vec::with_capacity(DIRSTATE_V2_MARKER.len() + 64);
+    let read = File::open(rootdir.join(".hg/dirstate"))
+        .and_then(|f| f.take(header.capacity() as u64).read_to_end(&mut header));
+    if read.is_err() {
+        return vec![];
+    }
+    let (p1, p2) = match header.strip_prefix(DIRSTATE_V2_MARKER) {
+        Some(docket) => (docket.get(..NODE_LEN), docket.get(32..32 + NODE_LEN)),
+        None => (header.get(..NODE_LEN), header.get(NODE_LEN..2 * NODE_LEN)),
+    };
+    [p1, p2]
+        .iter()
+        .flatten()
+        .filter(|node| node.iter().any(|&b| b != 0))
+        .map(|node| node.iter().map(|b| format!("{:02x}", b)).collect())
+        .collect()
+}
+
+/// Look for files in `.hg` that indicate an ongoing operation (e.g., a
+/// rebase) and update *list* accordingly
+fn get_operations(list: &mut Vec<&str>, rootdir: &Path) {
+    let hg_dir = rootdir.join(".hg");
+    for &(fname, op) in OPERATIONS.iter() {
+        if hg_dir.join(fname).exists() {
+            list.push(op);
         }
-        Err(_) => "".to_string(),
+    }
+    // The operations above merge as well, only report plain merges
+    if list.is_empty() && dirstate_parents(rootdir).len() == 2 {
+        list.push("MERGING");
+    }
+}
+
+/// Split a merge state file into `(type, data)` records.
+///
+/// `state2` consists of records with a type byte and a big-endian length,
+/// the older `state` of lines: the local node followed by file records.
+fn merge_records(data: &[u8], v2: bool) -> Vec<(u8, &[u8])> {
+    let mut records = vec![];
+    if !v2 {
+        for (i, line) in data.split(|&b| b == b'\n').enumerate() {
+            records.push((if i == 0 { b'L' } else { b'F' }, line));
+        }
+        return records;
+    }
+    let mut rest = data;
+    while rest.len() >= 5 {
+        let len = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+        match rest.get(5..5 + len) {
+            Some(record) => records.push((rest[0], record)),
+            None => break,
+        }
+        rest = &rest[5 + len..];
+    }
+    records
+}
+
+/// Count the unresolved files in the merge state
+fn count_conflicts(rootdir: &Path) -> u32 {
+    let merge_dir = rootdir.join(".hg/merge");
+    let (data, v2) = match fs::read(merge_dir.join("state2")) {
+        Ok(data) => (data, true),
+        Err(_) => match fs::read(merge_dir.join("state")) {
+            Ok(data) => (data, false),
+            Err(_) => return 0,
+        },
+    };
+    // File, change/delete and path conflict records start with the file
+    // name and its state (`u`/`pu` if unresolved)
+    merge_records(&data, v2)
+        .iter()
+        .filter(|(kind, _)| matches!(kind, b'F' | b'C' | b'P'))
+        .filter(|(_, record)| {
+            let state = record.split(|&b| b == 0).nth(1);
+            state == Some(b"u") || state == Some(b"pu")
+        })
+        .count() as u32
+}
+
+/// Count the shelved changes (each has a `.patch` file in `.hg/shelved`)
+fn count_shelves(rootdir: &Path) -> u32 {
+    match fs::read_dir(rootdir.join(".hg/shelved")) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().extension().is_some_and(|ext| ext == "patch"))
+            .count() as u32,
+        Err(_) => 0,
     }
 }
 
@@ -78,25 +448,213 @@ mod tests {
         let output = "
 M modified.txt
 A added.txt
+A copied.txt
+  modified.txt
+A moved.txt
+  old.txt
+R old.txt
 R removed.txt
+A copy.txt
+  source.txt
+A move.txt
+  source.txt
+R source.txt
 C clean.txt
 ? untracked.txt
 ! deleted.txt
 I ignored.txt
 ";
-        let mut expected = Status::new(VCS::Hg);
+        let mut expected = Status::new(&Hg);
         expected.branch = "<unknown>".to_string();
         expected.ahead = 0;
         expected.behind = 0;
-        expected.staged = 4;
+        expected.staged = 10;
         expected.changed = 0;
         expected.untracked = 1;
         expected.conflicts = 0;
+        expected.files.added = 3;
+        expected.files.deleted = 1;
+        expected.files.renamed = 2;
+        expected.files.missing = 1;
         assert_eq!(parse_status(output), expected);
     }
 
     #[test]
     fn parse_status_clean() {
-        assert_eq!(parse_status(""), Status::new(VCS::Hg));
+        assert_eq!(parse_status(""), Status::new(&Hg));
+    }
+
+    #[test]
+    fn diff_stat() {
+        let output =
+            " a.txt |  3 ++-\n b.txt |  1 -\n 2 files changed, 2 insertions(+), 2 deletions(-)\n";
+        let mut status = Status::new(&Hg);
+        parse_diff_stat(output, &mut status);
+        assert_eq!((status.added, status.deleted), (2, 2));
+        assert_eq!(status.fmt_diff().as_deref(), Some("+2/-2"));
+    }
+
+    #[test]
+    fn tracking() {
+        let mut status = Status::new(&Hg);
+        parse_tracking("draft  ", &mut status);
+        assert_eq!(status.phase.as_deref(), Some("draft"));
+        assert!(status.unstable.is_empty());
+
+        let mut status = Status::new(&Hg);
+        parse_tracking("", &mut status);
+        assert_eq!(status.phase, None);
+        assert!(status.unstable.is_empty());
+
+        let mut status = Status::new(&Hg);
+        parse_tracking("draft obsolete orphan content-divergent", &mut status);
+        assert_eq!(status.unstable, ["obsolete", "orphan", "content-divergent"]);
+    }
+
+    #[test]
+    fn drafts() {
+        let mut rootdir = std::env::temp_dir();
+        rootdir.push("test-vcprompt-hg-drafts");
+        let store = rootdir.join(".hg/store");
+        let _ = fs::remove_dir_all(&rootdir);
+        let changesets: [&[u8]; 4] = [
+            b"0\nuser\n0 0\n\nroot",
+            b"0\nuser\n0 0 branch:default\n\ndraft",
+            b"0\nuser\n0 0\n\ndraft",
+            b"0\nuser\n0 0 branch:feature\n\nfeature",
+        ];
+        let nodes = revlog::tests::write_revlog(&store.join("00changelog.i"), &changesets);
+        let hex =
+            |rev: usize| -> String { nodes[rev].iter().map(|b| format!("{:02x}", b)).collect() };
+        let drafts = |rev: usize| {
+            let mut status = Status::new(&Hg);
+            read_drafts(&rootdir, &hex(rev), &mut status).unwrap();
+            (status.phase.unwrap(), status.ahead, status.unstable)
+        };
+        let marker = |rev: usize| obsstore::tests::marker(&nodes[rev], &[], 0);
+        fs::write(store.join("obsstore"), [vec![1], marker(0)].concat()).unwrap();
+        assert_eq!(drafts(2), ("public".to_owned(), 0, vec![]));
+
+        fs::write(store.join("phaseroots"), format!("1 {}\n", hex(1))).unwrap();
+        assert_eq!(drafts(0), ("public".to_owned(), 0, vec![]));
+        assert_eq!(drafts(2), ("draft".to_owned(), 2, vec![]));
+        // Only the changeset itself is on its branch
+        assert_eq!(drafts(3).1, 1);
+        fs::write(store.join("obsstore"), [vec![1], marker(1)].concat()).unwrap();
+        assert_eq!(drafts(1).2, ["obsolete"]);
+        assert_eq!(drafts(2).2, ["orphan"]);
+        fs::remove_dir_all(&rootdir).unwrap();
+    }
+
+    #[test]
+    fn bookmarks_and_topic() {
+        let mut rootdir = std::env::temp_dir();
+        rootdir.push("test-vcprompt-hg-bookmarks");
+        let hg_dir = rootdir.join(".hg");
+        let _ = fs::remove_dir_all(&rootdir);
+        fs::create_dir_all(hg_dir.join("store")).unwrap();
+        let node = "ab".repeat(20);
+        assert!(get_bookmarks(&rootdir, &node).is_empty());
+        assert_eq!(read_name(&hg_dir.join("topic")), None);
+
+        let other = "cd".repeat(20);
+        let bookmarks = format!("{0} wip\n{1} other\n{0} feature\n", node, other);
+        fs::write(hg_dir.join("store/bookmarks"), bookmarks).unwrap();
+        fs::write(hg_dir.join("topic"), "login-form\n").unwrap();
+        fs::write(hg_dir.join("bookmarks.current"), "").unwrap();
+        assert_eq!(get_bookmarks(&rootdir, &node), ["feature", "wip"]);
+        let topic = read_name(&hg_dir.join("topic"));
+        assert_eq!(topic.as_deref(), Some("login-form"));
+        assert_eq!(read_name(&hg_dir.join("bookmarks.current")), None);
+        fs::remove_dir_all(&rootdir).unwrap();
+    }
+
+    #[test]
+    fn operations() {
+        let mut rootdir = std::env::temp_dir();
+        rootdir.push("test-vcprompt-hg-operations");
+        let hg_dir = rootdir.join(".hg");
+        let _ = fs::remove_dir_all(&rootdir);
+        fs::create_dir_all(&hg_dir).unwrap();
+        let mut operations = vec![];
+        get_operations(&mut operations, &rootdir);
+        assert!(operations.is_empty());
+
+        // dirstate-v1 header with both parents
+        let mut dirstate = vec![0xab; NODE_LEN];
+        dirstate.extend_from_slice(&[0xcd; NODE_LEN]);
+        dirstate.extend_from_slice(b"n\0\0\0\0");
+        fs::write(hg_dir.join("dirstate"), &dirstate).unwrap();
+        assert_eq!(
+            dirstate_parents(&rootdir),
+            vec!["ab".repeat(20), "cd".repeat(20)]
+        );
+        get_operations(&mut operations, &rootdir);
+        assert_eq!(operations, vec!["MERGING"]);
+
+        // dirstate-v2 docket with a null second parent
+        let mut dirstate = DIRSTATE_V2_MARKER.to_vec();
+        dirstate.extend_from_slice(&[0xab; NODE_LEN]);
+        dirstate.extend_from_slice(&[0; 64 - NODE_LEN]);
+        fs::write(hg_dir.join("dirstate"), &dirstate).unwrap();
+        assert_eq!(dirstate_parents(&rootdir), vec!["ab".repeat(20)]);
+
+        fs::create_dir_all(hg_dir.join("transplant")).unwrap();
+        File::create(hg_dir.join("transplant/journal")).unwrap();
+        File::create(hg_dir.join("rebasestate")).unwrap();
+        let mut operations = vec![];
+        get_operations(&mut operations, &rootdir);
+        assert_eq!(operations, vec!["REBASE", "TRANSPLANTING"]);
+        fs::remove_dir_all(&rootdir).unwrap();
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut rootdir = std::env::temp_dir();
+        rootdir.push("test-vcprompt-hg-conflicts");
+        let merge_dir = rootdir.join(".hg/merge");
+        let _ = fs::remove_dir_all(&rootdir);
+        fs::create_dir_all(&merge_dir).unwrap();
+        assert_eq!(count_conflicts(&rootdir), 0);
+
+        let mut state2 = vec![];
+        let records: [(u8, &[u8]); 5] = [
+            (b'L', b"abcd"),
+            (b'F', b"a.txt\0u\0hash\0a.txt"),
+            (b'F', b"b.txt\0r\0hash\0b.txt"),
+            (b'C', b"c.txt\0u\0hash\0c.txt"),
+            (b'P', b"d.txt\0pu\0d.txt~other\0l"),
+        ];
+        for (kind, data) in records.iter() {
+            state2.push(*kind);
+            state2.extend_from_slice(&(data.len() as u32).to_be_bytes());
+            state2.extend_from_slice(data);
+        }
+        fs::write(merge_dir.join("state2"), &state2).unwrap();
+        assert_eq!(count_conflicts(&rootdir), 3);
+
+        fs::remove_file(merge_dir.join("state2")).unwrap();
+        fs::write(
+            merge_dir.join("state"),
+            "abcd\na.txt\0u\0hash\nb.txt\0r\0hash\n",
+        )
+        .unwrap();
+        assert_eq!(count_conflicts(&rootdir), 1);
+        fs::remove_dir_all(&rootdir).unwrap();
+    }
+
+    #[test]
+    fn shelve_count() {
+        let mut rootdir = std::env::temp_dir();
+        rootdir.push("test-vcprompt-shelve");
+        let shelved = rootdir.join(".hg/shelved");
+        let _ = fs::remove_dir_all(&rootdir);
+        fs::create_dir_all(&shelved).unwrap();
+        let files = ["default.patch", "default.shelve", "old.patch", "old.hg"];
+        for name in files.iter() {
+            File::create(shelved.join(name)).unwrap();
+        }
+        assert_eq!(count_shelves(&rootdir), 2);
+        fs::remove_dir_all(&rootdir).unwrap();
     }
 }
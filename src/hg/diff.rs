@@ -0,0 +1,311 @@
+//! Count inserted and deleted lines by reading the files of the working
+//! directory parent from the store instead of running `hg diff`
+use super::{
+    revlog::{parse_node, Node, Revlog, INDEX_WINDOW},
+    NODE_LEN,
+};
+use crate::util::line_diff;
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs, io,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+/// Requirements changing the store in ways that are not supported
+const UNSUPPORTED: [&str; 8] = [
+    "treemanifest",
+    "revlogv2",
+    "changelogv2",
+    "shared",
+    "relshared",
+    "largefiles",
+    "lfs",
+    "narrowhg-experimental",
+];
+
+/// Longer paths are hashed in stores with fncache, which is not supported
+const MAX_STORE_PATH_LEN: usize = 120;
+
+/// Windows reserved file names, which are encoded in the store
+const WINDOWS_RESERVED_3: [&str; 4] = ["aux", "con", "prn", "nul"];
+const WINDOWS_RESERVED_4: [&str; 2] = ["com", "lpt"];
+
+/// Return the number of lines inserted and deleted in the working directory
+/// at *rootdir* compared to its first parent *parent* (hex, `None` before the
+/// first commit).
+///
+/// *changes* lists the changed files and whether they exist in the working
+/// directory; files missing from the parent are compared with an empty file
+/// like `hg diff` does without `--git`.
+pub fn diff_stat(
+    rootdir: &Path,
+    parent: Option<&str>,
+    changes: &[(&[u8], bool)],
+) -> Result<(u32, u32)> {
+    let store = Store::open(&rootdir.join(".hg"))?;
+    let old_nodes = match parent {
+        Some(parent) => {
+            let parent = parse_node(parent).context("Invalid parent")?;
+            let paths: HashSet<&[u8]> = changes.iter().map(|(path, _)| *path).collect();
+            store.file_nodes(&parent, &paths)?
+        }
+        None => HashMap::new(),
+    };
+    let (mut inserted, mut deleted) = (0, 0);
+    for &(path, exists) in changes {
+        let old = match old_nodes.get(path) {
+            Some(node) => store.file(path, node)?,
+            None => vec![],
+        };
+        let new = match exists {
+            true => read_working_file(&rootdir.join(OsStr::from_bytes(path)))?,
+            false => vec![],
+        };
+        let (i, d) = line_diff(&old, &new).unwrap_or((0, 0));
+        inserted += i;
+        deleted += d;
+    }
+    Ok((inserted, deleted))
+}
+
+/// Read a file, or the target of a symlink like Mercurial stores it
+fn read_working_file(path: &Path) -> Result<Vec<u8>> {
+    let meta = fs::symlink_metadata(path).with_context(|| format!("Failed to stat {:?}", path))?;
+    if meta.file_type().is_symlink() {
+        Ok(fs::read_link(path)?.as_os_str().as_bytes().to_vec())
+    } else {
+        fs::read(path).with_context(|| format!("Failed to read {:?}", path))
+    }
+}
+
+/// The store of a repository and how it encodes file names
+struct Store {
+    dir:       PathBuf,
+    fncache:   bool,
+    dotencode: bool,
+}
+
+impl Store {
+    /// Check the requirements of the repository in *hg_dir*
+    fn open(hg_dir: &Path) -> Result<Store> {
+        let mut requires = String::new();
+        // Repositories with share-safe keep the store requirements separately
+        for path in &[hg_dir.join("requires"), hg_dir.join("store/requires")] {
+            match fs::read_to_string(path) {
+                Ok(contents) => requires.push_str(&contents),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e).context("Failed to read requirements"),
+            }
+        }
+        let requires: HashSet<&str> = requires.lines().collect();
+        if !requires.contains("store") {
+            bail!("Repositories without a store are not supported");
+        }
+        if let Some(name) = UNSUPPORTED.iter().find(|name| requires.contains(*name)) {
+            bail!("Unsupported requirement {:?}", name);
+        }
+        Ok(Store {
+            dir:       hg_dir.join("store"),
+            fncache:   requires.contains("fncache"),
+            dotencode: requires.contains("dotencode"),
+        })
+    }
+
+    /// Return the file nodes of *paths* in the changeset *node*
+    fn file_nodes(&self, node: &Node, paths: &HashSet<&[u8]>) -> Result<HashMap<Vec<u8>, Node>> {
+        let mut changelog = Revlog::open(&self.dir.join("00changelog.i"), INDEX_WINDOW)?;
+        let rev = changelog.find(node)?;
+        let changeset = changelog.revision(rev)?;
+        // The changeset starts with the manifest node
+        let manifest_node = changeset
+            .split(|&b| b == b'\n')
+            .next()
+            .and_then(|line| parse_node(std::str::from_utf8(line).ok()?))
+            .context("Invalid changeset")?;
+        let mut nodes = HashMap::new();
+        if manifest_node == [0; NODE_LEN] {
+            return Ok(nodes);
+        }
+        let mut manifest = Revlog::open(&self.dir.join("00manifest.i"), INDEX_WINDOW)?;
+        let rev = manifest.find(&manifest_node)?;
+        // Lines of `<path>\0<hex node>[<flag>]`
+        for line in manifest.revision(rev)?.split(|&b| b == b'\n') {
+            let nul = match line.iter().position(|&b| b == 0) {
+                Some(nul) if paths.contains(&line[..nul]) => nul,
+                _ => continue,
+            };
+            let node = std::str::from_utf8(&line[nul + 1..])
+                .ok()
+                .and_then(|hex| parse_node(hex.get(..40)?))
+                .context("Invalid manifest entry")?;
+            nodes.insert(line[..nul].to_vec(), node);
+        }
+        Ok(nodes)
+    }
+
+    /// Return the contents of revision *node* of the file *path*
+    fn file(&self, path: &[u8], node: &Node) -> Result<Vec<u8>> {
+        let name = filelog_path(path, self.fncache, self.dotencode)
+            .context("Hashed store paths are not supported")?;
+        let mut filelog = Revlog::open(&self.dir.join(name), INDEX_WINDOW)?;
+        let rev = filelog.find(node)?;
+        let text = filelog.revision(rev)?;
+        // Copy information is stored as metadata between two `\1\n`
+        if let Some(meta) = text.strip_prefix(b"\x01\n") {
+            let end = meta
+                .windows(2)
+                .position(|w| w == b"\x01\n")
+                .context("Invalid file metadata")?;
+            return Ok(meta[end + 2..].to_vec());
+        }
+        Ok(text)
+    }
+}
+
+/// Return the store path of the filelog index of *path*, or `None` if it
+/// would be hashed
+fn filelog_path(path: &[u8], fncache: bool, dotencode: bool) -> Option<String> {
+    let path = [b"data/", path, b".i"].concat();
+    let mut encoded = String::with_capacity(path.len());
+    for &b in path.iter() {
+        match b {
+            b'A'..=b'Z' => {
+                encoded.push('_');
+                encoded.push(b.to_ascii_lowercase() as char);
+            }
+            b'_' => encoded.push_str("__"),
+            b'\\' | b':' | b'*' | b'?' | b'"' | b'<' | b'>' | b'|' => {
+                encoded.push_str(&format!("~{:02x}", b))
+            }
+            0..=31 | 126..=255 => encoded.push_str(&format!("~{:02x}", b)),
+            _ => encoded.push(b as char),
+        }
+    }
+    // Directories could clash with revlog files otherwise
+    let encoded = encoded
+        .replace(".hg/", ".hg.hg/")
+        .replace(".i/", ".i.hg/")
+        .replace(".d/", ".d.hg/");
+    if !fncache {
+        return Some(encoded);
+    }
+    if path.len() > MAX_STORE_PATH_LEN {
+        return None;
+    }
+    let encoded = encoded
+        .split('/')
+        .map(|segment| encode_segment(segment, dotencode))
+        .collect::<Vec<_>>()
+        .join("/");
+    Some(encoded).filter(|encoded| encoded.len() <= MAX_STORE_PATH_LEN)
+}
+
+/// Encode leading dots and spaces (with dotencode), Windows reserved names
+/// and trailing dots and spaces of a path segment
+fn encode_segment(segment: &str, dotencode: bool) -> String {
+    let mut segment = segment.to_string();
+    let bytes = segment.as_bytes();
+    if bytes.is_empty() {
+        return segment;
+    }
+    if dotencode && (bytes[0] == b'.' || bytes[0] == b' ') {
+        segment = format!("~{:02x}{}", bytes[0], &segment[1..]);
+    } else {
+        let stem = segment.find('.').unwrap_or(segment.len());
+        let reserved = match stem {
+            3 => WINDOWS_RESERVED_3.contains(&&segment[..3]),
+            4 => (b'1'..=b'9').contains(&bytes[3]) && WINDOWS_RESERVED_4.contains(&&segment[..3]),
+            _ => false,
+        };
+        if reserved {
+            segment = format!("{}~{:02x}{}", &segment[..2], bytes[2], &segment[3..]);
+        }
+    }
+    if segment.ends_with('.') || segment.ends_with(' ') {
+        let last = segment.pop().unwrap_or_default();
+        segment.push_str(&format!("~{:02x}", last as u8));
+    }
+    segment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hg::revlog::tests::write_revlog;
+
+    fn hex(node: &Node) -> String {
+        node.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    #[test]
+    fn store_paths() {
+        let encode = |path: &str| filelog_path(path.as_bytes(), true, true);
+        assert_eq!(encode("ABC").unwrap(), "data/_a_b_c.i");
+        assert_eq!(
+            encode("aux.bla/bla.aux/prn/PRN/lpt/com3/nul/coma/foo.NUL/normal.c").unwrap(),
+            "data/au~78.bla/bla.aux/pr~6e/_p_r_n/lpt/co~6d3/nu~6c/coma/foo._n_u_l/normal.c.i"
+        );
+        assert_eq!(
+            encode(".hgignore/a.i/x:y_z~ /b. ").unwrap(),
+            "data/~2ehgignore/a.i.hg/x~3ay__z~7e~20/b. .i"
+        );
+        assert_eq!(
+            filelog_path(b".x/\xc3\xa4.d/F", false, false).unwrap(),
+            "data/.x/~c3~a4.d.hg/_f.i"
+        );
+        assert_eq!(
+            filelog_path(b".x /y", true, false).unwrap(),
+            "data/.x~20/y.i"
+        );
+        assert!(encode(&"x".repeat(114)).is_none());
+        assert!(encode(&"X".repeat(60)).is_none());
+        assert!(encode(&"x".repeat(113)).is_some());
+    }
+
+    #[test]
+    fn count_lines() {
+        let mut rootdir = std::env::temp_dir();
+        rootdir.push("test-vcprompt-hg-diff");
+        let _ = fs::remove_dir_all(&rootdir);
+        let store = rootdir.join(".hg/store");
+        fs::create_dir_all(&store).unwrap();
+        fs::write(rootdir.join(".hg/requires"), "store\nfncache\ndotencode\n").unwrap();
+
+        let files = write_revlog(
+            &store.join("data/_r_e_a_d_m_e.i"),
+            &[b"a\nb\n", b"a\nb\nc\n"],
+        );
+        let meta = write_revlog(
+            &store.join("data/~2ecopy.i"),
+            &[b"\x01\ncopy: x\n\x01\nx\n"],
+        );
+        let manifest = format!("README\0{}\n.copy\0{}x\n", hex(&files[1]), hex(&meta[0]));
+        let manifests = write_revlog(&store.join("00manifest.i"), &[manifest.as_bytes()]);
+        let changeset = format!("{}\nuser\n0 0\nREADME\n\nMessage", hex(&manifests[0]));
+        let changesets = write_revlog(&store.join("00changelog.i"), &[changeset.as_bytes()]);
+        let parent = hex(&changesets[0]);
+
+        fs::write(rootdir.join("README"), "a\nc\nd\n").unwrap();
+        fs::write(rootdir.join("new"), "1\n2\n").unwrap();
+        let stat = |changes: &[(&[u8], bool)]| diff_stat(&rootdir, Some(&parent), changes);
+        assert_eq!(stat(&[(b"README", true)]).unwrap(), (1, 1));
+        assert_eq!(stat(&[(b".copy", false), (b"new", true)]).unwrap(), (2, 1));
+        assert_eq!(stat(&[(b"gone", false)]).unwrap(), (0, 0));
+        assert_eq!(
+            diff_stat(&rootdir, None, &[(b"README", true)]).unwrap(),
+            (3, 0)
+        );
+        assert!(stat(&[(b"missing", true)]).is_err());
+
+        fs::write(
+            rootdir.join(".hg/requires"),
+            "store\nfncache\ntreemanifest\n",
+        )
+        .unwrap();
+        assert!(stat(&[(b"README", true)]).is_err());
+        fs::remove_dir_all(&rootdir).unwrap();
+    }
+}
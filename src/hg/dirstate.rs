@@ -0,0 +1,288 @@
+//! Parse the Mercurial dirstate (`.hg/dirstate`, v1 and v2)
+use super::{DIRSTATE_V2_MARKER, NODE_LEN};
+use anyhow::{bail, Context, Result};
+use std::{fs, path::Path};
+
+/// Size of a v1 entry without its path (state, mode, size, mtime, length)
+const V1_ENTRY_LEN: usize = 17;
+/// v1 size of files that need to be compared by content
+const V1_SIZE_NONNORMAL: i32 = -1;
+/// v1 size of files taken from the second parent during a merge
+const V1_SIZE_FROM_P2: i32 = -2;
+
+/// Size of the v2 tree metadata in the docket
+const V2_TREE_METADATA_LEN: usize = 44;
+/// Size of a v2 node
+const V2_NODE_LEN: usize = 44;
+
+const V2_WDIR_TRACKED: u16 = 1 << 0;
+const V2_P1_TRACKED: u16 = 1 << 1;
+const V2_P2_INFO: u16 = 1 << 2;
+const V2_MODE_EXEC_PERM: u16 = 1 << 3;
+const V2_MODE_IS_SYMLINK: u16 = 1 << 4;
+const V2_EXPECTED_STATE_IS_MODIFIED: u16 = 1 << 9;
+const V2_HAS_MODE_AND_SIZE: u16 = 1 << 10;
+const V2_HAS_MTIME: u16 = 1 << 11;
+const V2_MTIME_SECOND_AMBIGUOUS: u16 = 1 << 12;
+
+/// State of a tracked file
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum State {
+    Normal,
+    Added,
+    Removed,
+    /// Merged from both parents
+    Merged,
+}
+
+/// A single file of the dirstate
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+    pub path:        Vec<u8>,
+    pub state:       State,
+    /// Known to differ from the first parent (e.g., taken from the second)
+    pub modified:    bool,
+    /// Executable bit and symlink flag, if known
+    pub mode:        Option<(bool, bool)>,
+    /// Size (truncated to 31 bits), if known
+    pub size:        Option<u32>,
+    /// Seconds (truncated to 31 bits) and nanoseconds (0 if unknown) of the
+    /// last modification, if known and unambiguous
+    pub mtime:       Option<(u32, u32)>,
+    pub copy_source: Option<Vec<u8>>,
+}
+
+/// Read the tracked files from the dirstate in *hg_dir*
+pub fn read(hg_dir: &Path) -> Result<Vec<Entry>> {
+    let data = match fs::read(hg_dir.join("dirstate")) {
+        Ok(data) => data,
+        // A fresh repository has no dirstate
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e).context("Failed to read dirstate"),
+    };
+    match data.strip_prefix(DIRSTATE_V2_MARKER) {
+        Some(docket) => read_v2(hg_dir, docket),
+        None => parse_v1(&data),
+    }
+}
+
+fn be_u16(data: &[u8], pos: usize) -> u16 {
+    u16::from_be_bytes([data[pos], data[pos + 1]])
+}
+
+fn be_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+/// Parse a v1 dirstate: both parents followed by the entries
+fn parse_v1(data: &[u8]) -> Result<Vec<Entry>> {
+    let mut entries = vec![];
+    let mut pos = 2 * NODE_LEN;
+    while pos < data.len() {
+        let header = data
+            .get(pos..pos + V1_ENTRY_LEN)
+            .context("Truncated dirstate entry")?;
+        let mode = be_u32(header, 1);
+        let size = be_u32(header, 5) as i32;
+        let mtime = be_u32(header, 9) as i32;
+        let len = be_u32(header, 13) as usize;
+        pos += V1_ENTRY_LEN;
+        let name = data
+            .get(pos..pos + len)
+            .context("Truncated dirstate entry")?;
+        pos += len;
+
+        // The copy source follows the path, separated by NUL
+        let mut parts = name.splitn(2, |&b| b == 0);
+        let path = parts.next().unwrap_or_default().to_vec();
+        let copy_source = parts.next().map(|source| source.to_vec());
+        let state = match header[0] {
+            b'n' => State::Normal,
+            b'a' => State::Added,
+            b'r' => State::Removed,
+            b'm' => State::Merged,
+            other => bail!("Unknown dirstate state {:?}", other as char),
+        };
+        let known = state == State::Normal && size >= 0;
+        entries.push(Entry {
+            path,
+            state,
+            modified:    state == State::Normal && size == V1_SIZE_FROM_P2,
+            mode:        Some((mode & 0o100 != 0, mode & 0o170000 == 0o120000)).filter(|_| known),
+            size:        Some(size as u32).filter(|_| known && size != V1_SIZE_NONNORMAL),
+            mtime:       Some((mtime as u32, 0)).filter(|_| known && mtime >= 0),
+            copy_source,
+        });
+    }
+    Ok(entries)
+}
+
+/// Read the v2 data file referenced by *docket* (without marker)
+fn read_v2(hg_dir: &Path, docket: &[u8]) -> Result<Vec<Entry>> {
+    // Both parents (padded to 32 bytes), the tree metadata, the used size
+    // of the data file and the length of its id
+    let pos = 64 + V2_TREE_METADATA_LEN;
+    let header = docket.get(..pos + 5).context("Truncated dirstate docket")?;
+    let metadata = &header[64..pos];
+    let data_size = be_u32(header, pos) as usize;
+    let id_len = header[pos + 4] as usize;
+    let id = docket
+        .get(pos + 5..pos + 5 + id_len)
+        .context("Truncated dirstate docket")?;
+    let name = format!("dirstate.{}", String::from_utf8_lossy(id));
+    let data = fs::read(hg_dir.join(name)).context("Failed to read dirstate data")?;
+    let data = data.get(..data_size).context("Truncated dirstate data")?;
+
+    let mut entries = vec![];
+    parse_v2_nodes(data, be_u32(metadata, 0), be_u32(metadata, 4), &mut entries)?;
+    Ok(entries)
+}
+
+/// Parse *count* nodes starting at *start* of the v2 data, including their
+/// children
+fn parse_v2_nodes(data: &[u8], start: u32, count: u32, entries: &mut Vec<Entry>) -> Result<()> {
+    let slice = |start: u32, len: u16| -> Result<Vec<u8>> {
+        let (start, len) = (start as usize, len as usize);
+        let path = data
+            .get(start..start + len)
+            .context("Invalid dirstate path")?;
+        Ok(path.to_vec())
+    };
+    for i in 0..count as usize {
+        let pos = start as usize + i * V2_NODE_LEN;
+        let node = data
+            .get(pos..pos + V2_NODE_LEN)
+            .context("Truncated dirstate node")?;
+        let flags = be_u16(node, 30);
+        let wc_tracked = flags & V2_WDIR_TRACKED != 0;
+        let p1_tracked = flags & V2_P1_TRACKED != 0;
+        let p2_info = flags & V2_P2_INFO != 0;
+        if wc_tracked || p1_tracked || p2_info {
+            let state = match (wc_tracked, p1_tracked, p2_info) {
+                (false, _, _) => State::Removed,
+                (true, false, false) => State::Added,
+                (true, true, true) => State::Merged,
+                _ => State::Normal,
+            };
+            let mode = (flags & V2_MODE_EXEC_PERM != 0, flags & V2_MODE_IS_SYMLINK != 0);
+            let has_mode = flags & V2_HAS_MODE_AND_SIZE != 0;
+            let has_mtime = flags & V2_HAS_MTIME != 0 && flags & V2_MTIME_SECOND_AMBIGUOUS == 0;
+            let copy_len = be_u16(node, 12);
+            entries.push(Entry {
+                path:        slice(be_u32(node, 0), be_u16(node, 4))?,
+                state,
+                modified:    state == State::Normal
+                    && (!p1_tracked || flags & V2_EXPECTED_STATE_IS_MODIFIED != 0),
+                mode:        Some(mode).filter(|_| has_mode),
+                size:        Some(be_u32(node, 32)).filter(|_| has_mode),
+                mtime:       Some((be_u32(node, 36), be_u32(node, 40))).filter(|_| has_mtime),
+                copy_source: match copy_len {
+                    0 => None,
+                    len => Some(slice(be_u32(node, 8), len)?),
+                },
+            });
+        }
+        parse_v2_nodes(data, be_u32(node, 14), be_u32(node, 18), entries)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a v1 entry
+    fn v1_entry(state: u8, mode: u32, size: i32, mtime: i32, name: &[u8]) -> Vec<u8> {
+        let mut entry = vec![state];
+        entry.extend_from_slice(&mode.to_be_bytes());
+        entry.extend_from_slice(&size.to_be_bytes());
+        entry.extend_from_slice(&mtime.to_be_bytes());
+        entry.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        entry.extend_from_slice(name);
+        entry
+    }
+
+    #[test]
+    fn v1() {
+        let mut data = vec![0xab; 2 * NODE_LEN];
+        data.extend(v1_entry(b'n', 0o100755, 12, 1000, b"bin/run"));
+        data.extend(v1_entry(b'n', 0o100644, -1, -1, b"dirty.txt"));
+        data.extend(v1_entry(b'a', 0, -1, -1, b"new.txt\0old.txt"));
+        data.extend(v1_entry(b'r', 0, 0, 0, b"old.txt"));
+        let entries = parse_v1(&data).unwrap();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(
+            entries[0],
+            Entry {
+                path:        b"bin/run".to_vec(),
+                state:       State::Normal,
+                modified:    false,
+                mode:        Some((true, false)),
+                size:        Some(12),
+                mtime:       Some((1000, 0)),
+                copy_source: None,
+            }
+        );
+        assert_eq!((entries[1].size, entries[1].mtime), (None, None));
+        assert_eq!(entries[2].state, State::Added);
+        assert_eq!(entries[2].copy_source.as_deref(), Some(&b"old.txt"[..]));
+        assert_eq!(entries[3].state, State::Removed);
+        assert!(parse_v1(&data[..data.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn v2() {
+        let mut hg_dir = std::env::temp_dir();
+        hg_dir.push("test-vcprompt-dirstate-v2");
+        let _ = fs::remove_dir_all(&hg_dir);
+        fs::create_dir_all(&hg_dir).unwrap();
+
+        // Paths, then the root node "src" with the child "src/a.rs"
+        let mut data = b"srcsrc/a.rsx.rs".to_vec();
+        let node = |path: (u32, u16), copy: (u32, u16), children: (u32, u32), flags: u16| {
+            let mut node = vec![];
+            node.extend_from_slice(&path.0.to_be_bytes());
+            node.extend_from_slice(&path.1.to_be_bytes());
+            node.extend_from_slice(&0u16.to_be_bytes());
+            node.extend_from_slice(&copy.0.to_be_bytes());
+            node.extend_from_slice(&copy.1.to_be_bytes());
+            node.extend_from_slice(&children.0.to_be_bytes());
+            node.extend_from_slice(&children.1.to_be_bytes());
+            node.extend_from_slice(&[0; 8]);
+            node.extend_from_slice(&flags.to_be_bytes());
+            node.extend_from_slice(&7u32.to_be_bytes());
+            node.extend_from_slice(&1000u32.to_be_bytes());
+            node.extend_from_slice(&5u32.to_be_bytes());
+            node
+        };
+        let root = data.len() as u32 + V2_NODE_LEN as u32;
+        let tracked = V2_WDIR_TRACKED | V2_P1_TRACKED | V2_HAS_MODE_AND_SIZE | V2_HAS_MTIME;
+        data.extend(node((3, 8), (11, 4), (0, 0), tracked | V2_MODE_EXEC_PERM));
+        data.extend(node((0, 3), (0, 0), (root - V2_NODE_LEN as u32, 1), 0));
+        fs::write(hg_dir.join("dirstate.abc"), &data).unwrap();
+
+        let mut docket = DIRSTATE_V2_MARKER.to_vec();
+        docket.extend_from_slice(&[0; 64]);
+        docket.extend_from_slice(&root.to_be_bytes());
+        docket.extend_from_slice(&1u32.to_be_bytes());
+        docket.extend_from_slice(&[0; V2_TREE_METADATA_LEN - 8]);
+        docket.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        docket.push(3);
+        docket.extend_from_slice(b"abc");
+        fs::write(hg_dir.join("dirstate"), &docket).unwrap();
+
+        assert_eq!(
+            read(&hg_dir).unwrap(),
+            vec![Entry {
+                path:        b"src/a.rs".to_vec(),
+                state:       State::Normal,
+                modified:    false,
+                mode:        Some((true, false)),
+                size:        Some(7),
+                mtime:       Some((1000, 5)),
+                copy_source: Some(b"x.rs".to_vec()),
+            }]
+        );
+        fs::remove_dir_all(&hg_dir).unwrap();
+    }
+}
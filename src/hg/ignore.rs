@@ -0,0 +1,311 @@
+//! Match paths against hgignore patterns
+use anyhow::{bail, Context, Result};
+use regex::bytes::Regex;
+use std::{env, fs, path::Path};
+
+/// Suffix of glob and path patterns, which also match everything inside a
+/// matching directory
+const GLOB_SUFFIX: &str = "(?:/|$)";
+
+/// Compiled patterns of `.hgignore` and the `ui.ignore` files
+#[derive(Debug)]
+pub struct Matcher {
+    regex: Option<Regex>,
+}
+
+impl Matcher {
+    /// Load the ignore files of the repository at *rootdir*
+    pub fn load(rootdir: &Path) -> Result<Matcher> {
+        let mut files = vec![rootdir.join(".hgignore")];
+        for hgrc in config_files(rootdir) {
+            if let Ok(content) = fs::read_to_string(&hgrc) {
+                files.extend(ui_ignore(&content).iter().map(|f| expand_home(f)));
+            }
+        }
+        let mut patterns = vec![];
+        for file in files {
+            match fs::read_to_string(&file) {
+                Ok(content) => patterns.extend(parse(&content)?),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+                Err(e) => return Err(e).with_context(|| format!("Failed to read {:?}", file)),
+            }
+        }
+        Matcher::new(&patterns)
+    }
+
+    /// Combine regular expressions (as produced by `parse()`)
+    fn new(patterns: &[String]) -> Result<Matcher> {
+        if patterns.is_empty() {
+            return Ok(Matcher { regex: None });
+        }
+        let combined = format!("^(?:{})", patterns.join("|"));
+        let regex = Regex::new(&combined).context("Unsupported hgignore pattern")?;
+        Ok(Matcher { regex: Some(regex) })
+    }
+
+    /// Check if the file or directory *path* (relative to the root) is ignored
+    pub fn is_ignored(&self, path: &[u8]) -> bool {
+        self.regex
+            .as_ref()
+            .is_some_and(|regex| regex.is_match(path))
+    }
+}
+
+/// Configuration files that may set `ui.ignore`, in the order Mercurial
+/// reads them (`HGRCPATH` replaces the user and system files)
+fn config_files(rootdir: &Path) -> Vec<std::path::PathBuf> {
+    let mut files = vec![];
+    match env::var_os("HGRCPATH") {
+        Some(paths) => {
+            for path in env::split_paths(&paths) {
+                match fs::read_dir(&path) {
+                    Ok(entries) => files.extend(
+                        entries
+                            .filter_map(|e| e.ok().map(|e| e.path()))
+                            .filter(|p| p.extension().is_some_and(|ext| ext == "rc")),
+                    ),
+                    Err(_) => files.push(path),
+                }
+            }
+        }
+        None => {
+            files.push("/etc/mercurial/hgrc".into());
+            if let Some(home) = env::var_os("HOME") {
+                let home = Path::new(&home);
+                files.push(home.join(".hgrc"));
+                let config_home = env::var_os("XDG_CONFIG_HOME")
+                    .map(Into::into)
+                    .unwrap_or_else(|| home.join(".config"));
+                files.push(config_home.join("hg/hgrc"));
+            }
+        }
+    }
+    files.push(rootdir.join(".hg/hgrc"));
+    files
+}
+
+/// Return the values of `ignore` and `ignore.*` in the `[ui]` section
+fn ui_ignore(hgrc: &str) -> Vec<String> {
+    let mut section = "";
+    let mut files = vec![];
+    for line in hgrc.lines() {
+        let line = line.trim();
+        if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            section = name.trim();
+        } else if let (true, Some((key, value))) = (section == "ui", line.split_once('=')) {
+            let key = key.trim();
+            if key == "ignore" || key.starts_with("ignore.") {
+                files.push(value.trim().to_string());
+            }
+        }
+    }
+    files
+}
+
+fn expand_home(path: &str) -> std::path::PathBuf {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => Path::new(&home).join(rest),
+        _ => path.into(),
+    }
+}
+
+/// Remove a comment (`#` not escaped with a backslash) from *line*
+fn strip_comment(line: &str) -> String {
+    let mut result = String::with_capacity(line.len());
+    let mut escaped = false;
+    for c in line.chars() {
+        match c {
+            '#' if !escaped => break,
+            '#' => {
+                result.pop();
+                escaped = false;
+            }
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+        result.push(c);
+    }
+    result
+}
+
+/// Parse an ignore file into regular expressions.
+///
+/// The default syntax is `regexp` and can be changed with `syntax: glob`
+/// (or `rootglob`); single lines may use a `re:`, `glob:` or `rootglob:`
+/// prefix.  Includes are not supported.
+fn parse(content: &str) -> Result<Vec<String>> {
+    const SYNTAXES: [(&str, &str); 6] = [
+        ("re", "relre"),
+        ("regexp", "relre"),
+        ("glob", "relglob"),
+        ("rootglob", "rootglob"),
+        ("include", "include"),
+        ("subinclude", "subinclude"),
+    ];
+    let mut syntax = "relre";
+    let mut patterns = vec![];
+    for line in content.lines() {
+        let line = strip_comment(line);
+        let line = line.trim_end();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix("syntax:") {
+            let name = name.trim();
+            match SYNTAXES.iter().find(|(s, _)| *s == name) {
+                Some(&(_, kind)) => syntax = kind,
+                None => bail!("Unknown hgignore syntax {:?}", name),
+            }
+            continue;
+        }
+        let mut kind = syntax;
+        let mut pattern = line;
+        for &(name, rel) in SYNTAXES.iter() {
+            let prefix = [rel, ":"].concat();
+            let short = [name, ":"].concat();
+            if let Some(rest) = line
+                .strip_prefix(&prefix)
+                .or_else(|| line.strip_prefix(&short))
+            {
+                kind = rel;
+                pattern = rest;
+                break;
+            }
+        }
+        patterns.push(match kind {
+            "relre" if pattern.starts_with('^') => pattern.to_string(),
+            "relre" => format!(".*{}", pattern),
+            "relglob" => match glob_to_regex(pattern).strip_prefix("[^/]*") {
+                Some(rest) => format!(".*{}{}", rest, GLOB_SUFFIX),
+                None => format!("(?:.*/)?{}{}", glob_to_regex(pattern), GLOB_SUFFIX),
+            },
+            "rootglob" => format!("{}{}", glob_to_regex(pattern), GLOB_SUFFIX),
+            _ => bail!("Unsupported hgignore pattern {:?}", line),
+        });
+    }
+    Ok(patterns)
+}
+
+/// Convert a Mercurial glob into a regular expression
+fn glob_to_regex(glob: &str) -> String {
+    let chars: Vec<char> = glob.chars().collect();
+    let mut regex = String::new();
+    let mut groups = 0;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        match c {
+            '*' if chars.get(i) == Some(&'*') => {
+                i += 1;
+                if chars.get(i) == Some(&'/') {
+                    i += 1;
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push('.'),
+            '[' => {
+                let mut j = i;
+                if matches!(chars.get(j), Some('!') | Some(']')) {
+                    j += 1;
+                }
+                while j < chars.len() && chars[j] != ']' {
+                    j += 1;
+                }
+                if j >= chars.len() {
+                    regex.push_str("\\[");
+                    continue;
+                }
+                let class: String = chars[i..j].iter().collect();
+                let class = class.replace('\\', "\\\\");
+                i = j + 1;
+                regex.push('[');
+                match class.strip_prefix('!') {
+                    Some(rest) => regex.push_str(&format!("^{}", rest)),
+                    None if class.starts_with('^') => regex.push_str(&format!("\\{}", class)),
+                    None => regex.push_str(&class),
+                }
+                regex.push(']');
+            }
+            '{' => {
+                groups += 1;
+                regex.push_str("(?:");
+            }
+            '}' if groups > 0 => {
+                groups -= 1;
+                regex.push(')');
+            }
+            ',' if groups > 0 => regex.push('|'),
+            '\\' if i < chars.len() => {
+                regex.push_str(&regex::escape(&chars[i].to_string()));
+                i += 1;
+            }
+            _ => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matcher(content: &str) -> Matcher {
+        Matcher::new(&parse(content).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn regexp() {
+        let m = matcher("\\.pyc$\n^build/\n# comment\n");
+        assert!(m.is_ignored(b"a.pyc"));
+        assert!(m.is_ignored(b"src/a.pyc"));
+        assert!(!m.is_ignored(b"a.pyc.txt"));
+        assert!(m.is_ignored(b"build/out"));
+        assert!(!m.is_ignored(b"src/build/out"));
+    }
+
+    #[test]
+    fn glob() {
+        let m = matcher("syntax: glob\n*.o\ntarget\ndocs/**/*.html\nrootglob:tmp\n*.{a,so}\n");
+        assert!(m.is_ignored(b"main.o"));
+        assert!(m.is_ignored(b"src/main.o"));
+        assert!(m.is_ignored(b"target"));
+        assert!(m.is_ignored(b"sub/target/debug"));
+        assert!(!m.is_ignored(b"targets"));
+        assert!(m.is_ignored(b"docs/index.html"));
+        assert!(m.is_ignored(b"docs/api/x.html"));
+        assert!(m.is_ignored(b"tmp/x"));
+        assert!(!m.is_ignored(b"src/tmp"));
+        assert!(m.is_ignored(b"lib.so"));
+        assert!(!m.is_ignored(b"lib.sox"));
+        assert!(!m.is_ignored(b"src/main.rs"));
+    }
+
+    #[test]
+    fn mixed_syntax() {
+        let m = matcher("syntax: glob\nre:^out\\d\n[!a]b.txt\nx\\#y # comment\n");
+        assert!(m.is_ignored(b"out1/file"));
+        assert!(!m.is_ignored(b"outx"));
+        assert!(m.is_ignored(b"bb.txt"));
+        assert!(!m.is_ignored(b"ab.txt"));
+        assert!(m.is_ignored(b"x#y"));
+    }
+
+    #[test]
+    fn unsupported() {
+        assert!(parse("include:other").is_err());
+        assert!(parse("syntax: foo").is_err());
+        assert!(Matcher::new(&parse("(unclosed").unwrap()).is_err());
+        assert!(!matcher("").is_ignored(b"a"));
+    }
+
+    #[test]
+    fn hgrc_ignore_files() {
+        let hgrc = "[ui]\nusername = a\nignore = ~/.hgignore\nignore.x = /etc/hgignore\n\
+                    [extensions]\nignore = x\n";
+        assert_eq!(ui_ignore(hgrc), ["~/.hgignore", "/etc/hgignore"]);
+    }
+}
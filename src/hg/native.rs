@@ -0,0 +1,277 @@
+//! Compute Mercurial status by reading the dirstate directly instead of
+//! spawning `hg`
+use super::{
+    diff,
+    dirstate::{self, Entry, State},
+    ignore::Matcher,
+    Hg,
+};
+use crate::{status::Status, util::logger::*};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    fs::{self, Metadata},
+    io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::Path,
+};
+
+/// Sizes and mtimes are stored with 31 bits in the dirstate
+const RANGE_MASK: u32 = 0x7fff_ffff;
+
+/// Status of a tracked file as reported by `hg status`
+#[derive(Debug, Eq, PartialEq)]
+enum FileStatus {
+    Clean,
+    Modified,
+    Added,
+    Removed,
+    Missing,
+}
+
+/// Get the status of the working directory at *rootdir*.
+///
+/// Fails if a file has to be compared by content (its size and mtime match,
+/// but the mtime is not known to be unambiguous) so that `hg status` can
+/// decide.
+pub fn status(rootdir: &Path) -> Result<Status> {
+    let entries = dirstate::read(&rootdir.join(".hg"))?;
+    debug!("Read {} dirstate entries", entries.len());
+    let mut result = Status::new(&Hg);
+    // Each removed file is the source of one rename at most
+    let mut removed: HashSet<&[u8]> = entries
+        .iter()
+        .filter(|e| e.state == State::Removed)
+        .map(|e| &e.path[..])
+        .collect();
+    // Changed files and whether they exist in the working directory
+    let mut changes: Vec<(&[u8], bool)> = vec![];
+    for entry in entries.iter() {
+        let status = file_status(rootdir, entry)?;
+        if status != FileStatus::Clean {
+            let exists = status != FileStatus::Removed && status != FileStatus::Missing;
+            changes.push((&entry.path, exists));
+        }
+        match status {
+            FileStatus::Clean => (),
+            FileStatus::Modified => result.staged += 1,
+            FileStatus::Added => {
+                result.staged += 1;
+                match &entry.copy_source {
+                    Some(source) if removed.remove(&source[..]) => result.files.renamed += 1,
+                    _ => result.files.added += 1,
+                }
+            }
+            FileStatus::Removed => {
+                result.staged += 1;
+                result.files.deleted += 1;
+            }
+            FileStatus::Missing => {
+                result.staged += 1;
+                result.files.missing += 1;
+            }
+        }
+    }
+    result.files.deleted -= result.files.renamed;
+
+    let tracked: HashSet<&[u8]> = entries.iter().map(|e| &e.path[..]).collect();
+    let walker = Walker {
+        rootdir,
+        tracked,
+        ignore: Matcher::load(rootdir)?,
+    };
+    result.untracked = walker.walk(b"")?;
+
+    if !changes.is_empty() {
+        let parent = super::dirstate_parents(rootdir).into_iter().next();
+        match diff::diff_stat(rootdir, parent.as_deref(), &changes) {
+            Ok((added, deleted)) => {
+                result.added = added;
+                result.deleted = deleted;
+            }
+            Err(e) => {
+                debug!("Failed to count changed lines, running `hg diff`: {:#}", e);
+                super::command_diff_stat(rootdir, &mut result);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Compare a dirstate entry with the file on disk
+fn file_status(rootdir: &Path, entry: &Entry) -> Result<FileStatus> {
+    let path = rootdir.join(OsStr::from_bytes(&entry.path));
+    let meta = match fs::symlink_metadata(&path) {
+        Ok(meta) if meta.is_file() || meta.file_type().is_symlink() => Some(meta),
+        // Replaced by a directory
+        Ok(_) => None,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        // A parent was replaced by a file (ENOTDIR)
+        Err(e) if e.raw_os_error() == Some(libc::ENOTDIR) => None,
+        Err(e) => return Err(e).with_context(|| format!("Failed to stat {:?}", path)),
+    };
+    let meta = match (entry.state, meta) {
+        (State::Removed, _) => return Ok(FileStatus::Removed),
+        (_, None) => return Ok(FileStatus::Missing),
+        (State::Added, _) => return Ok(FileStatus::Added),
+        (State::Merged, _) => return Ok(FileStatus::Modified),
+        (State::Normal, Some(meta)) => meta,
+    };
+    if entry.modified || entry.copy_source.is_some() || differs(entry, &meta) {
+        return Ok(FileStatus::Modified);
+    }
+    match entry.mtime {
+        Some(mtime) if mtime_matches(mtime, &meta) => Ok(FileStatus::Clean),
+        _ => bail!(
+            "{:?} needs to be compared by content",
+            String::from_utf8_lossy(&entry.path)
+        ),
+    }
+}
+
+/// Check if the cached size or mode of *entry* prove that it was modified
+fn differs(entry: &Entry, meta: &Metadata) -> bool {
+    let is_symlink = meta.file_type().is_symlink();
+    if let Some((exec, symlink)) = entry.mode {
+        if symlink != is_symlink || (!is_symlink && exec != (meta.mode() & 0o100 != 0)) {
+            return true;
+        }
+    }
+    match entry.size {
+        // Link targets may be stored with a different size (e.g., on
+        // encrypted file systems), so only regular files are compared
+        Some(size) => !is_symlink && size != meta.size() as u32 & RANGE_MASK,
+        None => false,
+    }
+}
+
+/// Compare a cached mtime with the file's; nanoseconds are only compared if
+/// both are known
+fn mtime_matches((secs, nsecs): (u32, u32), meta: &Metadata) -> bool {
+    let file_nsecs = meta.mtime_nsec() as u32;
+    secs == meta.mtime() as u32 & RANGE_MASK
+        && (nsecs == 0 || file_nsecs == 0 || nsecs == file_nsecs)
+}
+
+/// Traverses the working directory looking for unknown files
+struct Walker<'a> {
+    rootdir: &'a Path,
+    tracked: HashSet<&'a [u8]>,
+    ignore:  Matcher,
+}
+
+impl Walker<'_> {
+    /// Count the files below *dir* that are neither tracked nor ignored;
+    /// nested repositories are skipped
+    fn walk(&self, dir: &[u8]) -> Result<u32> {
+        let mut count = 0;
+        for entry in fs::read_dir(self.rootdir.join(OsStr::from_bytes(dir)))? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if dir.is_empty() && name == ".hg" {
+                continue;
+            }
+            let mut path = dir.to_vec();
+            if !path.is_empty() {
+                path.push(b'/');
+            }
+            path.extend_from_slice(name.as_bytes());
+            if self.tracked.contains(&path[..]) || self.ignore.is_ignored(&path) {
+                continue;
+            }
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                if !entry.path().join(".hg").exists() {
+                    count += self.walk(&path)?;
+                }
+            } else if file_type.is_file() || file_type.is_symlink() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encode a v1 dirstate entry for *path* with the file's current stat
+    /// data
+    fn entry(rootdir: &Path, state: u8, path: &str) -> Vec<u8> {
+        let (mode, size, mtime) = match fs::symlink_metadata(rootdir.join(path)) {
+            Ok(meta) => (meta.mode(), meta.size() as i32, meta.mtime() as i32),
+            Err(_) => (0o100644, 0, 0),
+        };
+        let mut data = vec![state];
+        data.extend_from_slice(&mode.to_be_bytes());
+        data.extend_from_slice(&size.to_be_bytes());
+        data.extend_from_slice(&mtime.to_be_bytes());
+        data.extend_from_slice(&(path.len() as u32).to_be_bytes());
+        data.extend_from_slice(path.as_bytes());
+        data
+    }
+
+    #[test]
+    fn matches_hg_status() {
+        let mut rootdir = std::env::temp_dir();
+        rootdir.push("test-vcprompt-hg-native");
+        let _ = fs::remove_dir_all(&rootdir);
+        fs::create_dir_all(rootdir.join(".hg")).unwrap();
+        fs::create_dir_all(rootdir.join("src/nested/.hg")).unwrap();
+        fs::create_dir_all(rootdir.join("build")).unwrap();
+        for file in &[
+            "clean.txt",
+            "modified.txt",
+            "new.txt",
+            "moved.txt",
+            "copy.txt",
+            "move.txt",
+            "src/a.rs",
+        ] {
+            fs::write(rootdir.join(file), "content\n").unwrap();
+        }
+        for file in &["src/b.rs", "unknown.txt", "build/out.o", "src/nested/x"] {
+            fs::write(rootdir.join(file), "").unwrap();
+        }
+        fs::write(rootdir.join(".hgignore"), "syntax: glob\nbuild\n").unwrap();
+
+        let mut data = vec![0; 40];
+        data.extend(entry(&rootdir, b'n', "clean.txt"));
+        data.extend(entry(&rootdir, b'n', "src/a.rs"));
+        data.extend(entry(&rootdir, b'n', "deleted.txt"));
+        data.extend(entry(&rootdir, b'a', "new.txt"));
+        data.extend(entry(&rootdir, b'a', "moved.txt\0old.txt"));
+        data.extend(entry(&rootdir, b'r', "old.txt"));
+        data.extend(entry(&rootdir, b'r', "removed.txt"));
+        // hg cp source.txt copy.txt; hg mv source.txt move.txt
+        data.extend(entry(&rootdir, b'a', "copy.txt\0source.txt"));
+        data.extend(entry(&rootdir, b'a', "move.txt\0source.txt"));
+        data.extend(entry(&rootdir, b'r', "source.txt"));
+        let mut modified = entry(&rootdir, b'n', "modified.txt");
+        modified[5..9].copy_from_slice(&3i32.to_be_bytes());
+        data.extend(modified);
+        fs::write(rootdir.join(".hg/dirstate"), &data).unwrap();
+
+        let result = status(&rootdir).unwrap();
+        assert_eq!(result.staged, 9);
+        assert_eq!(result.changed, 0);
+        // .hgignore, unknown.txt and src/b.rs
+        assert_eq!(result.untracked, 3);
+        assert_eq!(result.files.added, 2);
+        assert_eq!(result.files.deleted, 1);
+        assert_eq!(result.files.renamed, 2);
+        assert_eq!(result.files.missing, 1);
+
+        // Unknown mtime of a file that seems unchanged
+        let mut data = vec![0; 40];
+        let mut lookup = entry(&rootdir, b'n', "clean.txt");
+        lookup[9..13].copy_from_slice(&(-1i32).to_be_bytes());
+        data.extend(lookup);
+        fs::write(rootdir.join(".hg/dirstate"), &data).unwrap();
+        assert!(status(&rootdir).is_err());
+
+        fs::remove_dir_all(&rootdir).unwrap();
+    }
+}
@@ -0,0 +1,134 @@
+//! Read the obsolescence markers of changeset evolution (`obsstore`)
+use super::{
+    revlog::{be_u32, Node},
+    NODE_LEN,
+};
+use anyhow::{bail, Context, Result};
+use std::{collections::HashSet, fs, io, path::Path};
+
+/// Size of a version 0 marker without successors and metadata (number of
+/// successors, metadata size, flags and the precursor)
+const V0_FIXED_LEN: usize = 26;
+/// Size of a version 1 marker without nodes and metadata (marker size,
+/// date, time zone, flags and the numbers of successors, parents and
+/// metadata entries)
+const V1_FIXED_LEN: usize = 19;
+/// The nodes of a version 1 marker are SHA-256 hashes
+const V1_FLAG_SHA256: u16 = 1 << 1;
+
+/// Return the changesets that were rewritten or pruned (the precursors of
+/// all markers) according to the obsstore in *store*
+pub fn obsolete(store: &Path) -> Result<HashSet<Node>> {
+    let data = match fs::read(store.join("obsstore")) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e).context("Failed to read obsstore"),
+    };
+    let mut precursors = HashSet::new();
+    let (version, markers) = match data.split_first() {
+        Some(split) => split,
+        None => return Ok(precursors),
+    };
+    let mut pos = 0;
+    while pos < markers.len() {
+        match version {
+            0 => {
+                let fixed = markers
+                    .get(pos..pos + V0_FIXED_LEN)
+                    .context("Truncated obsolescence marker")?;
+                precursors.insert(to_node(&fixed[6..]));
+                let successors = fixed[0] as usize;
+                pos += V0_FIXED_LEN + successors * NODE_LEN + be_u32(fixed, 1) as usize;
+            }
+            1 => {
+                let fixed = markers
+                    .get(pos..pos + V1_FIXED_LEN)
+                    .context("Truncated obsolescence marker")?;
+                let size = be_u32(fixed, 0) as usize;
+                if size < V1_FIXED_LEN + NODE_LEN || pos + size > markers.len() {
+                    bail!("Invalid obsolescence marker size {}", size);
+                }
+                let flags = u16::from_be_bytes([fixed[14], fixed[15]]);
+                // Nodes of other hash functions cannot be in this repository
+                if flags & V1_FLAG_SHA256 == 0 {
+                    let start = pos + V1_FIXED_LEN;
+                    let node = markers
+                        .get(start..start + NODE_LEN)
+                        .context("Truncated obsolescence marker")?;
+                    precursors.insert(to_node(node));
+                }
+                pos += size;
+            }
+            _ => bail!("Unsupported obsstore version {}", version),
+        }
+    }
+    Ok(precursors)
+}
+
+fn to_node(data: &[u8]) -> Node {
+    let mut node = [0; NODE_LEN];
+    node.copy_from_slice(&data[..NODE_LEN]);
+    node
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    /// Encode a version 1 marker of *precursor* with *successors*, a parent
+    /// and a metadata entry
+    pub fn marker(precursor: &[u8], successors: &[Node], flags: u16) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&0f64.to_bits().to_be_bytes());
+        data.extend_from_slice(&0i16.to_be_bytes());
+        data.extend_from_slice(&flags.to_be_bytes());
+        data.extend_from_slice(&[successors.len() as u8, 1, 1]);
+        data.extend_from_slice(precursor);
+        for successor in successors {
+            data.extend_from_slice(successor);
+        }
+        data.extend_from_slice(&[9; NODE_LEN]);
+        data.extend_from_slice(&[4, 5]);
+        data.extend_from_slice(b"user alice");
+        let size = (data.len() + 4) as u32;
+        [&size.to_be_bytes()[..], &data].concat()
+    }
+
+    #[test]
+    fn read_markers() {
+        let mut store = std::env::temp_dir();
+        store.push("test-vcprompt-hg-obsstore");
+        let _ = fs::remove_dir_all(&store);
+        fs::create_dir_all(&store).unwrap();
+        assert!(obsolete(&store).unwrap().is_empty());
+
+        let mut data = vec![1];
+        // Rewritten, pruned and a SHA-256 marker
+        data.extend(marker(&[1; NODE_LEN], &[[2; NODE_LEN]], 0));
+        data.extend(marker(&[3; NODE_LEN], &[], 0));
+        data.extend(marker(&[4; 32], &[], V1_FLAG_SHA256));
+        fs::write(store.join("obsstore"), &data).unwrap();
+        let expected: HashSet<Node> = [[1; NODE_LEN], [3; NODE_LEN]].iter().copied().collect();
+        assert_eq!(obsolete(&store).unwrap(), expected);
+
+        fs::write(store.join("obsstore"), &data[..data.len() - 1]).unwrap();
+        assert!(obsolete(&store).is_err());
+
+        // Version 0 with a successor and metadata
+        let mut data = vec![0, 1];
+        data.extend_from_slice(&4u32.to_be_bytes());
+        data.push(0);
+        data.extend_from_slice(&[5; NODE_LEN]);
+        data.extend_from_slice(&[6; NODE_LEN]);
+        data.extend_from_slice(b"meta");
+        fs::write(store.join("obsstore"), &data).unwrap();
+        let expected: HashSet<Node> = [[5; NODE_LEN]].iter().copied().collect();
+        assert_eq!(obsolete(&store).unwrap(), expected);
+
+        fs::write(store.join("obsstore"), [2]).unwrap();
+        assert!(obsolete(&store).unwrap().is_empty());
+        fs::write(store.join("obsstore"), [2, 0]).unwrap();
+        assert!(obsolete(&store).is_err());
+        fs::remove_dir_all(&store).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+//! Determine the phases of a changeset and its ancestors from `phaseroots`
+//! and the changelog index instead of running `hg`
+use super::revlog::{parse_node, Node, Revlog, INDEX_WINDOW};
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::Path,
+};
+
+/// Phase numbers as stored in `phaseroots` and their names
+const PHASES: [(u32, &str); 5] = [
+    (0, "public"),
+    (1, "draft"),
+    (2, "secret"),
+    (32, "archived"),
+    (96, "internal"),
+];
+
+/// The phases of a changeset and its ancestors
+pub struct Drafts {
+    rev:       usize,
+    /// Phase numbers of the changeset and its ancestors that are not public
+    phases:    HashMap<usize, u32>,
+    /// The changelog, if there are phase roots at all
+    changelog: Option<Revlog>,
+}
+
+impl Drafts {
+    /// Return the name of the phase of the changeset
+    pub fn phase(&self) -> Result<&'static str> {
+        let number = self.phases.get(&self.rev).copied().unwrap_or(0);
+        match PHASES.iter().find(|(n, _)| *n == number) {
+            Some((_, name)) => Ok(name),
+            None => bail!("Unknown phase {}", number),
+        }
+    }
+
+    /// Return the number of non-public changesets among the changeset and its
+    /// ancestors on the same branch (outgoing changesets)
+    pub fn outgoing(&mut self) -> Result<u32> {
+        let changelog = match &mut self.changelog {
+            Some(changelog) if self.phases.contains_key(&self.rev) => changelog,
+            _ => return Ok(0),
+        };
+        let branch = changeset_branch(&changelog.revision(self.rev)?).to_vec();
+        let mut count = 0;
+        for &rev in self.phases.keys() {
+            if changeset_branch(&changelog.revision(rev)?) == &branch[..] {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Return the nodes of the ancestors (excluding the changeset itself)
+    /// that are not public
+    pub fn ancestors(&self) -> Vec<&[u8]> {
+        match &self.changelog {
+            Some(changelog) => self
+                .phases
+                .keys()
+                .filter(|&&rev| rev != self.rev)
+                .map(|&rev| changelog.node(rev))
+                .collect(),
+            None => vec![],
+        }
+    }
+}
+
+/// Read the phases of the changeset *node* (hex) and its ancestors in the
+/// repository whose `.hg` directory is *hg_dir*.
+///
+/// A changeset has the highest phase of the phase roots among its
+/// ancestors (including itself); without roots, everything is public.
+pub fn drafts(hg_dir: &Path, node: &str) -> Result<Drafts> {
+    let store = super::store_dir(hg_dir);
+    let roots = match fs::read_to_string(store.join("phaseroots")) {
+        Ok(contents) => parse_roots(&contents)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => vec![],
+        Err(e) => return Err(e).context("Failed to read phaseroots"),
+    };
+    if roots.is_empty() {
+        return Ok(Drafts {
+            rev:       0,
+            phases:    HashMap::new(),
+            changelog: None,
+        });
+    }
+    let node = parse_node(node).context("Invalid node")?;
+    let changelog = load(&store.join("00changelog.i"), &roots, node, INDEX_WINDOW)?;
+    let (rev, phases) = ancestor_phases(&changelog, &roots, &node)?;
+    Ok(Drafts {
+        rev,
+        phases,
+        changelog: Some(changelog),
+    })
+}
+
+/// Return the (escaped) branch of a changeset from the extra fields that
+/// follow its date
+fn changeset_branch(text: &[u8]) -> &[u8] {
+    let date = text.split(|&b| b == b'\n').nth(2).unwrap_or_default();
+    let extra = date.splitn(3, |&b| b == b' ').nth(2).unwrap_or_default();
+    extra
+        .split(|&b| b == 0)
+        .find_map(|field| field.strip_prefix(b"branch:"))
+        .unwrap_or(b"default")
+}
+
+/// Parse the `<phase> <node>` lines of `phaseroots`
+fn parse_roots(contents: &str) -> Result<Vec<(u32, Node)>> {
+    let mut roots = vec![];
+    for line in contents.lines().filter(|l| !l.is_empty()) {
+        let (number, node) = line.split_once(' ').context("Invalid phase root")?;
+        let number = number.parse().context("Invalid phase")?;
+        let node = parse_node(node).context("Invalid phase root")?;
+        // Public roots do not change anything
+        if number > 0 {
+            roots.push((number, node));
+        }
+    }
+    Ok(roots)
+}
+
+/// Read the changelog at *path*, starting with the last *window* entries
+/// and reading further back until *node* and all *roots* were found
+fn load(path: &Path, roots: &[(u32, Node)], node: Node, window: usize) -> Result<Revlog> {
+    let mut changelog = Revlog::open(path, window)?;
+    let mut needed: HashSet<Node> = roots.iter().map(|(_, node)| *node).collect();
+    needed.insert(node);
+    let mut end = changelog.count();
+    loop {
+        for rev in changelog.first()..end {
+            needed.remove(changelog.node(rev));
+        }
+        end = changelog.first();
+        if needed.is_empty() || !changelog.extend()? {
+            return Ok(changelog);
+        }
+    }
+}
+
+/// Return the revision of *node* and the phase numbers of it and its
+/// ancestors that are not public, given the phase *roots*
+fn ancestor_phases(
+    changelog: &Revlog,
+    roots: &[(u32, Node)],
+    node: &Node,
+) -> Result<(usize, HashMap<usize, u32>)> {
+    let revs: HashMap<&[u8], usize> = (changelog.first()..changelog.count())
+        .map(|r| (changelog.node(r), r))
+        .collect();
+    let rev = *revs.get(&node[..]).context("Changeset not in changelog")?;
+    let root_revs: HashMap<usize, u32> = roots
+        .iter()
+        .filter_map(|(number, node)| Some((*revs.get(&node[..])?, *number)))
+        .collect();
+    let mut phases = HashMap::new();
+    // Ancestors have lower revision numbers, so older ones are skipped
+    let min = match root_revs.keys().min() {
+        Some(&min) => min,
+        None => return Ok((rev, phases)),
+    };
+    let mut ancestors = vec![];
+    let mut seen = HashSet::new();
+    let mut stack = if rev >= min { vec![rev] } else { vec![] };
+    while let Some(rev) = stack.pop() {
+        if seen.insert(rev) {
+            ancestors.push(rev);
+            stack.extend(changelog.parents(rev, min));
+        }
+    }
+    // Parents come first, so their phases are known
+    ancestors.sort_unstable();
+    for rev in ancestors {
+        let inherited = changelog
+            .parents(rev, min)
+            .iter()
+            .filter_map(|p| phases.get(p).copied())
+            .max();
+        let phase = root_revs.get(&rev).copied().max(inherited).unwrap_or(0);
+        if phase > 0 {
+            phases.insert(rev, phase);
+        }
+    }
+    Ok((rev, phases))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hg::{
+        revlog::{FLAG_INLINE_DATA, INDEX_ENTRY_LEN, REVLOG_V1},
+        NODE_LEN,
+    };
+
+    fn node(rev: u8) -> Node {
+        [rev + 1; NODE_LEN]
+    }
+
+    fn hex(node: &Node) -> String {
+        node.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Encode an index entry with *data_len* bytes of data
+    fn entry(rev: u8, p1: i32, p2: i32, data_len: u32) -> Vec<u8> {
+        let mut entry = vec![0; INDEX_ENTRY_LEN];
+        entry[8..12].copy_from_slice(&data_len.to_be_bytes());
+        entry[24..28].copy_from_slice(&p1.to_be_bytes());
+        entry[28..32].copy_from_slice(&p2.to_be_bytes());
+        entry[32..32 + NODE_LEN].copy_from_slice(&node(rev));
+        entry
+    }
+
+    #[test]
+    fn phases_from_roots() {
+        let mut hg_dir = std::env::temp_dir();
+        hg_dir.push("test-vcprompt-hg-phases/.hg");
+        let store = hg_dir.join("store");
+        let _ = fs::remove_dir_all(&hg_dir);
+        fs::create_dir_all(&store).unwrap();
+        // 0 - 1 - 2 - 3 - 4 - 6
+        //          \- 5 ------/
+        let parents = [
+            (-1, -1),
+            (0, -1),
+            (1, -1),
+            (2, -1),
+            (3, -1),
+            (2, -1),
+            (4, 5),
+        ];
+        let drafts_of = |rev: u8| drafts(&hg_dir, &hex(&node(rev))).unwrap();
+        let phase_of = |rev: u8| drafts_of(rev).phase().unwrap();
+        assert_eq!(phase_of(4), "public");
+
+        for &inline in [true, false].iter() {
+            let mut index = vec![];
+            for (rev, &(p1, p2)) in parents.iter().enumerate() {
+                let data_len = if inline { 3 } else { 0 };
+                index.extend(entry(rev as u8, p1, p2, data_len));
+                index.extend(vec![b'x'; data_len as usize]);
+            }
+            let flags = if inline { FLAG_INLINE_DATA } else { 0 };
+            index[..4].copy_from_slice(&(REVLOG_V1 | flags).to_be_bytes());
+            fs::write(store.join("00changelog.i"), &index).unwrap();
+            let roots = format!(
+                "0 {}\n1 {}\n2 {}\n",
+                hex(&node(0)),
+                hex(&node(3)),
+                hex(&node(5))
+            );
+            fs::write(store.join("phaseroots"), roots).unwrap();
+
+            assert_eq!(phase_of(2), "public");
+            assert_eq!(phase_of(4), "draft");
+            assert_eq!(phase_of(5), "secret");
+            assert_eq!(phase_of(6), "secret");
+            let drafts = drafts_of(6);
+            let mut ancestors = drafts.ancestors();
+            ancestors.sort_unstable();
+            assert_eq!(ancestors, [&node(3)[..], &node(4), &node(5)]);
+            assert!(drafts_of(3).ancestors().is_empty());
+        }
+
+        // Older entries are only read if a root is missing
+        let roots = parse_roots(&fs::read_to_string(store.join("phaseroots")).unwrap()).unwrap();
+        let path = store.join("00changelog.i");
+        let loaded = load(&path, &roots, node(6), 2).unwrap();
+        assert_eq!(loaded.first(), 0);
+        let (rev, phases) = ancestor_phases(&loaded, &roots, &node(4)).unwrap();
+        assert_eq!(rev, 4);
+        assert_eq!(phases, [(3, 1), (4, 1)].iter().copied().collect());
+        let loaded = load(&path, &roots[1..], node(6), 2).unwrap();
+        assert_eq!(loaded.first(), 5);
+        let (_, phases) = ancestor_phases(&loaded, &roots[1..], &node(6)).unwrap();
+        assert_eq!(phases, [(5, 2), (6, 2)].iter().copied().collect());
+
+        fs::write(&path, [0, 0, 0xde, 0xad]).unwrap();
+        assert!(drafts(&hg_dir, &hex(&node(4))).is_err());
+        fs::remove_dir_all(hg_dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn branches() {
+        assert_eq!(
+            changeset_branch(b"abcd\nuser\n0 0\nfile\n\nmsg"),
+            b"default"
+        );
+        assert_eq!(changeset_branch(b""), b"default");
+        let text = b"abcd\nuser\n0 0 close:1\0branch:x y\0topic:t\nfile\n\nmsg";
+        assert_eq!(changeset_branch(text), b"x y");
+    }
+}
@@ -0,0 +1,417 @@
+//! Read revlogs, the files in which Mercurial stores the history of the
+//! changelog, the manifest and each tracked file
+use super::NODE_LEN;
+use anyhow::{bail, Context, Result};
+use flate2::read::ZlibDecoder;
+use std::{
+    fs::File,
+    io::prelude::*,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
+
+/// Size of an entry of a revlog v1 index
+pub const INDEX_ENTRY_LEN: usize = 64;
+/// Revlog version in the lower 16 bits of the first entry
+pub const REVLOG_V1: u32 = 1;
+/// Data is stored in the index, after each entry
+pub const FLAG_INLINE_DATA: u32 = 1 << 16;
+/// Deltas may be against any revision instead of the previous one
+pub const FLAG_GENERALDELTA: u32 = 1 << 17;
+/// Number of entries read from the end of an index at first; recent
+/// revisions are needed most, so older entries are only read on demand
+pub const INDEX_WINDOW: usize = 4096;
+
+pub type Node = [u8; NODE_LEN];
+
+/// A revlog whose index entries from revision `first` to the tip are loaded
+pub struct Revlog {
+    path:         PathBuf,
+    generaldelta: bool,
+    /// Contents of an inline revlog, where each entry is followed by its data
+    inline:       Option<Vec<u8>>,
+    /// Total number of revisions
+    count:        usize,
+    first:        usize,
+    entries:      Vec<u8>,
+}
+
+impl Revlog {
+    /// Open the revlog index at *path* and read its last *window* entries
+    pub fn open(path: &Path, window: usize) -> Result<Revlog> {
+        let mut file = File::open(path).with_context(|| format!("Failed to open {:?}", path))?;
+        let mut header = [0; 4];
+        file.read_exact(&mut header)
+            .with_context(|| format!("Failed to read {:?}", path))?;
+        let header = be_u32(&header, 0);
+        if header & 0xffff != REVLOG_V1 {
+            bail!("Unsupported revlog version {:#x} in {:?}", header, path);
+        }
+        let mut revlog = Revlog {
+            path:         path.to_path_buf(),
+            generaldelta: header & FLAG_GENERALDELTA != 0,
+            inline:       None,
+            count:        0,
+            first:        0,
+            entries:      vec![],
+        };
+        file.seek(SeekFrom::Start(0))?;
+        if header & FLAG_INLINE_DATA != 0 {
+            // Inline revlogs are split once they grow beyond 128 KiB
+            let mut data = vec![];
+            file.read_to_end(&mut data)?;
+            let mut pos = 0;
+            while pos < data.len() {
+                let entry = data
+                    .get(pos..pos + INDEX_ENTRY_LEN)
+                    .context("Truncated revlog entry")?;
+                revlog.entries.extend_from_slice(entry);
+                pos += INDEX_ENTRY_LEN + be_u32(entry, 8) as usize;
+            }
+            revlog.count = revlog.entries.len() / INDEX_ENTRY_LEN;
+            revlog.inline = Some(data);
+        } else {
+            revlog.count = file.metadata()?.len() as usize / INDEX_ENTRY_LEN;
+            revlog.first = revlog.count;
+            revlog.read_entries(&mut file, revlog.count.saturating_sub(window))?;
+        }
+        Ok(revlog)
+    }
+
+    /// Read the entries from revision *first* to the ones already loaded
+    fn read_entries(&mut self, file: &mut File, first: usize) -> Result<()> {
+        let mut entries = vec![0; (self.first - first) * INDEX_ENTRY_LEN];
+        file.seek(SeekFrom::Start((first * INDEX_ENTRY_LEN) as u64))?;
+        file.read_exact(&mut entries)
+            .with_context(|| format!("Failed to read {:?}", self.path))?;
+        entries.extend_from_slice(&self.entries);
+        self.entries = entries;
+        self.first = first;
+        Ok(())
+    }
+
+    /// Read four times as many entries as are loaded, going further back.
+    /// Returns false if all entries were loaded already.
+    pub fn extend(&mut self) -> Result<bool> {
+        if self.first == 0 {
+            return Ok(false);
+        }
+        let loaded = (self.count - self.first).max(1);
+        let mut file = File::open(&self.path)?;
+        self.read_entries(&mut file, self.first.saturating_sub(3 * loaded))?;
+        Ok(true)
+    }
+
+    /// First loaded revision
+    pub fn first(&self) -> usize {
+        self.first
+    }
+
+    /// Number of revisions
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    fn entry(&self, rev: usize) -> &[u8] {
+        let pos = (rev - self.first) * INDEX_ENTRY_LEN;
+        &self.entries[pos..pos + INDEX_ENTRY_LEN]
+    }
+
+    pub fn node(&self, rev: usize) -> &[u8] {
+        &self.entry(rev)[32..32 + NODE_LEN]
+    }
+
+    /// Parents of *rev* that are at least *min*
+    pub fn parents(&self, rev: usize, min: usize) -> Vec<usize> {
+        let entry = self.entry(rev);
+        // The null revision is stored as -1
+        let parents = [be_u32(entry, 24) as i32, be_u32(entry, 28) as i32];
+        parents
+            .iter()
+            .filter(|&&p| p >= min as i32)
+            .map(|&p| p as usize)
+            .collect()
+    }
+
+    /// Return the revision number of *node*, loading older entries as needed
+    pub fn find(&mut self, node: &Node) -> Result<usize> {
+        let mut end = self.count;
+        loop {
+            if let Some(rev) = (self.first..end).rev().find(|&r| self.node(r) == node) {
+                return Ok(rev);
+            }
+            end = self.first;
+            if !self.extend()? {
+                bail!("Node not found in {:?}", self.path);
+            }
+        }
+    }
+
+    /// Return the text of revision *rev* by applying its delta chain
+    pub fn revision(&mut self, rev: usize) -> Result<Vec<u8>> {
+        if rev >= self.count {
+            bail!("Revision {} not in {:?}", rev, self.path);
+        }
+        // Revisions stored as deltas, newest first, down to a full text
+        let mut chain = vec![];
+        let mut current = rev;
+        loop {
+            while current < self.first {
+                self.extend()?;
+            }
+            let base = be_u32(self.entry(current), 16) as i32;
+            if base == current as i32 {
+                break;
+            }
+            chain.push(current);
+            // Without generaldelta, each delta is against the previous
+            // revision and the base is where the chain starts
+            let next = if self.generaldelta {
+                base
+            } else {
+                current as i32 - 1
+            };
+            if next < 0 || next >= current as i32 {
+                bail!(
+                    "Invalid delta base of revision {} in {:?}",
+                    current,
+                    self.path
+                );
+            }
+            current = next as usize;
+        }
+
+        let mut data_file = None;
+        let mut text = self.chunk(current, &mut data_file)?;
+        for &rev in chain.iter().rev() {
+            text = patch(&text, &self.chunk(rev, &mut data_file)?)?;
+        }
+        let size = be_u32(self.entry(rev), 12) as i32;
+        if size >= 0 && size as usize != text.len() {
+            bail!("Revision {} of {:?} has the wrong size", rev, self.path);
+        }
+        Ok(text)
+    }
+
+    /// Read and decompress the data of *rev*, opening the data file (`.d`)
+    /// into *data_file* if needed
+    fn chunk(&self, rev: usize, data_file: &mut Option<File>) -> Result<Vec<u8>> {
+        let entry = self.entry(rev);
+        // The flags mark censored or externally stored revisions
+        if entry[6..8] != [0, 0] {
+            bail!("Unsupported flags of revision {} in {:?}", rev, self.path);
+        }
+        // The offset of the first revision holds the header instead
+        let offset = match rev {
+            0 => 0,
+            _ => (be_u32(entry, 0) as usize) << 16 | (be_u32(entry, 2) & 0xffff) as usize,
+        };
+        let len = be_u32(entry, 8) as usize;
+        let chunk = match &self.inline {
+            Some(data) => {
+                let pos = offset + (rev + 1) * INDEX_ENTRY_LEN;
+                data.get(pos..pos + len)
+                    .context("Truncated revlog data")?
+                    .to_vec()
+            }
+            None => {
+                let file = match data_file {
+                    Some(file) => file,
+                    None => data_file.get_or_insert(
+                        File::open(self.path.with_extension("d"))
+                            .with_context(|| format!("Failed to open data of {:?}", self.path))?,
+                    ),
+                };
+                let mut chunk = vec![0; len];
+                file.seek(SeekFrom::Start(offset as u64))?;
+                file.read_exact(&mut chunk)
+                    .context("Truncated revlog data")?;
+                chunk
+            }
+        };
+        decompress(chunk)
+    }
+}
+
+/// Decompress a chunk according to its first byte
+fn decompress(chunk: Vec<u8>) -> Result<Vec<u8>> {
+    match chunk.first() {
+        None | Some(b'\0') => Ok(chunk),
+        Some(b'u') => Ok(chunk[1..].to_vec()),
+        Some(b'x') => {
+            let mut data = vec![];
+            ZlibDecoder::new(&chunk[..])
+                .read_to_end(&mut data)
+                .context("Failed to decompress revision")?;
+            Ok(data)
+        }
+        Some(kind) => bail!("Unsupported compression {:?}", *kind as char),
+    }
+}
+
+/// Apply a delta (a list of fragments replacing the bytes from start to end
+/// of *base*) to *base*
+fn patch(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut text = Vec::with_capacity(base.len());
+    let (mut last, mut pos) = (0, 0);
+    while pos < delta.len() {
+        let header = delta.get(pos..pos + 12).context("Truncated delta")?;
+        let (start, end) = (be_u32(header, 0) as usize, be_u32(header, 4) as usize);
+        let len = be_u32(header, 8) as usize;
+        let data = delta
+            .get(pos + 12..pos + 12 + len)
+            .context("Truncated delta")?;
+        if start < last || end < start {
+            bail!("Invalid delta fragment");
+        }
+        text.extend_from_slice(base.get(last..start).context("Invalid delta fragment")?);
+        text.extend_from_slice(data);
+        last = end;
+        pos += 12 + len;
+    }
+    text.extend_from_slice(base.get(last..).context("Invalid delta fragment")?);
+    Ok(text)
+}
+
+pub fn parse_node(hex: &str) -> Option<Node> {
+    let mut node = [0; NODE_LEN];
+    if hex.len() != 2 * NODE_LEN {
+        return None;
+    }
+    for (i, byte) in node.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(node)
+}
+
+pub fn be_u32(data: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes([data[pos], data[pos + 1], data[pos + 2], data[pos + 3]])
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::fs;
+
+    /// Encode an index entry
+    pub fn entry(offset: usize, data_len: usize, size: usize, base: i32, p1: i32) -> Vec<u8> {
+        let mut entry = vec![0; INDEX_ENTRY_LEN];
+        entry[..8].copy_from_slice(&((offset as u64) << 16).to_be_bytes());
+        entry[8..12].copy_from_slice(&(data_len as u32).to_be_bytes());
+        entry[12..16].copy_from_slice(&(size as u32).to_be_bytes());
+        entry[16..20].copy_from_slice(&base.to_be_bytes());
+        entry[24..28].copy_from_slice(&p1.to_be_bytes());
+        entry[28..32].copy_from_slice(&(-1i32).to_be_bytes());
+        entry
+    }
+
+    /// Write an inline revlog of uncompressed full texts, each revision
+    /// being the child of the previous one, and return the nodes
+    pub fn write_revlog(path: &Path, texts: &[&[u8]]) -> Vec<Node> {
+        let mut data = vec![];
+        let mut nodes = vec![];
+        let mut offset = 0;
+        for (rev, text) in texts.iter().enumerate() {
+            let chunk = [b"u", *text].concat();
+            let mut entry = entry(offset, chunk.len(), text.len(), rev as i32, rev as i32 - 1);
+            let node = [rev as u8 + 1; NODE_LEN];
+            entry[32..32 + NODE_LEN].copy_from_slice(&node);
+            data.extend(entry);
+            data.extend_from_slice(&chunk);
+            nodes.push(node);
+            offset += chunk.len();
+        }
+        data[..4].copy_from_slice(&(REVLOG_V1 | FLAG_INLINE_DATA).to_be_bytes());
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+        nodes
+    }
+
+    /// Encode a delta with a single fragment
+    fn fragment(start: u32, end: u32, data: &[u8]) -> Vec<u8> {
+        let mut delta = vec![];
+        delta.extend_from_slice(&start.to_be_bytes());
+        delta.extend_from_slice(&end.to_be_bytes());
+        delta.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        delta.extend_from_slice(data);
+        delta
+    }
+
+    #[test]
+    fn apply_deltas() {
+        let delta = [fragment(0, 2, b"x\n"), fragment(4, 4, b"y\n")].concat();
+        assert_eq!(patch(b"a\nb\nc\n", &delta).unwrap(), b"x\nb\ny\nc\n");
+        assert_eq!(patch(b"a\n", b"").unwrap(), b"a\n");
+        assert!(patch(b"a\n", &fragment(1, 5, b"")).is_err());
+        assert!(patch(
+            b"a\nb\n",
+            &[fragment(2, 4, b""), fragment(0, 2, b"")].concat()
+        )
+        .is_err());
+        assert!(patch(b"a\n", &fragment(0, 1, b"x")[..12]).is_err());
+    }
+
+    #[test]
+    fn read_revisions() {
+        let mut dir = std::env::temp_dir();
+        dir.push("test-vcprompt-hg-revlog");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("file.i");
+
+        let nodes = write_revlog(&path, &[b"a\n", b"", b"a\nb\n"]);
+        let mut revlog = Revlog::open(&path, INDEX_WINDOW).unwrap();
+        assert_eq!(revlog.count(), 3);
+        assert_eq!(revlog.find(&nodes[2]).unwrap(), 2);
+        assert_eq!(revlog.revision(0).unwrap(), b"a\n");
+        assert_eq!(revlog.revision(1).unwrap(), b"");
+        assert_eq!(revlog.revision(2).unwrap(), b"a\nb\n");
+        assert!(revlog.revision(3).is_err());
+        assert!(revlog.find(&[0; NODE_LEN]).is_err());
+
+        // Non-inline: a zlib compressed full text, a delta against it and
+        // either a raw full text or a delta against the first revision
+        let mut compressed = vec![];
+        let mut encoder = flate2::write::ZlibEncoder::new(&mut compressed, Default::default());
+        encoder.write_all(b"a\nb\nc\n").unwrap();
+        encoder.finish().unwrap();
+        for &generaldelta in [false, true].iter() {
+            let mut chunks = [
+                (compressed.clone(), 6, 0),
+                ([b"u".to_vec(), fragment(2, 4, b"x\ny\n")].concat(), 8, 0),
+                (b"\0raw".to_vec(), 4, 2),
+            ];
+            if generaldelta {
+                chunks[2] = ([b"u".to_vec(), fragment(0, 2, b"")].concat(), 4, 0);
+            }
+            let (mut index, mut data) = (vec![], vec![]);
+            for (rev, (chunk, size, base)) in chunks.iter().enumerate() {
+                index.extend(entry(data.len(), chunk.len(), *size, *base, rev as i32 - 1));
+                data.extend_from_slice(chunk);
+            }
+            let flags = if generaldelta { FLAG_GENERALDELTA } else { 0 };
+            index[..4].copy_from_slice(&(REVLOG_V1 | flags).to_be_bytes());
+            fs::write(&path, &index).unwrap();
+            fs::write(dir.join("file.d"), &data).unwrap();
+
+            let mut revlog = Revlog::open(&path, 1).unwrap();
+            assert_eq!(revlog.first(), 2);
+            let last: &[u8] = if generaldelta { b"b\nc\n" } else { b"\0raw" };
+            assert_eq!(revlog.revision(2).unwrap(), last);
+            assert_eq!(revlog.revision(1).unwrap(), b"a\nx\ny\nc\n");
+            assert_eq!(revlog.first(), 0);
+            assert_eq!(revlog.revision(0).unwrap(), b"a\nb\nc\n");
+        }
+
+        // Base pointing to a later revision
+        let mut index = entry(0, 0, 0, 1, -1);
+        index.extend(entry(0, 0, 0, 1, 0));
+        index[..4].copy_from_slice(&(REVLOG_V1 | FLAG_GENERALDELTA).to_be_bytes());
+        fs::write(&path, &index).unwrap();
+        assert!(Revlog::open(&path, 2).unwrap().revision(0).is_err());
+
+        fs::write(&path, [0, 2, 0, 0]).unwrap();
+        assert!(Revlog::open(&path, 2).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
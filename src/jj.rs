@@ -0,0 +1,197 @@
+//! Get Jujutsu (jj) status
+use crate::{
+    config::Config,
+    status::Status,
+    util::{exec_cmd, logger::*, parse_diff_stat_summary, CommandOutput},
+    vcs::Backend,
+};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Template printing one value per line for the working-copy commit
+const LOG_TEMPLATE: &str = r#"change_id.short(8) ++ "\n"
+    ++ commit_id ++ "\n"
+    ++ local_bookmarks.map(|b| b.name()).join(",") ++ "\n"
+    ++ if(conflict, "conflict") ++ "\n""#;
+
+/// Jujutsu backend
+pub struct Jj;
+
+impl Backend for Jj {
+    fn name(&self) -> &'static str {
+        "Jj"
+    }
+
+    fn symbol(&self) -> &'static str {
+        "jj"
+    }
+
+    fn detect(&self, dir: &Path) -> bool {
+        dir.join(".jj/repo").exists()
+    }
+
+    fn status(&self, rootdir: &Path) -> Result<Status> {
+        status(rootdir)
+    }
+
+    /// The working-copy state and the heads of the operation log
+    fn watched_paths(&self, rootdir: &Path, _config: &Config) -> Vec<PathBuf> {
+        vec![
+            rootdir.join(".jj/working_copy"),
+            rootdir.join(".jj/repo/op_heads/heads"),
+        ]
+    }
+}
+
+/// Get the status for the cwd
+fn status(rootdir: &Path) -> Result<Status> {
+    let rootdir = rootdir.to_str().context("Invalid repository path")?;
+    // This snapshots the working copy, so later commands can skip it
+    let log_output = jj_log(rootdir)?;
+    debug!("jj log: {:?}", log_output.stdout);
+    let mut result = parse_log(&log_output.stdout);
+    parse_diff_stat(&jj_diff_stat(rootdir)?.stdout, &mut result);
+    if result.conflicts > 0 {
+        result.conflicts = count_conflicts(&jj_resolve_list(rootdir)?.stdout);
+    }
+    Ok(result)
+}
+
+fn jj_log(rootdir: &str) -> Result<CommandOutput> {
+    exec_cmd(
+        "jj",
+        &[
+            "log",
+            "--repository",
+            rootdir,
+            "--no-graph",
+            "--color=never",
+            "-r",
+            "@",
+            "-T",
+            LOG_TEMPLATE,
+        ],
+        Path::new(rootdir),
+    )
+}
+
+fn jj_diff_stat(rootdir: &str) -> Result<CommandOutput> {
+    exec_cmd(
+        "jj",
+        &[
+            "diff",
+            "--repository",
+            rootdir,
+            "--ignore-working-copy",
+            "--color=never",
+            "--stat",
+        ],
+        Path::new(rootdir),
+    )
+}
+
+fn jj_resolve_list(rootdir: &str) -> Result<CommandOutput> {
+    exec_cmd(
+        "jj",
+        &[
+            "resolve",
+            "--repository",
+            rootdir,
+            "--ignore-working-copy",
+            "--color=never",
+            "--list",
+        ],
+        Path::new(rootdir),
+    )
+}
+
+/// Parse the output of `jj_log()`.
+///
+/// The branch is the change id, followed by the bookmarks pointing to the
+/// working-copy commit (e.g., `kxqpmnzt*main`).
+fn parse_log(log: &str) -> Status {
+    let mut result = Status::new(&Jj);
+    let mut lines = log.lines();
+    if let Some(change_id) = lines.next() {
+        result.branch = change_id.to_string();
+    }
+    if let Some(commit_id) = lines.next() {
+        result.commit = commit_id.to_string();
+    }
+    if let Some(bookmarks) = lines.next().filter(|b| !b.is_empty()) {
+        result.branch.push('*');
+        result.branch.push_str(bookmarks);
+    }
+    // Only a flag for now, the number of files is counted separately
+    if lines.next() == Some("conflict") {
+        result.conflicts = 1;
+    }
+    result
+}
+
+/// Parse the summary line of `jj diff --stat`
+/// (`2 files changed, 3 insertions(+), 1 deletion(-)`).
+///
+/// jj has no staging area, so all changes count as changed files.
+fn parse_diff_stat(stat: &str, status: &mut Status) {
+    let (files, insertions, deletions) = parse_diff_stat_summary(stat);
+    status.changed = files;
+    status.added = insertions;
+    status.deleted = deletions;
+}
+
+/// Count the paths listed by `jj resolve --list`
+fn count_conflicts(list: &str) -> u32 {
+    list.lines().filter(|l| !l.trim().is_empty()).count() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_log_full() {
+        let output = "kxqpmnzt\n30a6f71d1e5c2bbd8a6cd0d7e2c1f1f3a0c5e2b9\nmain,feature\nconflict\n";
+        let mut expected = Status::new(&Jj);
+        expected.branch = "kxqpmnzt*main,feature".to_owned();
+        expected.commit = "30a6f71d1e5c2bbd8a6cd0d7e2c1f1f3a0c5e2b9".to_owned();
+        expected.conflicts = 1;
+        assert_eq!(parse_log(output), expected);
+    }
+
+    #[test]
+    fn parse_log_no_bookmarks() {
+        let output = "kxqpmnzt\n30a6f71d1e5c2bbd8a6cd0d7e2c1f1f3a0c5e2b9\n\n\n";
+        let mut expected = Status::new(&Jj);
+        expected.branch = "kxqpmnzt".to_owned();
+        expected.commit = "30a6f71d1e5c2bbd8a6cd0d7e2c1f1f3a0c5e2b9".to_owned();
+        assert_eq!(parse_log(output), expected);
+    }
+
+    #[test]
+    fn parse_diff_stat_full() {
+        let output = "\
+src/main.rs | 3 ++-
+README.md   | 1 +
+2 files changed, 3 insertions(+), 1 deletion(-)
+";
+        let mut result = Status::new(&Jj);
+        parse_diff_stat(output, &mut result);
+        assert_eq!(result.changed, 2);
+        assert_eq!(result.added, 3);
+        assert_eq!(result.deleted, 1);
+        assert!(!result.is_clean());
+    }
+
+    #[test]
+    fn parse_diff_stat_clean() {
+        let mut result = Status::new(&Jj);
+        parse_diff_stat("0 files changed, 0 insertions(+), 0 deletions(-)\n", &mut result);
+        assert_eq!(result, Status::new(&Jj));
+    }
+
+    #[test]
+    fn conflict_list() {
+        assert_eq!(count_conflicts("a.txt    2-sided conflict\nb.txt    2-sided conflict\n"), 2);
+    }
+}
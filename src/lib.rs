@@ -0,0 +1,60 @@
+//! Version control information for shell prompts and status lines.
+//!
+//! The library is split into three steps, which the `vcprompt-rs` binary
+//! simply chains together:
+//!
+//! 1. Repository discovery: [`Registry`] finds the inner most repository
+//!    for a directory and returns a [`VCContext`].
+//! 2. Status collection: [`VCContext::get_status`] asks the matching
+//!    [`Backend`] for a [`Status`].
+//! 3. Rendering: [`get_output`] formats a [`Status`] in one of the
+//!    [`OutputStyle`]s.
+//!
+//! ```no_run
+//! use vcprompt_rs::{get_output, Config, OutputStyle, Registry, Shell};
+//!
+//! # fn main() -> anyhow::Result<()> {
+//! let config = Config::default();
+//! if let Some(vcs) = Registry::new().find("/path/to/repo".as_ref()) {
+//!     let status = vcs.get_status(&config)?;
+//!     println!("{} on {}", status.name, status.branch);
+//!     println!("{}", get_output(&status, OutputStyle::Minimal, None, Shell::Plain, &config)?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Additional version control systems can be supported by implementing
+//! [`Backend`] and adding it to a [`Registry`].  [`Status`] and the types
+//! it uses are `#[non_exhaustive]`; create them with [`Status::new`].
+pub mod config;
+#[cfg(unix)]
+mod daemon;
+pub mod format;
+mod fossil;
+mod git;
+mod hg;
+mod jj;
+pub mod status;
+mod svn;
+mod template;
+mod util;
+pub mod vcs;
+
+pub use crate::{
+    config::Config,
+    format::{get_output, OutputStyle, Shell},
+    status::Status,
+    vcs::{Backend, Registry, VCContext},
+};
+
+/// Internals used by the `vcprompt-rs` binary, not part of the API
+#[doc(hidden)]
+pub mod cli {
+    #[cfg(unix)]
+    pub use crate::daemon::{query, socket_path, Daemon};
+    pub use crate::util::{
+        globals::{DESCRIPTION, VERSION},
+        logger::init_logger,
+    };
+}
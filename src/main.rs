@@ -1,18 +1,21 @@
-mod format;
-mod git;
-mod hg;
-mod status;
-mod util;
-mod vcs;
-
-use crate::{
-    format::OutputStyle,
-    util::{globals::*, logger::*},
-    vcs::VCContext,
-};
 use anyhow::{Context, Result};
 use getopts::Options;
-use std::env;
+use log::debug;
+use std::{env, path::Path};
+#[cfg(unix)]
+use std::time::Duration;
+#[cfg(unix)]
+use vcprompt_rs::{
+    cli::{query, socket_path, Daemon},
+    vcs::Registry,
+};
+use vcprompt_rs::{
+    cli::{init_logger, DESCRIPTION, VERSION},
+    config::Config,
+    format::{self, OutputStyle, Shell},
+    status::Status,
+    vcs::VCContext,
+};
 
 fn print_usage(program: &str, opts: Options) {
     let brief = format!(
@@ -40,10 +43,33 @@ fn main() -> Result<()> {
         .optflagopt(
             "f",
             "format",
-            "format output using this printf-style string",
+            "format output using this format string (placeholders and groups)",
             "FORMAT_STRING",
         )
-        .optflag("m", "minimal", "use minimal format instead of full");
+        .optflag("m", "minimal", "use minimal format instead of full")
+        .optflag("j", "json", "print the full status as JSON")
+        .optopt(
+            "c",
+            "config",
+            "read settings from this file instead of the default config file",
+            "FILE",
+        )
+        .optopt(
+            "s",
+            "shell",
+            "wrap color codes for this shell (bash, zsh, fish or tcsh)",
+            "SHELL",
+        )
+        .optflag(
+            "",
+            "daemon",
+            "keep statuses up to date in the background and serve them to prompts",
+        )
+        .optflag(
+            "",
+            "no-daemon",
+            "compute the status even if a daemon is running",
+        );
     let matches = match opts.parse(args) {
         Ok(m) => m,
         Err(e) => {
@@ -64,7 +90,16 @@ fn main() -> Result<()> {
 
     debug!("Run with args: {:?}", std::env::args());
 
-    let style = if matches.opt_present("m") {
+    #[cfg(unix)]
+    {
+        if matches.opt_present("daemon") {
+            return run_daemon(matches.opt_str("c"));
+        }
+    }
+
+    let style = if matches.opt_present("j") {
+        OutputStyle::Json
+    } else if matches.opt_present("m") {
         OutputStyle::Minimal
     } else if matches.opt_present("f") {
         OutputStyle::FormatString
@@ -72,7 +107,15 @@ fn main() -> Result<()> {
         OutputStyle::Detailed
     };
 
-    if let Some(dir) = matches.free.get(0) {
+    let shell = match matches.opt_str("s") {
+        Some(name) => name.parse()?,
+        None => Shell::Plain,
+    };
+
+    let config = Config::load(matches.opt_str("c").as_deref().map(Path::new))?;
+    debug!("{:?}", config);
+
+    if let Some(dir) = matches.free.first() {
         debug!("Changing dir to {}", dir);
         env::set_current_dir(dir)?;
     }
@@ -80,13 +123,54 @@ fn main() -> Result<()> {
     if let Some(vcs) = VCContext::get_vcs() {
         debug!("{:?}", vcs);
 
-        let status = vcs.get_status()?;
+        let status = match query_daemon(&vcs, &config, matches.opt_present("no-daemon")) {
+            Some(status) => status,
+            None => vcs.get_status(&config)?,
+        };
         debug!("Status: {:#?}", &status);
 
         println!(
             "{}",
-            format::get_output(&status, style, matches.opt_str("f"))?
+            format::get_output(&status, style, matches.opt_str("f"), shell, &config)?
         );
     }
     Ok(())
 }
+
+/// Serve statuses computed with the built-in backends and the config file
+/// *config* on the daemon socket.  Prompts with a different base ref,
+/// backend selection (`VCP_GIT_BACKEND`, `VCP_HG_BACKEND`) or
+/// `VCP_HG_TRACKING` compute their statuses themselves.
+/// `VCP_DAEMON_INTERVAL` sets the shortest pause between checks for changes
+/// in milliseconds.
+#[cfg(unix)]
+fn run_daemon(config: Option<String>) -> Result<()> {
+    let config = Config::load(config.as_deref().map(Path::new))?;
+    let interval = env::var("VCP_DAEMON_INTERVAL")
+        .ok()
+        .and_then(|ms| ms.parse().ok())
+        .unwrap_or(1000);
+    Daemon::new(Registry::new(), config, Duration::from_millis(interval)).serve(&socket_path())
+}
+
+/// Ask a running daemon for the status of *vcs*, unless *disabled* or the
+/// daemon's settings that change statuses differ from *config* and the
+/// environment
+#[cfg(unix)]
+fn query_daemon(vcs: &VCContext, config: &Config, disabled: bool) -> Option<Status> {
+    if disabled {
+        return None;
+    }
+    match query(&socket_path(), vcs, config) {
+        Ok(status) => Some(status),
+        Err(e) => {
+            debug!("No status from daemon: {:#}", e);
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn query_daemon(_vcs: &VCContext, _config: &Config, _disabled: bool) -> Option<Status> {
+    None
+}
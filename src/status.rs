@@ -1,83 +1,259 @@
-use crate::vcs::VCS;
+//! Repository status shared by all backends
+use crate::vcs::Backend;
+use anyhow::{bail, Result};
+use std::{path::PathBuf, str::FromStr};
+
+/// Progress of a multi-step operation (e.g., a rebase)
+#[derive(Clone, PartialEq, Debug)]
+#[non_exhaustive]
+pub struct Progress {
+    /// The operation as listed in [`Status::operations`]
+    pub operation: &'static str,
+    /// Current step, starting at 1
+    pub step:      u32,
+    /// Total number of steps
+    pub total:     u32,
+    /// The branch the operation was started on
+    pub head_name: Option<String>,
+}
+
+/// Number of files per kind of change, staged or not
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+#[non_exhaustive]
+pub struct FileCounts {
+    /// New files
+    pub added:   u32,
+    /// Removed files
+    pub deleted: u32,
+    /// Renamed files (git: exact renames only in the native backend)
+    pub renamed: u32,
+    /// Files deleted from the work tree without removing them from the VCS
+    pub missing: u32,
+}
+
+/// What the current branch name refers to
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[non_exhaustive]
+pub enum RefKind {
+    /// A branch (or bookmark, change id, ...)
+    Branch,
+    /// A tag, possibly with distance (e.g., `v1.0~2`) on a detached HEAD
+    Tag,
+    /// An abbreviated commit hash on a detached HEAD
+    Commit,
+}
+
+impl RefKind {
+    /// Lower case name (e.g., `tag`)
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RefKind::Branch => "branch",
+            RefKind::Tag => "tag",
+            RefKind::Commit => "commit",
+        }
+    }
+}
+
+impl FromStr for RefKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "branch" => RefKind::Branch,
+            "tag" => RefKind::Tag,
+            "commit" => RefKind::Commit,
+            _ => bail!("Unknown ref kind `{}`", s),
+        })
+    }
+}
+
+/// State of the upstream (tracking) branch
+#[derive(Clone, Copy, PartialEq, Debug)]
+#[non_exhaustive]
+pub enum Tracking {
+    /// Not on a branch or not supported by the VCS
+    Unknown,
+    /// No upstream configured, e.g., for a branch that was never pushed
+    None,
+    /// The configured upstream branch does not exist (anymore)
+    Gone,
+    /// The upstream branch exists
+    Upstream,
+}
+
+impl Tracking {
+    /// Lower case name (e.g., `gone`)
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Tracking::Unknown => "unknown",
+            Tracking::None => "none",
+            Tracking::Gone => "gone",
+            Tracking::Upstream => "upstream",
+        }
+    }
+}
+
+impl FromStr for Tracking {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "unknown" => Tracking::Unknown,
+            "none" => Tracking::None,
+            "gone" => Tracking::Gone,
+            "upstream" => Tracking::Upstream,
+            _ => bail!("Unknown tracking state `{}`", s),
+        })
+    }
+}
 
 /// The current VC status
 #[derive(PartialEq, Debug)]
+#[non_exhaustive]
 pub struct Status {
     /// Version control system
-    pub name:       VCS,
+    pub name:        &'static str,
     /// VCS symbol
-    pub symbol:     String,
+    pub symbol:      String,
     /// The branch name
-    pub branch:     String,
+    pub branch:      String,
+    /// What `branch` refers to
+    pub ref_kind:    RefKind,
     /// Commit hash
-    pub commit:     String,
+    pub commit:      String,
+    /// Short name of the upstream branch (e.g., `origin/main`)
+    pub upstream:    Option<String>,
+    /// Remote of the upstream branch, `None` for local branches
+    pub remote:      Option<String>,
+    /// State of the upstream branch
+    pub tracking:    Tracking,
     /// Number of revisions we are ahead of upstream
-    pub ahead:      u32,
+    pub ahead:       u32,
     /// Number of revisions we are behind upstream
-    pub behind:     u32,
+    pub behind:      u32,
+    /// Phase of the working directory parent (hg), e.g., `draft`
+    pub phase:       Option<String>,
+    /// Bookmarks on the working directory parent (hg)
+    pub bookmarks:   Vec<String>,
+    /// The active bookmark (hg)
+    pub bookmark:    Option<String>,
+    /// The active topic (hg)
+    pub topic:       Option<String>,
+    /// Instabilities of the working directory parent (hg evolve), e.g.,
+    /// `obsolete` or `orphan`
+    pub unstable:    Vec<String>,
+    /// Ref the branch is compared with (e.g., `main`), if configured and found
+    pub base:        Option<String>,
+    /// Number of revisions we are ahead of `base`
+    pub base_ahead:  u32,
+    /// Number of revisions we are behind `base`
+    pub base_behind: u32,
     /// Number of staged files
-    pub staged:     u32,
+    pub staged:      u32,
     /// Number of modified/added/removed files
-    pub changed:    u32,
+    pub changed:     u32,
     /// Number of untracked files
-    pub untracked:  u32,
+    pub untracked:   u32,
     /// Number of conflicts
-    pub conflicts:  u32,
-    /// Number added chunks
-    pub added:      u32,
-    /// Number deleted chunks
-    pub deleted:    u32,
+    pub conflicts:   u32,
+    /// Number of stashed (git) or shelved (hg) changes
+    pub stashes:     u32,
+    /// Number of added, deleted, renamed and missing files
+    pub files:       FileCounts,
+    /// Number of added lines
+    pub added:       u32,
+    /// Number of deleted lines
+    pub deleted:     u32,
     /// Ongoing operations (e.g., merging)
-    pub operations: Vec<&'static str>,
+    pub operations:  Vec<&'static str>,
+    /// Progress of an ongoing operation with multiple steps
+    pub progress:    Option<Progress>,
+    /// Root directory of the repository
+    pub rootdir:     PathBuf,
+    /// Name of the linked worktree (git), `None` for the main work tree
+    pub worktree:    Option<String>,
+    /// Bare repository without work tree (all counts are zero)
+    pub bare:        bool,
 }
 
 impl Status {
     /// Create a new instance with all values set to default.
-    pub fn new(vcs: VCS) -> Status {
+    pub fn new(backend: &dyn Backend) -> Status {
         Status {
-            name:       vcs,
-            symbol:     vcs.default_symbol().to_owned(),
-            branch:     "<unknown>".to_owned(),
-            commit:     String::with_capacity(40), // Should be max length of git commit hash
-            ahead:      0,
-            behind:     0,
-            staged:     0,
-            changed:    0,
-            untracked:  0,
-            conflicts:  0,
-            added:      0,
-            deleted:    0,
-            operations: vec![],
+            name:        backend.name(),
+            symbol:      backend.symbol().to_owned(),
+            branch:      "<unknown>".to_owned(),
+            ref_kind:    RefKind::Branch,
+            commit:      String::with_capacity(40), // Should be max length of git commit hash
+            upstream:    None,
+            remote:      None,
+            tracking:    Tracking::Unknown,
+            ahead:       0,
+            behind:      0,
+            phase:       None,
+            bookmarks:   vec![],
+            bookmark:    None,
+            topic:       None,
+            unstable:    vec![],
+            base:        None,
+            base_ahead:  0,
+            base_behind: 0,
+            staged:      0,
+            changed:     0,
+            untracked:   0,
+            conflicts:   0,
+            stashes:     0,
+            files:       FileCounts::default(),
+            added:       0,
+            deleted:     0,
+            operations:  vec![],
+            progress:    None,
+            rootdir:     PathBuf::new(),
+            worktree:    None,
+            bare:        false,
         }
     }
 
     /// Returns true if repo has no changes
     pub fn is_clean(&self) -> bool {
-        (self.staged == 0 && self.conflicts == 0 && self.changed == 0 && self.untracked == 0)
+        self.staged == 0 && self.conflicts == 0 && self.changed == 0 && self.untracked == 0
     }
 
     /// Format commit hash
     pub fn fmt_commit(&self, len: usize) -> &str {
-        if self.commit != "(initial)" {
+        // Short ids (e.g., svn revisions) are shown in full
+        if self.commit != "(initial)" && self.commit.len() > len {
             return &self.commit[..len];
         }
         &self.commit
     }
 
-    /// Format diff numstat
+    /// Format an operation with its progress, if known (e.g., `REBASE 3/12`)
+    pub fn fmt_operation(&self, operation: &str) -> String {
+        match &self.progress {
+            Some(p) if p.operation == operation => {
+                format!("{} {}/{}", operation, p.step, p.total)
+            }
+            _ => operation.to_string(),
+        }
+    }
+
+    /// Format diff numstat (e.g., `+3/-1`)
     pub fn fmt_diff(&self) -> Option<String> {
-        if self.changed == 0 {
+        if self.added == 0 && self.deleted == 0 {
             return None;
         }
         let mut s = String::with_capacity(10);
         if self.added > 0 {
-            // write!(buf, "+{}", self.insertions)?;
             s.push('+');
             s.push_str(&self.added.to_string());
-            if self.deleted > 0 {
-                s.push_str("/-");
-                s.push_str(&self.deleted.to_string());
+        }
+        if self.deleted > 0 {
+            if !s.is_empty() {
+                s.push('/');
             }
+            s.push('-');
+            s.push_str(&self.deleted.to_string());
         }
         Some(s)
     }
@@ -0,0 +1,255 @@
+//! Get Subversion (svn) status
+use crate::{
+    config::Config,
+    status::Status,
+    util::{exec_cmd, logger::*, CommandOutput},
+    vcs::Backend,
+};
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+
+/// Subversion backend
+pub struct Svn;
+
+impl Backend for Svn {
+    fn name(&self) -> &'static str {
+        "Svn"
+    }
+
+    fn symbol(&self) -> &'static str {
+        "svn"
+    }
+
+    fn detect(&self, dir: &Path) -> bool {
+        dir.join(".svn/wc.db").exists()
+    }
+
+    fn status(&self, rootdir: &Path) -> Result<Status> {
+        status(rootdir)
+    }
+
+    fn watched_paths(&self, rootdir: &Path, _config: &Config) -> Vec<PathBuf> {
+        vec![rootdir.join(".svn")]
+    }
+}
+
+/// Get the status for the working copy at *rootdir*
+fn status(rootdir: &Path) -> Result<Status> {
+    let rootdir = rootdir.to_str().context("Invalid working copy path")?;
+    let info_output = svn_info(rootdir)?;
+    debug!("svn info: {:?}", info_output.stdout);
+    let mut result = parse_info(&info_output.stdout);
+    parse_status(&svn_status(rootdir)?.stdout, &mut result);
+    Ok(result)
+}
+
+fn svn_info(rootdir: &str) -> Result<CommandOutput> {
+    exec_cmd(
+        "svn",
+        &["info", "--xml", "--non-interactive", rootdir],
+        Path::new(rootdir),
+    )
+}
+
+fn svn_status(rootdir: &str) -> Result<CommandOutput> {
+    exec_cmd(
+        "svn",
+        &[
+            "status",
+            "--xml",
+            "--non-interactive",
+            "--ignore-externals",
+            rootdir,
+        ],
+        Path::new(rootdir),
+    )
+}
+
+/// Parse the output of `svn info --xml` (revision and branch)
+fn parse_info(info: &str) -> Status {
+    let mut result = Status::new(&Svn);
+    for (tag, text) in xml_tags(info) {
+        match tag_name(tag) {
+            "entry" => {
+                if let Some(revision) = attribute(tag, "revision") {
+                    result.commit = revision;
+                }
+            }
+            "relative-url" => result.branch = branch_from_url(&unescape(text)),
+            _ => (),
+        }
+    }
+    result
+}
+
+/// Derive the branch name from a repository relative URL using the
+/// standard `trunk`, `branches/<name>` and `tags/<name>` layout
+fn branch_from_url(url: &str) -> String {
+    let parts: Vec<&str> = url
+        .trim_start_matches('^')
+        .split('/')
+        .filter(|p| !p.is_empty())
+        .collect();
+    for (i, part) in parts.iter().enumerate() {
+        match *part {
+            "trunk" => return String::from("trunk"),
+            "branches" | "tags" => {
+                if let Some(name) = parts.get(i + 1) {
+                    return name.to_string();
+                }
+            }
+            _ => (),
+        }
+    }
+    parts
+        .last()
+        .map_or_else(|| String::from("<unknown>"), |p| p.to_string())
+}
+
+/// Parse the output of `svn status --xml` and update the counts of *status*.
+///
+/// Scheduled additions, deletions and replacements count as staged,
+/// local modifications as changed.
+fn parse_status(xml: &str, status: &mut Status) {
+    for (tag, _) in xml_tags(xml) {
+        if tag_name(tag) != "wc-status" {
+            continue;
+        }
+        let item = attribute(tag, "item").unwrap_or_default();
+        let props = attribute(tag, "props").unwrap_or_default();
+        if item == "conflicted"
+            || props == "conflicted"
+            || attribute(tag, "tree-conflicted").as_deref() == Some("true")
+        {
+            status.conflicts += 1;
+            continue;
+        }
+        match item.as_str() {
+            "added" | "deleted" | "replaced" => status.staged += 1,
+            "modified" | "missing" | "obstructed" | "incomplete" => status.changed += 1,
+            "unversioned" => status.untracked += 1,
+            _ if props == "modified" => status.changed += 1,
+            _ => (),
+        }
+    }
+}
+
+/// Iterate over (tag, text following the tag) pairs of an XML document
+fn xml_tags(xml: &str) -> impl Iterator<Item = (&str, &str)> {
+    xml.split('<').skip(1).filter_map(|chunk| {
+        let end = chunk.find('>')?;
+        Some((&chunk[..end], &chunk[end + 1..]))
+    })
+}
+
+fn tag_name(tag: &str) -> &str {
+    tag.split_whitespace().next().unwrap_or_default()
+}
+
+/// Return the unescaped value of the attribute *name* of *tag*
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    while let Some(pos) = rest.find(name) {
+        let after = &rest[pos + name.len()..];
+        let preceded_by_space = rest[..pos].ends_with(char::is_whitespace);
+        if let (true, Some(value)) = (preceded_by_space, after.strip_prefix("=\"")) {
+            return value.find('"').map(|end| unescape(&value[..end]));
+        }
+        rest = after;
+    }
+    None
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_info_branch() {
+        let output = r#"<?xml version="1.0" encoding="UTF-8"?>
+<info>
+<entry
+   kind="dir"
+   path="."
+   revision="1234">
+<url>https://svn.example.com/repo/branches/feature-x</url>
+<relative-url>^/branches/feature-x</relative-url>
+<repository>
+<root>https://svn.example.com/repo</root>
+</repository>
+<commit
+   revision="1230">
+<author>nick</author>
+</commit>
+</entry>
+</info>
+"#;
+        let mut expected = Status::new(&Svn);
+        expected.branch = "feature-x".to_owned();
+        expected.commit = "1234".to_owned();
+        assert_eq!(parse_info(output), expected);
+    }
+
+    #[test]
+    fn branch_names() {
+        assert_eq!(branch_from_url("^/trunk"), "trunk");
+        assert_eq!(branch_from_url("^/project/trunk/src"), "trunk");
+        assert_eq!(branch_from_url("^/branches/fix-1/src"), "fix-1");
+        assert_eq!(branch_from_url("^/tags/v1.0"), "v1.0");
+        assert_eq!(branch_from_url("^/project"), "project");
+        assert_eq!(branch_from_url("^/"), "<unknown>");
+    }
+
+    #[test]
+    fn parse_status_full() {
+        let output = r#"<?xml version="1.0" encoding="UTF-8"?>
+<status>
+<target path=".">
+<entry path="modified.txt">
+<wc-status props="none" item="modified" revision="1234">
+<commit revision="1200"></commit>
+</wc-status>
+</entry>
+<entry path="props.txt"><wc-status props="modified" item="normal" revision="1234"></wc-status></entry>
+<entry path="added.txt"><wc-status props="none" item="added" revision="-1"></wc-status></entry>
+<entry path="deleted.txt"><wc-status props="none" item="deleted" revision="1234"></wc-status></entry>
+<entry path="missing.txt"><wc-status props="none" item="missing" revision="1234"></wc-status></entry>
+<entry path="conflict.txt"><wc-status props="none" item="conflicted" revision="1234"></wc-status></entry>
+<entry path="tree.txt"><wc-status props="none" item="normal" tree-conflicted="true"></wc-status></entry>
+<entry path="new.txt"><wc-status props="none" item="unversioned"></wc-status></entry>
+<entry path="ext"><wc-status props="none" item="external"></wc-status></entry>
+</target>
+</status>
+"#;
+        let mut result = Status::new(&Svn);
+        parse_status(output, &mut result);
+        assert_eq!(result.staged, 2);
+        assert_eq!(result.changed, 3);
+        assert_eq!(result.conflicts, 2);
+        assert_eq!(result.untracked, 1);
+    }
+
+    #[test]
+    fn parse_status_clean() {
+        let output = "<status>\n<target path=\".\">\n</target>\n</status>\n";
+        let mut result = Status::new(&Svn);
+        parse_status(output, &mut result);
+        assert!(result.is_clean());
+    }
+
+    #[test]
+    fn xml_attributes() {
+        let tag = r#"entry kind="dir" path="a&amp;b" revision="7""#;
+        assert_eq!(attribute(tag, "path").as_deref(), Some("a&b"));
+        assert_eq!(attribute(tag, "revision").as_deref(), Some("7"));
+        assert_eq!(attribute(tag, "vision"), None);
+    }
+}
@@ -0,0 +1,317 @@
+//! Parse and render format strings
+//!
+//! Besides `%x` placeholders, a format string can contain groups:
+//!
+//! ```text
+//! %( ... %)          shown only if a placeholder inside is non-empty
+//! %( a %| b %)       first alternative with a non-empty placeholder; an
+//!                    alternative without placeholders is used as fallback
+//! %?x( ... %| ... %) shown if placeholder x is non-empty, else the
+//!                    optional second alternative
+//! %{red bold}( ... %) contents are styled, the outer style is restored after
+//! %%                 a literal `%`
+//! ```
+use crate::util::globals::COLORS;
+use anyhow::{bail, Result};
+use std::{iter::Peekable, str::Chars};
+
+/// A parsed format string
+#[derive(Debug, PartialEq)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    Literal(String),
+    Placeholder(char),
+    Group(Group),
+}
+
+#[derive(Debug, PartialEq)]
+struct Group {
+    /// Placeholder deciding which alternative is shown (`%?x(`)
+    condition:    Option<char>,
+    /// Style names (without braces) applied to the contents
+    style:        Vec<String>,
+    alternatives: Vec<Vec<Node>>,
+}
+
+/// The value of a placeholder: the rendered text and whether the
+/// underlying value is non-empty (e.g., `ahead > 0`)
+pub type Value = (String, bool);
+
+/// Result of rendering a list of nodes
+#[derive(Default)]
+struct Rendered {
+    text:             String,
+    has_placeholders: bool,
+    non_empty:        bool,
+}
+
+impl Template {
+    /// Parse *source* into a template
+    pub fn parse(source: &str) -> Result<Template> {
+        let mut chars = source.chars().peekable();
+        let mut alternatives = parse_nodes(&mut chars, false)?;
+        Ok(Template {
+            nodes: alternatives.remove(0),
+        })
+    }
+
+    /// Render the template, looking up placeholders with *lookup*.
+    ///
+    /// *lookup* returns `None` for unknown placeholders, which are
+    /// rendered literally.
+    pub fn render<F>(&self, lookup: F) -> String
+    where
+        F: Fn(char) -> Option<Value>,
+    {
+        render_nodes(&self.nodes, &lookup, &mut vec![]).text
+    }
+}
+
+/// Parse until the end of the group (`%)`) or the input.
+///
+/// Returns the alternatives separated by `%|`.
+fn parse_nodes(chars: &mut Peekable<Chars>, in_group: bool) -> Result<Vec<Vec<Node>>> {
+    let mut alternatives = vec![];
+    let mut nodes = vec![];
+    let mut literal = String::new();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            literal.push(c);
+            continue;
+        }
+        let next = match chars.next() {
+            Some(next) => next,
+            // A trailing `%` is kept as is
+            None => {
+                literal.push('%');
+                break;
+            }
+        };
+        if next == '%' {
+            literal.push('%');
+            continue;
+        }
+        if !literal.is_empty() {
+            nodes.push(Node::Literal(literal.split_off(0)));
+        }
+        match next {
+            '(' => nodes.push(Node::Group(Group {
+                condition: None,
+                style: vec![],
+                alternatives: parse_nodes(chars, true)?,
+            })),
+            '?' => {
+                let condition = chars.next();
+                if condition.is_none() || chars.next() != Some('(') {
+                    bail!("Expected `%?x(` in format string");
+                }
+                let alternatives = parse_nodes(chars, true)?;
+                if alternatives.len() > 2 {
+                    bail!("Conditional groups take at most two alternatives");
+                }
+                nodes.push(Node::Group(Group {
+                    condition,
+                    style: vec![],
+                    alternatives,
+                }))
+            }
+            '{' => {
+                let style = parse_style(chars)?;
+                if chars.next() != Some('(') {
+                    bail!("Expected `(` after style in format string");
+                }
+                nodes.push(Node::Group(Group {
+                    condition: None,
+                    style,
+                    alternatives: parse_nodes(chars, true)?,
+                }))
+            }
+            '|' | ')' if !in_group => bail!("Unexpected `%{}` outside of group", next),
+            '|' => alternatives.push(nodes.split_off(0)),
+            ')' => {
+                alternatives.push(nodes);
+                return Ok(alternatives);
+            }
+            c => nodes.push(Node::Placeholder(c)),
+        }
+    }
+    if in_group {
+        bail!("Unterminated group in format string");
+    }
+    if !literal.is_empty() {
+        nodes.push(Node::Literal(literal));
+    }
+    alternatives.push(nodes);
+    Ok(alternatives)
+}
+
+/// Parse the style names of `%{red bold}`
+fn parse_style(chars: &mut Peekable<Chars>) -> Result<Vec<String>> {
+    let mut spec = String::new();
+    loop {
+        match chars.next() {
+            Some('}') => break,
+            Some(c) => spec.push(c),
+            None => bail!("Unterminated style in format string"),
+        }
+    }
+    let names: Vec<String> = spec
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect();
+    for name in names.iter() {
+        let known = COLORS
+            .iter()
+            .any(|(k, _)| k.trim_start_matches('{').trim_end_matches('}') == name);
+        if !known {
+            bail!("Unknown style `{}` in format string", name);
+        }
+    }
+    Ok(names)
+}
+
+fn push_styles(text: &mut String, styles: &[String]) {
+    for style in styles.iter() {
+        text.push('{');
+        text.push_str(style);
+        text.push('}');
+    }
+}
+
+fn render_nodes<F>(nodes: &[Node], lookup: &F, styles: &mut Vec<String>) -> Rendered
+where
+    F: Fn(char) -> Option<Value>,
+{
+    let mut result = Rendered::default();
+    for node in nodes.iter() {
+        match node {
+            Node::Literal(s) => result.text.push_str(s),
+            Node::Placeholder(c) => match lookup(*c) {
+                Some((text, non_empty)) => {
+                    result.text.push_str(&text);
+                    result.has_placeholders = true;
+                    result.non_empty |= non_empty;
+                    // Placeholders usually end with `{reset}`
+                    push_styles(&mut result.text, styles);
+                }
+                None => result.text.push(*c),
+            },
+            Node::Group(group) => {
+                let rendered = render_group(group, lookup, styles);
+                result.text.push_str(&rendered.text);
+                result.has_placeholders |= rendered.has_placeholders;
+                result.non_empty |= rendered.non_empty;
+            }
+        }
+    }
+    result
+}
+
+fn render_group<F>(group: &Group, lookup: &F, styles: &mut Vec<String>) -> Rendered
+where
+    F: Fn(char) -> Option<Value>,
+{
+    let depth = styles.len();
+    styles.extend(group.style.iter().cloned());
+    let chosen = match group.condition {
+        Some(c) => {
+            let index = if lookup(c).is_some_and(|(_, non_empty)| non_empty) {
+                0
+            } else {
+                1
+            };
+            group
+                .alternatives
+                .get(index)
+                .map(|nodes| render_nodes(nodes, lookup, styles))
+        }
+        None => group
+            .alternatives
+            .iter()
+            .map(|nodes| render_nodes(nodes, lookup, styles))
+            .find(|r| r.non_empty || !r.has_placeholders),
+    };
+    styles.truncate(depth);
+
+    let mut result = match chosen {
+        Some(rendered) => rendered,
+        None => return Rendered::default(),
+    };
+    if !group.style.is_empty() {
+        let mut text = String::with_capacity(result.text.len() + 16);
+        push_styles(&mut text, &group.style);
+        text.push_str(&result.text);
+        text.push_str("{reset}");
+        push_styles(&mut text, styles);
+        result.text = text;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(c: char) -> Option<Value> {
+        match c {
+            'b' => Some((String::from("main"), true)),
+            'A' => Some((String::from("⇡2"), true)),
+            'B' => Some((String::new(), false)),
+            'u' => Some((String::new(), false)),
+            _ => None,
+        }
+    }
+
+    fn render(source: &str) -> String {
+        Template::parse(source).unwrap().render(lookup)
+    }
+
+    #[test]
+    fn placeholders_and_literals() {
+        assert_eq!(render("%b on %x 100%%"), "main on x 100%");
+        assert_eq!(render("%b 100%"), "main 100%");
+        assert_eq!(render("%(%b%)%"), "main%");
+    }
+
+    #[test]
+    fn group_hidden_when_empty() {
+        assert_eq!(render("%b%( [%A%B]%)"), "main [⇡2]");
+        assert_eq!(render("%b%( [%B%u]%)"), "main");
+        assert_eq!(render("%(static%)"), "static");
+    }
+
+    #[test]
+    fn fallback_alternatives() {
+        assert_eq!(render("%(%u%|%B%|%A%)"), "⇡2");
+        assert_eq!(render("%(%u%|clean%)"), "clean");
+    }
+
+    #[test]
+    fn conditionals() {
+        assert_eq!(render("%?A(ahead%|even%)"), "ahead");
+        assert_eq!(render("%?B(behind%|even%)"), "even");
+        assert_eq!(render("%?B(behind%)"), "");
+    }
+
+    #[test]
+    fn nested_styles() {
+        assert_eq!(
+            render("%{red}(a%{bold}(%b%)c%)"),
+            "{red}a{bold}main{red}{bold}{reset}{red}c{reset}"
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!(Template::parse("%(unterminated").is_err());
+        assert!(Template::parse("stray%)").is_err());
+        assert!(Template::parse("%{nocolor}(x%)").is_err());
+        assert!(Template::parse("%?b(a%|b%|c%)").is_err());
+    }
+}
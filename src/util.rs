@@ -1,6 +1,10 @@
 //! Commonly used utilities
 use anyhow::{format_err, Result};
-use std::process::Command;
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+};
 
 pub mod globals {
     pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -33,15 +37,21 @@ impl PartialEq for CommandOutput {
     }
 }
 
-/// Execute a command and return the output on stdout and stderr if sucessful
+/// Execute a command in *dir* and return the output on stdout and stderr if
+/// sucessful
 ///
 /// Most of this borrowed from Starship
 /// https://github.com/starship/starship/blob/master/src/utils.rs
 ///
 /// If no arguments, pass empty array slice `&[]`
-pub fn exec_cmd(cmd: &str, args: &[&str]) -> Result<CommandOutput> {
-    log::trace!("Executing command '{:?}' with args '{:?}'", cmd, args);
-    let output = Command::new(cmd).args(args).output()?;
+pub fn exec_cmd(cmd: &str, args: &[&str], dir: &Path) -> Result<CommandOutput> {
+    log::trace!(
+        "Executing command '{:?}' with args '{:?}' in {:?}",
+        cmd,
+        args,
+        dir
+    );
+    let output = Command::new(cmd).args(args).current_dir(dir).output()?;
     let stdout_string = String::from_utf8(output.stdout).unwrap_or_default();
     let stderr_string = String::from_utf8(output.stderr).unwrap_or_default();
 
@@ -61,6 +71,94 @@ pub fn exec_cmd(cmd: &str, args: &[&str]) -> Result<CommandOutput> {
     })
 }
 
+/// Parse the summary line of a `diff --stat` (e.g., `2 files changed,
+/// 3 insertions(+), 1 deletion(-)`) into files, insertions and deletions
+pub fn parse_diff_stat_summary(stat: &str) -> (u32, u32, u32) {
+    let (mut files, mut insertions, mut deletions) = (0, 0, 0);
+    let summary = stat.lines().last().unwrap_or_default();
+    for part in summary.split(',') {
+        let mut words = part.split_whitespace();
+        let count = words.next().unwrap_or_default().parse().unwrap_or(0);
+        match words.next() {
+            Some(w) if w.starts_with("file") => files = count,
+            Some(w) if w.starts_with("insertion") => insertions = count,
+            Some(w) if w.starts_with("deletion") => deletions = count,
+            _ => (),
+        }
+    }
+    (files, insertions, deletions)
+}
+
+/// Maximum edit distance searched for before counting all lines as changed
+const MAX_EDIT_COST: usize = 1000;
+
+/// Count inserted and deleted lines between *old* and *new*.
+///
+/// Returns `None` for binary content.
+pub fn line_diff(old: &[u8], new: &[u8]) -> Option<(u32, u32)> {
+    let is_binary = |data: &[u8]| data.iter().take(8000).any(|&b| b == 0);
+    if is_binary(old) || is_binary(new) {
+        return None;
+    }
+    let mut a: Vec<&[u8]> = old.split_inclusive(|&b| b == b'\n').collect();
+    let mut b: Vec<&[u8]> = new.split_inclusive(|&b| b == b'\n').collect();
+    // Common prefix and suffix do not contribute to the diff
+    let prefix = a.iter().zip(b.iter()).take_while(|(x, y)| x == y).count();
+    a.drain(..prefix);
+    b.drain(..prefix);
+    let suffix = a
+        .iter()
+        .rev()
+        .zip(b.iter().rev())
+        .take_while(|(x, y)| x == y)
+        .count();
+    a.truncate(a.len() - suffix);
+    b.truncate(b.len() - suffix);
+
+    let (n, m) = (a.len(), b.len());
+    let d = edit_distance(&a, &b, MAX_EDIT_COST).unwrap_or(n + m);
+    // d = inserted + deleted and m - n = inserted - deleted
+    Some((((d + m - n) / 2) as u32, ((d + n - m) / 2) as u32))
+}
+
+/// Length of the shortest edit script (insertions and deletions only)
+/// using Myers' algorithm, or `None` if it is longer than *limit*
+fn edit_distance(a: &[&[u8]], b: &[&[u8]], limit: usize) -> Option<usize> {
+    let (n, m) = (a.len() as isize, b.len() as isize);
+    // The running time is O((n + m) * d), so give up on large differences
+    let max = (n + m).min(limit as isize);
+    let offset = max as usize + 1;
+    let mut v = vec![0isize; 2 * offset + 1];
+    for d in 0..=max {
+        for k in (-d..=d).step_by(2) {
+            let idx = (k + offset as isize) as usize;
+            let mut x = if k == -d || (k != d && v[idx - 1] < v[idx + 1]) {
+                v[idx + 1]
+            } else {
+                v[idx - 1] + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && a[x as usize] == b[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[idx] = x;
+            if x >= n && y >= m {
+                return Some(d as usize);
+            }
+        }
+    }
+    None
+}
+
+/// Return `$XDG_CONFIG_HOME` or its default `~/.config`
+pub fn xdg_config_home() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+}
+
 pub mod logger {
     // Format output of env_logger buffer
     use chrono::Local;
@@ -68,6 +166,7 @@ pub mod logger {
     use log::{self, Level};
     use std::io::Write;
 
+    #[allow(unused_imports)]
     pub use log::{debug, error, info, trace, warn};
 
     // Colors
@@ -138,9 +237,29 @@ pub mod logger {
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[test]
+    fn line_diff_counts() {
+        assert_eq!(line_diff(b"a\nb\nc\n", b"a\nb\nc\n"), Some((0, 0)));
+        assert_eq!(line_diff(b"a\nb\nc\n", b"a\nx\nc\nd\n"), Some((2, 1)));
+        assert_eq!(line_diff(b"", b"a\nb\n"), Some((2, 0)));
+        assert_eq!(line_diff(b"a\nb\n", b""), Some((0, 2)));
+        assert_eq!(line_diff(b"a", b"a\n"), Some((1, 1)));
+        assert_eq!(line_diff(b"\0binary", b"text"), None);
+    }
+
+    #[test]
+    fn edit_distance_limit() {
+        let a: Vec<&[u8]> = vec![b"a\n", b"b\n", b"c\n"];
+        let b: Vec<&[u8]> = vec![b"x\n", b"b\n", b"y\n"];
+        assert_eq!(edit_distance(&a, &b, 10), Some(4));
+        assert_eq!(edit_distance(&a, &b, 4), Some(4));
+        assert_eq!(edit_distance(&a, &b, 3), None);
+        assert_eq!(edit_distance(&a, &a, 0), Some(0));
+    }
+
     #[test]
     fn exec_no_output() {
-        let result = exec_cmd("true", &[]).unwrap();
+        let result = exec_cmd("true", &[], Path::new(".")).unwrap();
         let expected = CommandOutput {
             stdout: String::from(""),
             stderr: String::from(""),
@@ -150,7 +269,7 @@ mod tests {
 
     #[test]
     fn exec_with_output_stdout() {
-        let result = exec_cmd("/bin/echo", &["-n", "hello"]).unwrap();
+        let result = exec_cmd("/bin/echo", &["-n", "hello"], Path::new(".")).unwrap();
         let expected = CommandOutput {
             stdout: String::from("hello"),
             stderr: String::from(""),
@@ -160,7 +279,7 @@ mod tests {
 
     #[test]
     fn exec_with_output_stderr() {
-        let result = exec_cmd("/bin/sh", &["-c", "echo hello >&2"]).unwrap();
+        let result = exec_cmd("/bin/sh", &["-c", "echo hello >&2"], Path::new(".")).unwrap();
         let expected = CommandOutput {
             stdout: String::from(""),
             stderr: String::from("hello\n"),
@@ -170,7 +289,8 @@ mod tests {
 
     #[test]
     fn exec_with_output_both() {
-        let result = exec_cmd("/bin/sh", &["-c", "echo hello; echo world >&2"]).unwrap();
+        let args = ["-c", "echo hello; echo world >&2"];
+        let result = exec_cmd("/bin/sh", &args, Path::new(".")).unwrap();
         let expected = CommandOutput {
             stdout: String::from("hello\n"),
             stderr: String::from("world\n"),
@@ -178,9 +298,16 @@ mod tests {
         assert_eq!(result, expected)
     }
 
+    #[test]
+    fn exec_in_dir() {
+        let dir = env::temp_dir().canonicalize().unwrap();
+        let result = exec_cmd("pwd", &["-P"], &dir).unwrap();
+        assert_eq!(result.stdout.trim(), dir.to_str().unwrap());
+    }
+
     #[test]
     fn exec_with_non_zero_exit_code() {
-        let result = exec_cmd("false", &[]);
+        let result = exec_cmd("false", &[], Path::new("."));
         assert!(result.is_err(), "Result wasn't an error")
     }
 }
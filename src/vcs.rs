@@ -1,86 +1,224 @@
-use crate::{git, hg, status::Status};
+//! Repository discovery and the backend interface
+use crate::{config::Config, fossil::Fossil, git::Git, hg::Hg, jj::Jj, status::Status, svn::Svn};
 use anyhow::Result;
-use std::{env, fmt, path::PathBuf};
+use std::{
+    env, fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
-/// Supported version control systems
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-pub enum VCS {
-    Git,
-    Hg,
+/// A version control system implementation
+pub trait Backend: Send + Sync {
+    /// Name of the VCS (e.g., `Git`)
+    fn name(&self) -> &'static str;
+
+    /// Default symbol shown instead of the name
+    fn symbol(&self) -> &'static str;
+
+    /// Return true if *dir* is the root of a repository of this VCS
+    fn detect(&self, dir: &Path) -> bool;
+
+    /// Return the root of a repository selected by environment variables
+    /// (e.g., `GIT_DIR`) for the working directory *cwd*
+    fn find_from_env(&self, _cwd: &Path) -> Option<PathBuf> {
+        None
+    }
+
+    /// Get the status of the repository rooted at *rootdir*
+    fn status(&self, rootdir: &Path) -> Result<Status>;
+
+    /// Get the status of the repository rooted at *rootdir*, looking up
+    /// backend settings (e.g., `VCP_BASE_REF`) not given as environment
+    /// variable in *config*
+    fn status_with_config(&self, rootdir: &Path, _config: &Config) -> Result<Status> {
+        self.status(rootdir)
+    }
+
+    /// Return the files and directories outside the work tree (or hidden
+    /// in it) that the status of *rootdir* is read from, e.g., the index
+    /// and refs; directories stand for their direct entries.  The daemon
+    /// recomputes the status when they change.
+    fn watched_paths(&self, _rootdir: &Path, _config: &Config) -> Vec<PathBuf> {
+        vec![]
+    }
+
+    /// Return a filter telling which directories (relative to *rootdir*)
+    /// are ignored, so that the daemon does not check them for changes
+    fn ignored_dirs(&self, _rootdir: &Path) -> DirFilter {
+        Box::new(|_| false)
+    }
 }
 
-impl fmt::Display for VCS {
+/// Tells whether a directory is ignored, see [`Backend::ignored_dirs`]
+pub type DirFilter = Box<dyn FnMut(&Path) -> bool>;
+
+impl fmt::Debug for dyn Backend {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match *self {
-            VCS::Git => write!(f, "Git"),
-            VCS::Hg => write!(f, "Hg"),
-        }
+        write!(f, "{}", self.name())
     }
 }
 
-impl VCS {
-    pub fn default_symbol(&self) -> &'static str {
-        match *self {
-            VCS::Git => "",
-            VCS::Hg => "☿",
+/// Ordered list of known backends
+pub struct Registry {
+    backends: Vec<Arc<dyn Backend>>,
+}
+
+impl Default for Registry {
+    /// Same as [`Registry::new`]
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Registry {
+    /// Create a registry with all built-in backends, to which further
+    /// backends can be added
+    ///
+    /// jj comes first so that colocated jj/git repos are treated as jj.
+    pub fn new() -> Self {
+        let mut registry = Self::empty();
+        registry
+            .register(Box::new(Jj))
+            .register(Box::new(Git))
+            .register(Box::new(Hg))
+            .register(Box::new(Svn))
+            .register(Box::new(Fossil));
+        registry
+    }
+
+    /// Create a registry without any backends, e.g., to use only custom
+    /// ones or to choose the order of the built-in ones
+    pub fn empty() -> Self {
+        Registry { backends: vec![] }
+    }
+
+    /// Add *backend*; backends are tried in the order they were registered
+    pub fn register(&mut self, backend: Box<dyn Backend>) -> &mut Self {
+        self.backends.push(Arc::from(backend));
+        self
+    }
+
+    /// Determine the inner most VCS for the cwd.
+    ///
+    /// Repositories selected by environment variables take precedence.
+    pub fn get_vcs(&self) -> Option<VCContext> {
+        let cwd = env::current_dir().ok()?;
+        for backend in self.backends.iter() {
+            if let Some(rootdir) = backend.find_from_env(&cwd) {
+                return Some(VCContext::new(backend.clone(), rootdir));
+            }
+        }
+        self.find(&cwd)
+    }
+
+    /// Determine the inner most VCS for *dir*.
+    ///
+    /// This functions works for nest (sub) repos and always returns
+    /// the most inner repository type.
+    pub fn find(&self, dir: &Path) -> Option<VCContext> {
+        let mut cwd = Some(dir);
+        while let Some(path) = cwd {
+            if let Some(backend) = self.backends.iter().find(|b| b.detect(path)) {
+                return Some(VCContext::new(backend.clone(), path));
+            }
+            cwd = path.parent();
         }
+        None
     }
 }
 
-#[derive(Clone, Debug, Eq, PartialEq)]
+/// A repository found by [`Registry::find`]
+#[derive(Clone, Debug)]
 pub struct VCContext {
-    system:  VCS,
+    backend: Arc<dyn Backend>,
     rootdir: PathBuf,
 }
 
 impl VCContext {
-    pub fn defaults() -> [VCContext; 2] {
-        [
-            VCContext {
-                system:  VCS::Git,
-                rootdir: PathBuf::from(".git/HEAD"),
-            },
-            VCContext {
-                system:  VCS::Hg,
-                rootdir: PathBuf::from(".hg/00changelog.i"),
-            },
-        ]
-    }
-
     /// Create new instance of VCContext
-    pub fn new<P>(vcs: VCS, rootdir: P) -> Self
+    pub fn new<P>(backend: Arc<dyn Backend>, rootdir: P) -> Self
     where
         P: Into<PathBuf>,
     {
         Self {
-            system:  vcs,
+            backend,
             rootdir: rootdir.into(),
         }
     }
 
-    /// Determine the inner most VCS.
-    ///
-    /// This functions works for nest (sub) repos and always returns
-    /// the most inner repository type.
+    /// Determine the inner most VCS using the built-in backends.
     pub fn get_vcs() -> Option<Self> {
-        let mut cwd = env::current_dir().ok();
-        while let Some(path) = cwd {
-            for def in VCContext::defaults().iter() {
-                let mut fname = path.clone();
-                fname.push(&def.rootdir);
-                if fname.exists() {
-                    return Some(VCContext::new(def.system, path));
-                }
-            }
-            cwd = path.parent().map(PathBuf::from);
-        }
-        None
+        Registry::new().get_vcs()
+    }
+
+    /// The backend handling this repository
+    pub fn backend(&self) -> &dyn Backend {
+        &*self.backend
+    }
+
+    /// Root directory of the repository
+    pub fn rootdir(&self) -> &Path {
+        &self.rootdir
     }
 
-    pub fn get_status(self) -> Result<Status> {
-        match self.system {
-            VCS::Git => git::status(self.rootdir),
-            VCS::Hg => hg::status(self.rootdir),
+    /// Collect the status of the repository using the settings in *config*
+    pub fn get_status(self, config: &Config) -> Result<Status> {
+        let mut status = self.backend.status_with_config(&self.rootdir, config)?;
+        status.rootdir = self.rootdir;
+        Ok(status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env::temp_dir, fs};
+
+    struct Dummy;
+
+    impl Backend for Dummy {
+        fn name(&self) -> &'static str {
+            "Dummy"
+        }
+
+        fn symbol(&self) -> &'static str {
+            "D"
+        }
+
+        fn detect(&self, dir: &Path) -> bool {
+            dir.join(".dummy").exists()
+        }
+
+        fn status(&self, _rootdir: &Path) -> Result<Status> {
+            Ok(Status::new(self))
         }
     }
+
+    #[test]
+    fn custom_backend() {
+        let mut rootdir = temp_dir();
+        rootdir.push("test-vcprompt-registry");
+        fs::create_dir_all(rootdir.join(".dummy")).unwrap();
+        fs::create_dir_all(rootdir.join("sub")).unwrap();
+
+        let mut registry = Registry::empty();
+        registry.register(Box::new(Dummy));
+        let vcs = registry.find(&rootdir.join("sub")).unwrap();
+        assert_eq!(vcs.rootdir, rootdir);
+        assert_eq!(vcs.backend().name(), "Dummy");
+
+        let status = vcs.get_status(&Config::default()).unwrap();
+        assert_eq!(status.name, "Dummy");
+        assert_eq!(status.symbol, "D");
+        assert_eq!(status.rootdir, rootdir);
+        assert!(Registry::empty().find(&rootdir).is_none());
+
+        // Built-in backends are tried first
+        let mut registry = Registry::new();
+        registry.register(Box::new(Dummy));
+        fs::create_dir_all(rootdir.join(".hg")).unwrap();
+        fs::write(rootdir.join(".hg/00changelog.i"), "").unwrap();
+        assert_eq!(registry.find(&rootdir).unwrap().backend().name(), "Hg");
+        fs::remove_dir_all(&rootdir).unwrap();
+    }
 }